@@ -4,24 +4,34 @@ use std::{
 };
 
 use music_server::{
-    request::{self, Answer, AnswerType, ObjRequest, Request, RequestType},
+    request::{
+        self, Answer, AnswerType, Credentials, EventType, ObjRequest, Request, RequestType,
+        SourceStatus, Topic,
+    },
     source_types::{Playlist, Song},
 };
-use tokio::{
-    io::WriteHalf,
-    net::{tcp::OwnedWriteHalf, TcpStream},
-};
+use tokio::io::AsyncWriteExt;
 use tui::{
     style::{Color, Modifier, Style},
-    widgets::{Block, Borders, List, ListItem, ListState},
+    text::{Spans, Text},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
 };
 
+use crate::connection::Writer;
 use crate::player::Player;
+use crate::queue::{Queue, QueueEntry, Repeat};
+use crate::search::{self, Filter, Found, Input, Search};
+use crate::url;
+
+/// How many results the search shows at most
+const MAX_RESULTS: usize = 100;
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum Panel {
     Sources,
     Playlists,
     Songs,
+    Queue,
 }
 
 pub enum Event {
@@ -30,6 +40,8 @@ pub enum Event {
     Pause,
     Stop,
     Shuffle,
+    /// Goes through the repeat modes
+    Repeat,
     VolumeUp,
     VolumeDown,
     Download,
@@ -38,6 +50,21 @@ pub enum Event {
     Prev,
     SeekForward,
     SeekBackward,
+    /// Adds the selected songs at the end of the queue
+    Enqueue,
+    /// Adds the selected songs after the one playing
+    PlayNext,
+    /// Removes the selected entry of the queue
+    Remove,
+    ClearQueue,
+    /// Moves the selected entry of the queue by this offset
+    MoveEntry(i32),
+    /// Starts typing a filter of the focused panel
+    Filter,
+    /// Selects the next item matching the last filter, the previous one when negative
+    Match(i32),
+    /// Opens the search of every playlist loaded
+    Search,
 }
 
 pub enum Direction {
@@ -47,6 +74,7 @@ pub enum Direction {
     LeftPanel,
     UpPanel,
     DownPanel,
+    QueuePanel,
 }
 
 #[derive(Debug)]
@@ -61,6 +89,10 @@ pub struct SourceWidget {
     state: ListState,
     playlist: Vec<PlaylistWidget>,
     pub name: String,
+    /// `None` until the source sent its status
+    status: Option<SourceStatus>,
+    /// Where the user has to log in for the source to work
+    auth_url: Option<String>,
 }
 
 #[derive(Default, Clone)]
@@ -92,17 +124,26 @@ impl SourceWidget {
             state: Default::default(),
             playlist: Default::default(),
             name,
+            status: None,
+            auth_url: None,
         }
     }
-    pub fn get_playlists_widget(&self) -> List<'_> {
-        make_list(
-            self.playlist
-                .iter()
-                .cloned()
-                .map(|p| ListItem::new(p.name))
-                .collect(),
-            "Playlists",
-        )
+
+    /// The name, along with what the source is waiting for
+    fn label(&self) -> String {
+        match (&self.auth_url, &self.status) {
+            (Some(_), _) => format!("{} (login required)", self.name),
+            (None, Some(SourceStatus::Loading)) => format!("{} (loading)", self.name),
+            (None, Some(SourceStatus::Syncing)) => format!("{} (syncing)", self.name),
+            _ => self.name.clone(),
+        }
+    }
+    fn get_playlist_items(&self) -> Vec<ListItem<'_>> {
+        self.playlist
+            .iter()
+            .cloned()
+            .map(|p| ListItem::new(p.name))
+            .collect()
     }
 
     fn add_playlistlist(&mut self, playlistlist: Vec<Playlist>) {
@@ -113,22 +154,80 @@ impl SourceWidget {
     }
 }
 
+/// The song given to the player, along with where it comes from,
+/// so that nothing has to be guessed from what mpv reports
+pub struct NowPlaying {
+    /// The entry of the queue it was started from, kept up to date with the song
+    pub entry: QueueEntry,
+    /// What the player was given
+    pub url: String,
+}
+
 pub struct App {
-    pub stream: OwnedWriteHalf,
+    pub stream: Writer,
     sources: Vec<SourceWidget>,
     pub state: ListState,
     pub current_panel: Panel,
     pub player: Player,
+    pub queue: Queue,
+    queue_state: ListState,
+    /// Whether the player loaded the song started by the queue, which ended once unloaded
+    track_started: bool,
+    /// Sent along the stream urls, which do not go through the logged in connection
+    credentials: Option<Credentials>,
+    /// Source and id of the playlist the queue was replaced with
+    active_playlist: Option<(String, String)>,
+    now_playing: Option<NowPlaying>,
+    /// The filter being typed
+    filter: Option<Filter>,
+    /// The last filter typed, which `n` and `N` go through the matches of
+    last_query: String,
+    search: Option<Search>,
 }
 
 impl App {
-    pub fn new(stream: OwnedWriteHalf) -> Self {
+    pub fn new(
+        stream: Writer,
+        credentials: Option<Credentials>,
+        player: Player,
+        queue: Queue,
+    ) -> Self {
         App {
             stream,
+            credentials,
             sources: Default::default(),
             state: Default::default(),
             current_panel: Panel::Sources,
-            player: Player::new(),
+            player,
+            queue,
+            queue_state: Default::default(),
+            track_started: false,
+            active_playlist: None,
+            now_playing: None,
+            filter: None,
+            last_query: String::new(),
+            search: None,
+        }
+    }
+
+    /// Where to play `song` from: the local file when it is downloaded on this machine,
+    /// the server's http stream otherwise
+    fn song_url(&self, song: &Song) -> String {
+        let local = song.downloaded && std::path::Path::new(&song.url).exists();
+        if local || song.stream_url.is_empty() {
+            return song.url.clone();
+        }
+        match &self.credentials {
+            Some(Credentials::Token(token)) => {
+                format!("{}?token={}", song.stream_url, url::encode(token))
+            }
+            Some(Credentials::Password { user, password }) => format!(
+                "{}?user={}&password={}",
+                song.stream_url,
+                url::encode(user),
+                url::encode(password)
+            ),
+            None => song.stream_url.clone(),
         }
     }
 
@@ -162,37 +261,74 @@ impl App {
                 }
             }
             AnswerType::Songs(playlist, songs) => self.add_songs(answer.client, playlist, songs),
+            AnswerType::Event(EventType::PlaylistUpdated(playlist, songs)) => {
+                self.update_playlist(answer.client, playlist, songs)
+            }
+            AnswerType::Event(EventType::SongDownloaded(song)) => {
+                self.update_song(answer.client, song)
+            }
+            AnswerType::Event(EventType::SourceStatus(status)) => {
+                let source = self.source_mut(&answer.client);
+                // the sources are only ready once they are logged in
+                if matches!(status, SourceStatus::Ready) {
+                    source.auth_url = None;
+                }
+                source.status = Some(status);
+            }
+            AnswerType::Event(EventType::AuthRequired(url)) => {
+                self.source_mut(&answer.client).auth_url = Some(url)
+            }
             _ => (),
         }
     }
 
-    pub async fn send_request(&self, request: &Request) {
+    pub async fn send_request(&mut self, request: &Request) {
         let json = serde_json::to_string(request).unwrap();
         let message = request::prepare_message(json);
-        self.stream.writable().await;
-        match self.stream.try_write(&message) {
-            Ok(n) => (),
-            Err(err) => eprintln!("{:?}", err),
-        };
+        if let Err(err) = self.stream.write_all(&message).await {
+            eprintln!("{:?}", err);
+        }
     }
 
-    pub async fn add_source(&mut self, source: String) {
-        self.sources.push(SourceWidget::new(source.clone()));
+    /// The source called `name`, added if its events came before it was listed
+    fn source_mut(&mut self, name: &str) -> &mut SourceWidget {
+        let index = match self.sources.iter().position(|s| s.name == name) {
+            Some(index) => index,
+            None => {
+                self.sources.push(SourceWidget::new(name.to_string()));
+                self.sources.len() - 1
+            }
+        };
         if self.state.selected().is_none() {
             self.state.select(Some(0));
         }
-        self.send_request(&Request {
-            client: source,
-            ty: RequestType::GetAll(ObjRequest::PlaylistList),
-        })
+        &mut self.sources[index]
+    }
+
+    pub async fn add_source(&mut self, source: String) {
+        self.source_mut(&source);
+        self.send_request(&Request::new(
+            source,
+            RequestType::GetAll(ObjRequest::PlaylistList),
+        ))
         .await;
     }
 
-    pub async fn request_sources(&self) {
-        let request = Request {
-            client: "all".to_owned(),
-            ty: RequestType::GetAll(ObjRequest::ClientList),
-        };
+    pub async fn request_sources(&mut self) {
+        let subscribe = Request::new(
+            "all".to_owned(),
+            RequestType::Subscribe(vec![
+                Topic::SourceStatus,
+                Topic::Playlist,
+                Topic::Download,
+                Topic::Auth,
+            ]),
+        );
+        self.send_request(&subscribe).await;
+        let request = Request::new(
+            "all".to_owned(),
+            RequestType::GetAll(ObjRequest::ClientList),
+        );
         self.send_request(&request).await;
     }
 
@@ -200,16 +336,22 @@ impl App {
         let sources: Vec<ListItem> = self
             .sources
             .iter()
-            .cloned()
-            .map(|s| ListItem::new(s.name))
+            .map(|s| ListItem::new(s.label()))
             .collect();
-        make_list(sources, "Sources")
+        self.filtered_list(Panel::Sources, sources, "Sources")
+    }
+
+    pub fn get_sources_state(&self) -> ListState {
+        self.filtered_state(Panel::Sources, self.state.clone())
     }
 
     pub fn get_playlists_widget(&self) -> List<'_> {
         let route = self.get_current_route();
         match route.source {
-            Some(i) => self.sources[i].get_playlists_widget(),
+            Some(i) => {
+                let items = self.sources[i].get_playlist_items();
+                self.filtered_list(Panel::Playlists, items, "Playlists")
+            }
             _ => make_list(vec![], "Playlist"),
         }
     }
@@ -217,7 +359,7 @@ impl App {
     pub fn get_playlists_state(&self) -> ListState {
         let route = self.get_current_route();
         match route.source {
-            Some(i) => self.sources[i].state.clone(),
+            Some(i) => self.filtered_state(Panel::Playlists, self.sources[i].state.clone()),
             _ => Default::default(),
         }
     }
@@ -249,10 +391,10 @@ impl App {
     }
 
     pub async fn load_playlist(&mut self, client: String, playlist: Playlist) {
-        let request = Request {
-            client: client.clone(),
-            ty: RequestType::GetAll(ObjRequest::Playlist(playlist.id.clone())),
-        };
+        let request = Request::new(
+            client.clone(),
+            RequestType::GetAll(ObjRequest::Playlist(playlist.id.clone())),
+        );
         self.send_request(&request).await;
     }
 
@@ -268,47 +410,289 @@ impl App {
             Direction::Down => self.move_current_panel(1),
             Direction::DownPanel => self.current_panel = Panel::Playlists,
             Direction::UpPanel => self.current_panel = Panel::Sources,
+            Direction::QueuePanel => self.current_panel = Panel::Queue,
         }
     }
-    pub fn play(&mut self) {
-        let route = self.get_current_route();
-        if let Some(s) = route.source {
-            if let Some(p) = route.playlist {
-                if let Some(c) = route.song {
-                    let song = &self.sources[s].playlist[p].songs[c];
-                    self.player.play(&song.url);
+
+    /// Plays the selected entry of the queue, or the selected song right away
+    pub async fn play(&mut self) {
+        let index = match self.current_panel {
+            Panel::Queue => self.queue_state.selected(),
+            _ => {
+                let entries = self.selected_entries(false, false);
+                if entries.is_empty() {
+                    None
+                } else {
+                    Some(self.queue.play_next(entries))
                 }
             }
+        };
+        if let Some(index) = index {
+            self.go_to(index).await;
+        }
+    }
+
+    /// Plays the entry of the queue at `index`
+    pub async fn go_to(&mut self, index: usize) {
+        if self.queue.jump(index).is_some() {
+            self.play_current().await;
+        }
+    }
+
+    /// Plays the current entry of the queue
+    async fn play_current(&mut self) {
+        let Some(entry) = self.queue.current_entry() else {
+            return;
+        };
+        let now_playing = NowPlaying {
+            entry: entry.clone(),
+            url: self.song_url(&entry.song),
+        };
+        self.player.play(&now_playing.url);
+        self.track_started = false;
+        // the server keeps the listening history of the user, of the songs it knows
+        let entry = &now_playing.entry;
+        let request = (!entry.source.is_empty()).then(|| {
+            Request::new(
+                entry.source.clone(),
+                RequestType::Played(entry.song.id.clone()),
+            )
+        });
+        self.now_playing = Some(now_playing);
+        if let Some(request) = request {
+            self.send_request(&request).await;
+        }
+    }
+
+    /// The entry of the queue the player plays, `None` when stopped
+    pub fn now_playing(&self) -> Option<&QueueEntry> {
+        if self.player.is_stopped() {
+            return None;
+        }
+        self.now_playing
+            .as_ref()
+            .map(|now_playing| &now_playing.entry)
+    }
+
+    pub fn stop(&mut self) {
+        self.player.stop();
+        self.now_playing = None;
+    }
+
+    /// Moves on to the next entry of the queue once the song playing ended
+    pub async fn tick(&mut self) {
+        if self.player.is_stopped() {
+            return;
+        }
+        let loaded = !self.player.get_state().path.is_empty();
+        if loaded {
+            self.track_started = true;
+        } else if self.track_started {
+            if self.queue.advance().is_some() {
+                self.play_current().await;
+            } else {
+                self.stop();
+            }
+        }
+    }
+
+    async fn next(&mut self) {
+        if self.queue.next().is_some() {
+            self.play_current().await;
+        } else {
+            self.stop();
+        }
+    }
+
+    async fn prev(&mut self) {
+        if self.queue.previous().is_some() {
+            self.play_current().await;
+        }
+    }
+
+    /// Queues the selected playlist after the current entry and plays it from its start,
+    /// keeping the rest of the queue
+    async fn auto(&mut self) {
+        let entries = self.selected_entries(true, true);
+        if entries.is_empty() {
+            return;
+        }
+        let index = self.queue.play_next(entries);
+        self.go_to(index).await;
+    }
+
+    /// Replaces the queue with the playlist `id` of `source` and plays it from its start
+    pub async fn activate_playlist(&mut self, source: &str, id: &str) {
+        let entries = self
+            .sources
+            .iter()
+            .filter(|s| s.name == source)
+            .flat_map(|s| s.playlist.iter())
+            .filter(|p| p.playlist.id == id)
+            .flat_map(|p| p.songs.iter())
+            .map(|song| QueueEntry::new(source.to_string(), id.to_string(), song.clone()))
+            .collect();
+        self.play_playlist(entries).await;
+    }
+
+    /// Replaces the queue with `entries`, the songs of a single playlist, and plays them
+    async fn play_playlist(&mut self, entries: Vec<QueueEntry>) {
+        let Some(first) = entries.first() else {
+            return;
+        };
+        self.active_playlist = Some((first.source.clone(), first.playlist.clone()));
+        self.queue.replace(entries);
+        self.go_to(0).await;
+    }
+
+    /// Every playlist of every source, along with the name of its source
+    pub fn playlists(&self) -> impl Iterator<Item = (&str, &Playlist)> {
+        self.sources.iter().flat_map(|source| {
+            source
+                .playlist
+                .iter()
+                .map(|p| (source.name.as_str(), &p.playlist))
+        })
+    }
+
+    /// The playlist the queue was last replaced with
+    pub fn active_playlist(&self) -> Option<(&str, &Playlist)> {
+        let (source, id) = self.active_playlist.as_ref()?;
+        self.playlists()
+            .find(|(name, playlist)| name == source && &playlist.id == id)
+    }
+
+    /// The selected song when the songs are focused and `all` is not set,
+    /// every song of the selected playlist otherwise when `playlist` is set
+    fn selected_entries(&self, playlist: bool, all: bool) -> Vec<QueueEntry> {
+        let route = self.get_current_route();
+        let (Some(s), Some(p)) = (route.source, route.playlist) else {
+            return vec![];
+        };
+        let source = &self.sources[s];
+        let widget = &source.playlist[p];
+        let songs: Vec<&Song> = match (&self.current_panel, route.song) {
+            (Panel::Songs, Some(c)) if !all => widget.songs.get(c).into_iter().collect(),
+            _ if playlist => widget.songs.iter().collect(),
+            _ => vec![],
+        };
+        songs
+            .into_iter()
+            .map(|song| {
+                QueueEntry::new(
+                    source.name.clone(),
+                    widget.playlist.id.clone(),
+                    song.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// Removes the selected entry of the queue
+    fn remove_entry(&mut self) {
+        if let Some(index) = self.queue_state.selected() {
+            self.remove_at(index);
+        }
+    }
+
+    /// Removes the entry of the queue at `index`, stopping the playback when it plays
+    pub fn remove_at(&mut self, index: usize) {
+        let Some(id) = self.queue.entries().get(index).map(|entry| entry.id) else {
+            return;
+        };
+        self.queue.remove(index);
+        if self.now_playing().is_some_and(|entry| entry.id == id) {
+            self.stop();
+        }
+    }
+
+    fn move_entry(&mut self, off: i32) {
+        if let Some(index) = self.queue_state.selected() {
+            let to = compute_new_i(Some(index), off, self.queue.len());
+            self.queue.move_entry(index, to);
+            self.queue_state.select(Some(to));
+        }
+    }
+
+    fn clear_queue(&mut self) {
+        self.stop();
+        self.queue.clear();
+        self.active_playlist = None;
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        self.queue.repeat()
+    }
+
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.queue.set_repeat(repeat);
+    }
+
+    pub fn shuffled(&self) -> bool {
+        self.queue.shuffled()
+    }
+
+    /// Shuffles the queue, keeping the song playing, or puts it back in order
+    pub fn set_shuffled(&mut self, shuffled: bool) {
+        let selected = self.queue_state.selected();
+        self.queue.set_shuffled(shuffled);
+        // the selection follows the current entry, which the shuffle moves
+        if selected.is_some() {
+            self.queue_state.select(self.queue.current().or(Some(0)));
         }
     }
 
     pub async fn handle_event(&mut self, event: Event) {
         match event {
             Event::Move(dir) => self.handle_move(dir),
-            Event::Play => self.play(),
+            Event::Play => self.play().await,
             Event::Pause => {
                 self.player.playpause();
             }
             Event::VolumeUp => self.player.incr_volume(5),
             Event::VolumeDown => self.player.incr_volume(-5),
             Event::Download => self.download().await,
-            Event::Shuffle => self.player.shuffle(),
-            Event::Prev => self.player.prev(),
-            Event::Next => self.player.next(),
-            Event::Auto => self.auto(),
+            Event::Shuffle => self.set_shuffled(!self.shuffled()),
+            Event::Repeat => self.set_repeat(self.repeat().cycle()),
+            Event::Prev => self.prev().await,
+            Event::Next => self.next().await,
+            Event::Auto => self.auto().await,
             Event::SeekForward => self.player.seek(5),
             Event::SeekBackward => self.player.seek(-5),
-            _ => (),
+            Event::Enqueue => {
+                let entries = self.selected_entries(true, false);
+                self.queue.enqueue(entries);
+            }
+            Event::PlayNext => {
+                let entries = self.selected_entries(true, false);
+                self.queue.play_next(entries);
+            }
+            Event::Stop => self.stop(),
+            Event::Remove => self.remove_entry(),
+            Event::ClearQueue => self.clear_queue(),
+            Event::MoveEntry(off) => self.move_entry(off),
+            Event::Filter => self.start_filter(),
+            Event::Match(off) => {
+                let query = self.last_query.clone();
+                if !query.is_empty() {
+                    self.jump_match(&query, off);
+                }
+            }
+            Event::Search => self.search = Some(Search::default()),
         }
         self.move_current_panel(0);
     }
 
-    async fn download(&self) {
+    async fn download(&mut self) {
         let route = self.get_current_route();
         if let Some(p) = route.playlist {
             let source_name = self.sources[route.source.unwrap()].name.clone();
             let playlist_id = self.sources[route.source.unwrap()].playlist[p].playlist.id.clone();
-            self.send_request(&Request {client: source_name, ty: RequestType::Download(ObjRequest::Playlist(playlist_id))}).await;
+            self.send_request(&Request::new(
+                source_name,
+                RequestType::Download(ObjRequest::Playlist(playlist_id)),
+            ))
+            .await;
         }
     }
 
@@ -321,6 +705,13 @@ impl App {
             }
             Panel::Playlists => self.set_playlist_state(off),
             Panel::Songs => self.set_song_state(off),
+            Panel::Queue => {
+                let selected = match self.queue.len() {
+                    0 => None,
+                    len => Some(compute_new_i(self.queue_state.selected(), off, len)),
+                };
+                self.queue_state.select(selected);
+            }
         }
     }
 
@@ -334,6 +725,47 @@ impl App {
         playlist.add_songs(songs);
     }
 
+    fn update_playlist(&mut self, client: String, playlist: Playlist, songs: Vec<Song>) {
+        let source = match self.sources.iter_mut().find(|s| s.name == client) {
+            Some(source) => source,
+            None => return,
+        };
+        match source
+            .playlist
+            .iter_mut()
+            .find(|p| p.playlist.id == playlist.id)
+        {
+            Some(widget) => {
+                widget.name = playlist.title.clone();
+                widget.playlist = playlist;
+                widget.add_songs(songs);
+            }
+            None => {
+                let mut widget = PlaylistWidget::from_playlist(playlist);
+                widget.add_songs(songs);
+                source.playlist.push(widget);
+            }
+        }
+    }
+
+    /// Replaces every copy of `song` in the playlists and the queue of `client`
+    fn update_song(&mut self, client: String, song: Song) {
+        self.queue.update_song(&client, &song);
+        if let Some(now_playing) = self.now_playing.as_mut() {
+            let entry = &mut now_playing.entry;
+            if entry.source == client && entry.song.id == song.id {
+                entry.song = song.clone();
+            }
+        }
+        if let Some(source) = self.sources.iter_mut().find(|s| s.name == client) {
+            for playlist in source.playlist.iter_mut() {
+                for s in playlist.songs.iter_mut().filter(|s| s.id == song.id) {
+                    *s = song.clone();
+                }
+            }
+        }
+    }
+
     pub fn get_songs_widget(&self) -> List<'_> {
         let route = self.get_current_route();
         if let Some(s) = route.source {
@@ -344,7 +776,7 @@ impl App {
                     .iter()
                     .map(|s| ListItem::new(s.title.clone()))
                     .collect();
-                make_list(items, "Songs")
+                self.filtered_list(Panel::Songs, items, "Songs")
             } else {
                 make_list(vec![], "Songs")
             }
@@ -358,7 +790,7 @@ impl App {
         if let Some(s) = route.source {
             if let Some(p) = route.playlist {
                 let playlist = &self.sources[s].playlist[p];
-                playlist.state.clone()
+                self.filtered_state(Panel::Songs, playlist.state.clone())
             } else {
                 Default::default()
             }
@@ -367,30 +799,64 @@ impl App {
         }
     }
 
+    pub fn get_queue_widget(&self) -> List<'_> {
+        let items = self
+            .queue
+            .entries()
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let marker = if Some(i) == self.queue.current() {
+                    "> "
+                } else {
+                    "  "
+                };
+                ListItem::new(format!(
+                    "{}{} - {}",
+                    marker,
+                    entry.song.title,
+                    entry.song.artists.join(", ")
+                ))
+            })
+            .collect();
+        self.filtered_list(Panel::Queue, items, "Queue")
+    }
+
+    pub fn get_queue_state(&self) -> ListState {
+        self.filtered_state(Panel::Queue, self.queue_state.clone())
+    }
+
     pub fn get_options_widget(&self) -> List<'_> {
         let items = vec![
-            ListItem::new(format!("Auto: {}", self.player.is_in_playlist())),
-            ListItem::new("Repeat"),
-            ListItem::new(format!("Shuffle: {}", self.player.is_shuffled())),
+            ListItem::new(format!("Queue: {} songs", self.queue.len())),
+            ListItem::new(format!("Repeat: {:?}", self.repeat())),
+            ListItem::new(format!("Shuffle: {}", self.shuffled())),
             ListItem::new(format!("Volume: {}/100", self.player.get_volume())),
         ];
         make_list(items, "Options")
     }
 
-    pub fn get_info_widget(&self) -> List<'_> {
-        let route = self.get_current_route();
-        if let Some(song) = route.song {
-            let playlist = route.playlist.unwrap();
-            let source = route.source.unwrap();
-            let song = &self.sources[source].playlist[playlist].songs[song];
-            let items = vec![
-                ListItem::new(format!("Title:\n {}", song.title.clone())),
-                ListItem::new(format!("Artists:\n {}", song.artists.clone().join(","))),
-            ];
-            make_list(items, "Information")
-        } else {
-            make_list(vec![], "Information")
-        }
+    /// Information about the song playing
+    pub fn get_info_widget(&self) -> Paragraph<'_> {
+        let auth = self
+            .get_current_route()
+            .source
+            .map(|i| &self.sources[i])
+            .and_then(|source| Some((&source.name, source.auth_url.as_ref()?)));
+        let text = match (auth, self.now_playing()) {
+            // the url is wrapped rather than cut so that it can be copied
+            (Some((name, url)), _) => format!("Log in to {} at:\n{}", name, url),
+            (_, Some(entry)) => format!(
+                "Title:\n {}\nArtists:\n {}",
+                entry.song.title,
+                entry.song.artists.join(",")
+            ),
+            (_, None) => String::new(),
+        };
+        Paragraph::new(Text::from(text))
+            .block(Block::default().title("Information").borders(Borders::ALL))
+            .style(Style::default().fg(Color::White))
+            .wrap(Wrap { trim: false })
     }
 
     pub fn get_current_song(&self) -> Song {
@@ -405,57 +871,351 @@ impl App {
         }
     }
 
-    pub fn get_playing_song_info(&self) -> Song {
-        let state = self.player.get_state();
-        let parts: Vec<&str> = state.path.split('/').collect();
-        if parts.len() < 3 {
-            return Default::default()
+    pub fn set_pause_val(&mut self, val: bool)  {
+        if val == self.player.paused() {
+            return
+        } else {
+            self.player.playpause()
         }
-        let playlist = parts[parts.len() -2];
-        let source = parts[parts.len() -3];
-        if let Some(source) = self.sources.iter().find(|s| s.name == source) {
-            if let Some(playlist) = source.playlist.iter().find(|p| p.name == playlist) {
-                if let Some(song) = playlist.songs.iter().find(|s| s.title == state.title) {
-                    song.clone()
-                } else { Default::default()}
-            } else { Default::default() }
-        } else { Default::default() }
     }
 
-    fn auto(&mut self) {
+    /// Resumes the playback, or plays the current entry of the queue when stopped
+    pub async fn resume(&mut self) {
+        if !self.player.is_stopped() {
+            self.set_pause_val(false);
+            return;
+        }
+        if self.queue.current_entry().is_none() {
+            self.queue.jump(0);
+        }
+        self.play_current().await;
+    }
+
+    pub async fn play_pause(&mut self) {
+        if self.player.is_stopped() {
+            self.resume().await;
+        } else {
+            self.player.playpause();
+        }
+    }
+
+    /// Plays `uri` right away, queued after the current entry
+    pub async fn open_uri(&mut self, uri: String) {
+        let at = self.queue.current().map_or(0, |current| current + 1);
+        self.add_uri(uri, at, true).await;
+    }
+
+    /// Queues `uri` at `at`, and plays it right away when `play` is set
+    pub async fn add_uri(&mut self, uri: String, at: usize, play: bool) {
+        let song = Song {
+            title: uri.clone(),
+            id: uri.clone(),
+            url: uri,
+            ..Default::default()
+        };
+        let entry = QueueEntry::new(String::new(), String::new(), song);
+        let index = self.queue.insert(at, vec![entry]);
+        if play {
+            self.go_to(index).await;
+        }
+    }
+
+    /// Whether the keys go to the query of the filter or of the search
+    pub fn is_typing(&self) -> bool {
+        self.filter.is_some() || self.search.is_some()
+    }
+
+    pub fn handle_input(&mut self, input: Input) {
+        if self.search.is_some() {
+            self.search_input(input);
+        } else {
+            self.filter_input(input);
+        }
+    }
+
+    /// Selection of the focused panel
+    fn selected(&self) -> Option<usize> {
         let route = self.get_current_route();
-        if let Some(p) = route.playlist {
-            let source = &self.sources[route.source.unwrap()];
-            let playlist = &source.playlist[p].songs;
-            // a bit ugly, but there seems to be no good solution
-            // to convert from Vec<String> to Vec<&str>
-            let songs: Vec<&str> = playlist.iter().map(|s| String::as_ref(&s.url)).collect();
-            self.player.set_auto(&songs);
+        match self.current_panel {
+            Panel::Sources => route.source,
+            Panel::Playlists => route.playlist,
+            Panel::Songs => route.song,
+            Panel::Queue => self.queue_state.selected(),
+        }
+    }
+
+    fn select(&mut self, index: Option<usize>) {
+        let route = self.get_current_route();
+        match (self.current_panel, route.source, route.playlist) {
+            (Panel::Sources, _, _) => self.state.select(index),
+            (Panel::Playlists, Some(s), _) => self.sources[s].state.select(index),
+            (Panel::Songs, Some(s), Some(p)) => self.sources[s].playlist[p].state.select(index),
+            (Panel::Queue, _, _) => self.queue_state.select(index),
+            _ => (),
+        }
+    }
+
+    /// Positions and scores of the items of `panel` matching `query`
+    fn matches(&self, panel: Panel, query: &str) -> Vec<(usize, i64)> {
+        let route = self.get_current_route();
+        let scores: Vec<Option<i64>> = match (panel, route.source, route.playlist) {
+            (Panel::Sources, _, _) => self
+                .sources
+                .iter()
+                .map(|source| search::fuzzy_score(query, &source.name))
+                .collect(),
+            (Panel::Playlists, Some(s), _) => self.sources[s]
+                .playlist
+                .iter()
+                .map(|p| search::playlist_score(query, &p.playlist))
+                .collect(),
+            (Panel::Songs, Some(s), Some(p)) => self.sources[s].playlist[p]
+                .songs
+                .iter()
+                .map(|song| search::song_score(query, song))
+                .collect(),
+            (Panel::Queue, _, _) => self
+                .queue
+                .entries()
+                .iter()
+                .map(|entry| search::song_score(query, &entry.song))
+                .collect(),
+            _ => vec![],
+        };
+        scores
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, score)| Some((i, score?)))
+            .collect()
+    }
+
+    /// The items of `panel`, only the ones matching the filter while it is typed in it
+    fn filtered_list<'a>(&self, panel: Panel, items: Vec<ListItem<'a>>, title: &str) -> List<'a> {
+        match &self.filter {
+            Some(filter) if self.current_panel == panel => {
+                let matches = self.matches(panel, &filter.query);
+                let items = items
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| matches.binary_search_by_key(i, |(m, _)| *m).is_ok())
+                    .map(|(_, item)| item)
+                    .collect();
+                make_list(items, format!("{} /{}", title, filter.query))
+            }
+            _ => make_list(items, title.to_string()),
+        }
+    }
+
+    /// `state` of `panel`, pointing among the items the filter keeps while it is typed in it
+    fn filtered_state(&self, panel: Panel, state: ListState) -> ListState {
+        match &self.filter {
+            Some(filter) if self.current_panel == panel => {
+                let matches = self.matches(panel, &filter.query);
+                let mut filtered = ListState::default();
+                filtered.select(
+                    state
+                        .selected()
+                        .and_then(|selected| matches.iter().position(|(i, _)| *i == selected)),
+                );
+                filtered
+            }
+            _ => state,
         }
     }
 
-    pub fn is_auto(&self) -> bool {
-        self.player.is_in_playlist()
+    fn start_filter(&mut self) {
+        self.filter = Some(Filter {
+            query: String::new(),
+            previous: self.selected(),
+        });
     }
 
-    pub fn set_auto_val(&mut self, val: bool)  {
-        if val == self.player.is_in_playlist() {
+    /// Types into the filter, selecting the best match of the focused panel as it changes
+    fn filter_input(&mut self, input: Input) {
+        let Some(mut filter) = self.filter.take() else {
             return;
-        } else {
-            self.auto()
+        };
+        match input {
+            Input::Char(c) => filter.query.push(c),
+            Input::Backspace => {
+                filter.query.pop();
+            }
+            Input::Up | Input::Down => {
+                let off = if matches!(input, Input::Up) { -1 } else { 1 };
+                self.jump_match(&filter.query, off);
+                self.filter = Some(filter);
+                return;
+            }
+            Input::Enter => {
+                self.last_query = filter.query;
+                return;
+            }
+            Input::Escape => {
+                self.select(filter.previous);
+                return;
+            }
+        }
+        let best = self
+            .matches(self.current_panel, &filter.query)
+            .into_iter()
+            .min_by_key(|(i, score)| (-score, *i));
+        match best {
+            _ if filter.query.is_empty() => self.select(filter.previous),
+            Some((i, _)) => self.select(Some(i)),
+            None => (),
         }
+        self.filter = Some(filter);
     }
 
-    pub fn set_pause_val(&mut self, val: bool)  {
-        if val == self.player.paused() {
-            return
+    /// Selects the next item of the focused panel matching `query`,
+    /// the previous one when `off` is negative, going around the ends
+    fn jump_match(&mut self, query: &str, off: i32) {
+        let matches: Vec<usize> = self
+            .matches(self.current_panel, query)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
+        let selected = self.selected();
+        let next = if off < 0 {
+            let mut before = matches.iter().rev();
+            before
+                .find(|&&i| selected.is_none_or(|s| i < s))
+                .or(matches.last())
         } else {
-            self.player.playpause()
+            let mut after = matches.iter();
+            after
+                .find(|&&i| selected.is_none_or(|s| i > s))
+                .or(matches.first())
+        };
+        if let Some(&i) = next {
+            self.select(Some(i));
         }
     }
+
+    /// Every playlist and song matching `query`, the best ones first
+    fn find(&self, query: &str) -> Vec<Found> {
+        let mut results = vec![];
+        for (s, source) in self.sources.iter().enumerate() {
+            for (p, widget) in source.playlist.iter().enumerate() {
+                if let Some(score) = search::playlist_score(query, &widget.playlist) {
+                    results.push(Found {
+                        source: s,
+                        playlist: p,
+                        song: None,
+                        label: format!("Playlist {} ({})", widget.name, source.name),
+                        score,
+                    });
+                }
+                for (i, song) in widget.songs.iter().enumerate() {
+                    if let Some(score) = search::song_score(query, song) {
+                        let label = format!(
+                            "{} - {} ({} / {})",
+                            song.title,
+                            song.artists.join(", "),
+                            source.name,
+                            widget.name
+                        );
+                        results.push(Found {
+                            source: s,
+                            playlist: p,
+                            song: Some(i),
+                            label,
+                            score,
+                        });
+                    }
+                }
+            }
+        }
+        // the sort is stable, the results as good as each other stay in the order of the sources
+        results.sort_by_key(|found| -found.score);
+        results.truncate(MAX_RESULTS);
+        results
+    }
+
+    /// Types into the search, or goes to the playlist of the result chosen
+    fn search_input(&mut self, input: Input) {
+        let Some(mut search) = self.search.take() else {
+            return;
+        };
+        match input {
+            Input::Char(c) => search.query.push(c),
+            Input::Backspace => {
+                search.query.pop();
+            }
+            Input::Up | Input::Down if search.results.is_empty() => (),
+            Input::Up => {
+                let selected = compute_new_i(search.state.selected(), -1, search.results.len());
+                search.state.select(Some(selected));
+            }
+            Input::Down => {
+                let selected = compute_new_i(search.state.selected(), 1, search.results.len());
+                search.state.select(Some(selected));
+            }
+            Input::Enter => {
+                let selected = search.state.selected();
+                if let Some(found) = selected.and_then(|i| search.results.get(i)) {
+                    self.show_found(found);
+                }
+                return;
+            }
+            Input::Escape => return,
+        }
+        if matches!(input, Input::Char(_) | Input::Backspace) {
+            search.results = match search.query.trim() {
+                "" => vec![],
+                query => self.find(query),
+            };
+            let first = (!search.results.is_empty()).then_some(0);
+            search.state.select(first);
+        }
+        self.search = Some(search);
+    }
+
+    /// Focuses the playlist of `found`, on its song when it is one
+    fn show_found(&mut self, found: &Found) {
+        // the playlists may have changed while the search was open
+        let Some(source) = self.sources.get_mut(found.source) else {
+            return;
+        };
+        let Some(playlist) = source.playlist.get_mut(found.playlist) else {
+            return;
+        };
+        self.current_panel = match found.song {
+            Some(song) if song < playlist.songs.len() => {
+                playlist.state.select(Some(song));
+                Panel::Songs
+            }
+            _ => Panel::Playlists,
+        };
+        source.state.select(Some(found.playlist));
+        self.state.select(Some(found.source));
+    }
+
+    /// The results of the search, `None` when it is closed
+    pub fn get_search_widget(&self) -> Option<(List<'_>, ListState)> {
+        let search = self.search.as_ref()?;
+        let items = search
+            .results
+            .iter()
+            .map(|found| ListItem::new(found.label.clone()))
+            .collect();
+        let title = format!("Search: {}", search.query);
+        Some((make_list(items, title), search.state.clone()))
+    }
+
+    /// Title of the playlist `id` of `source`
+    pub fn playlist_title(&self, source: &str, id: &str) -> Option<String> {
+        self.sources
+            .iter()
+            .find(|s| s.name == source)?
+            .playlist
+            .iter()
+            .find(|p| p.playlist.id == id)
+            .map(|p| p.name.clone())
+    }
 }
 
-fn make_list<'a>(items: Vec<ListItem<'a>>, title: &'a str) -> List<'a> {
+fn make_list<'a>(items: Vec<ListItem<'a>>, title: impl Into<Spans<'a>>) -> List<'a> {
     List::new(items)
         .block(Block::default().title(title).borders(Borders::ALL))
         .style(Style::default().fg(Color::White))
@@ -486,3 +1246,92 @@ fn compute_new_i(i: Option<usize>, off: i32, max: usize) -> usize {
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::player::fake::{Clock, FakeBackend};
+
+    /// How long the tracks of the fake player last
+    pub const TRACK: Duration = Duration::from_secs(10);
+
+    /// An app whose queue holds the songs at `urls`, playing the first one with a fake
+    /// player following `clock`
+    pub async fn playing(clock: &Clock, urls: &[&str]) -> App {
+        let backend = FakeBackend::with_clock(clock.clone(), TRACK);
+        let player = Player::with_backend(Box::new(backend));
+        let mut app = App::new(Box::new(tokio::io::sink()), None, player, Queue::load(None));
+        for (i, url) in urls.iter().enumerate() {
+            app.add_uri(url.to_string(), i, false).await;
+        }
+        app.go_to(0).await;
+        app.tick().await;
+        app
+    }
+
+    /// Lets the song playing end, and the app move on
+    async fn finish(app: &mut App, clock: &Clock) {
+        clock.advance(TRACK);
+        app.tick().await;
+        app.tick().await;
+    }
+
+    fn playing_url(app: &App) -> Option<String> {
+        app.now_playing().map(|entry| entry.song.url.clone())
+    }
+
+    #[tokio::test]
+    async fn advances_on_track_end() {
+        let clock = Clock::manual();
+        let mut app = playing(&clock, &["a", "b"]).await;
+        clock.advance(TRACK / 2);
+        app.tick().await;
+        assert_eq!(playing_url(&app).as_deref(), Some("a"));
+        finish(&mut app, &clock).await;
+        assert_eq!(playing_url(&app).as_deref(), Some("b"));
+        assert_eq!(app.player.get_state().time_pos, 0);
+        finish(&mut app, &clock).await;
+        assert_eq!(playing_url(&app), None);
+        assert!(app.player.is_stopped());
+    }
+
+    #[tokio::test]
+    async fn repeat() {
+        let clock = Clock::manual();
+        let mut app = playing(&clock, &["a", "b"]).await;
+        app.set_repeat(Repeat::One);
+        finish(&mut app, &clock).await;
+        assert_eq!(playing_url(&app).as_deref(), Some("a"));
+        app.set_repeat(Repeat::All);
+        finish(&mut app, &clock).await;
+        finish(&mut app, &clock).await;
+        assert_eq!(playing_url(&app).as_deref(), Some("a"));
+        assert_eq!(app.queue.current(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn shuffle() {
+        let clock = Clock::manual();
+        let urls = ["a", "b", "c", "d", "e"];
+        let mut app = playing(&clock, &urls).await;
+        app.set_shuffled(true);
+        assert!(app.shuffled());
+        assert_eq!(playing_url(&app).as_deref(), Some("a"));
+        // the song playing goes first, the others are all played once after it
+        assert_eq!(app.queue.current(), Some(0));
+        let mut played = vec![playing_url(&app).unwrap()];
+        for _ in 1..urls.len() {
+            finish(&mut app, &clock).await;
+            played.extend(playing_url(&app));
+        }
+        played.sort();
+        assert_eq!(played, urls);
+        app.set_shuffled(false);
+        let unshuffled: Vec<_> = app.queue.entries().iter().map(|e| &e.song.url).collect();
+        assert_eq!(unshuffled, urls);
+        let current = &app.queue.entries()[app.queue.current().unwrap()];
+        assert_eq!(Some(current.song.url.clone()), playing_url(&app));
+    }
+}
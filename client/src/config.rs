@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use music_server::request::Credentials;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Name of the server used when none is given on the command line
+    pub default_server: String,
+    pub servers: BTreeMap<String, ServerConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ServerConfig {
+    /// `host:port`, `tls:host:port` or `unix:/path/to/socket`
+    pub address: String,
+    /// PEM file of the authority that signed the server certificate,
+    /// the usual authorities are trusted when empty
+    #[serde(default)]
+    pub ca_certificate: String,
+    /// Token to log in with
+    #[serde(default)]
+    pub token: String,
+    /// Name and password to log in with, when there is no token
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub password: String,
+}
+
+impl ServerConfig {
+    pub fn credentials(&self) -> Option<Credentials> {
+        if !self.token.is_empty() {
+            Some(Credentials::Token(self.token.clone()))
+        } else if !self.user.is_empty() {
+            Some(Credentials::Password {
+                user: self.user.clone(),
+                password: self.password.clone(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl std::default::Default for Config {
+    fn default() -> Self {
+        let local = ServerConfig {
+            address: "127.0.0.1:8080".to_string(),
+            ..Default::default()
+        };
+        Self {
+            default_server: "local".to_string(),
+            servers: BTreeMap::from([("local".to_string(), local)]),
+        }
+    }
+}
+
+impl Config {
+    /// The server named `server`, or the default one.
+    /// A name that is not configured is used as an address.
+    pub fn server(&self, server: Option<&str>) -> ServerConfig {
+        let server = server.unwrap_or(&self.default_server);
+        self.servers
+            .get(server)
+            .cloned()
+            .unwrap_or_else(|| ServerConfig {
+                address: server.to_string(),
+                ..Default::default()
+            })
+    }
+}
+
+/// Configuration file given on the command line, the default location is used otherwise
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
+
+/// File keeping the play queue, next to the configuration
+pub fn queue_path() -> Option<PathBuf> {
+    let config = match CONFIG_PATH.get() {
+        Some(path) => path.clone(),
+        None => confy::get_configuration_file_path("music_client", None).ok()?,
+    };
+    Some(config.with_file_name("queue.json"))
+}
+
+pub fn get_config() -> Config {
+    match CONFIG_PATH.get() {
+        Some(path) => confy::load_path(path).unwrap(),
+        None => confy::load("music_client", None).unwrap(),
+    }
+}
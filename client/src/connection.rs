@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use music_server::request::{
+    self, get_answer, Answer, AnswerType, Credentials, ErrorType, Request, RequestType,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::{rustls, TlsConnector};
+
+use crate::config::ServerConfig;
+
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Connects to `server` and logs in when it has credentials
+pub async fn connect(server: &ServerConfig) -> io::Result<(Reader, Writer)> {
+    let (mut reader, mut writer) = open(server).await?;
+    if let Some(credentials) = server.credentials() {
+        login(&mut reader, &mut writer, credentials).await?;
+    }
+    Ok((reader, writer))
+}
+
+async fn open(server: &ServerConfig) -> io::Result<(Reader, Writer)> {
+    let address = server.address.as_str();
+    if let Some(path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            let (rx, tx) = tokio::io::split(UnixStream::connect(path).await?);
+            return Ok((Box::new(rx), Box::new(tx)));
+        }
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unix sockets are not supported on this platform: {}", path),
+        ));
+    }
+    if let Some(address) = address.strip_prefix("tls:") {
+        let connector = tls_connector(&server.ca_certificate)?;
+        let host = address
+            .rsplit_once(':')
+            .map_or(address, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let name = rustls::ServerName::try_from(host)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let stream = connector
+            .connect(name, TcpStream::connect(address).await?)
+            .await?;
+        let (rx, tx) = tokio::io::split(stream);
+        return Ok((Box::new(rx), Box::new(tx)));
+    }
+    let (rx, tx) = TcpStream::connect(address).await?.into_split();
+    Ok((Box::new(rx), Box::new(tx)))
+}
+
+/// Trusts the authority in `ca_certificate`, or the usual ones when it is empty
+fn tls_connector(ca_certificate: &str) -> io::Result<TlsConnector> {
+    let mut roots = rustls::RootCertStore::empty();
+    if ca_certificate.is_empty() {
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+    } else {
+        let mut reader = BufReader::new(File::open(ca_certificate)?);
+        for certificate in rustls_pemfile::certs(&mut reader)? {
+            roots
+                .add(&rustls::Certificate(certificate))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Sends the credentials and waits for the server to accept them
+async fn login(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    credentials: Credentials,
+) -> io::Result<()> {
+    let request = Request::new("server".to_string(), RequestType::Login(credentials));
+    let message = request::prepare_message(serde_json::to_string(&request)?);
+    writer.write_all(&message).await?;
+    while let Some(answer) = read_answer(reader).await? {
+        match answer.data {
+            AnswerType::LoggedIn(_) => return Ok(()),
+            AnswerType::Error(ErrorType::PermissionDenied(err)) => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, err))
+            }
+            _ => (),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "The server closed the connection",
+    ))
+}
+
+/// Reads the next answer sent by the server, `None` once the connection is closed
+pub async fn read_answer(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Answer>> {
+    loop {
+        // an answer is preceded by its size
+        let mut size = [0; 8];
+        match stream.read_exact(&mut size).await {
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let size = usize::from_be_bytes(size);
+        if size == 0 {
+            return Ok(None);
+        }
+        let mut buf = vec![0; size];
+        stream.read_exact(&mut buf).await?;
+        let message = match String::from_utf8(buf) {
+            Ok(val) => val,
+            Err(_) => continue,
+        };
+        match get_answer(message).await {
+            Ok(answer) => return Ok(Some(answer)),
+            Err(err) => eprintln!("error while parsing answer {}", err),
+        }
+    }
+}
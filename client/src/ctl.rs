@@ -0,0 +1,321 @@
+use std::{error::Error, time::Duration};
+
+use clap::{Args, Subcommand};
+use music_server::request::{
+    self, Answer, AnswerType, EventType, ObjRequest, PlayerRequest, PlayerStatus, Request,
+    RequestType, Topic,
+};
+use music_server::source_types::{Playlist, Song};
+use serde::Serialize;
+use tokio::{
+    io::AsyncWriteExt,
+    time::{timeout_at, Instant},
+};
+
+use crate::connection::{read_answer, Reader, Writer};
+
+/// How long to wait for more sources once one answered
+const SOURCES_IDLE: Duration = Duration::from_secs(1);
+
+type CtlResult<T> = Result<T, Box<dyn Error>>;
+
+#[derive(Args)]
+pub struct CtlArgs {
+    /// Seconds to wait for an answer
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+    #[command(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Subcommand)]
+pub enum CtlCommand {
+    /// Lists the sources of the server
+    Sources,
+    /// Lists the playlists of a source
+    Playlists { source: String },
+    /// Prints the songs of a playlist, given by id or title
+    Songs { source: String, playlist: String },
+    /// Downloads a playlist, given by id or title
+    Download {
+        source: String,
+        playlist: String,
+        /// Waits for the download to finish, printing the progress on stderr
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Searches the songs of the loaded playlists
+    Search {
+        query: String,
+        /// Only searches this source
+        #[arg(long)]
+        source: Option<String>,
+    },
+    /// Controls the player of the server, printing its status
+    Player {
+        #[command(subcommand)]
+        command: PlayerCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PlayerCommand {
+    /// Prints the status of the player
+    Status,
+    /// Plays the song at this position of the queue, or resumes the playback
+    Play {
+        position: Option<usize>,
+    },
+    Pause,
+    /// Pauses or resumes the playback
+    Toggle,
+    Stop,
+    Next,
+    Previous,
+    /// Seeks to this many seconds in the current song
+    Seek {
+        seconds: f64,
+    },
+    /// Sets the volume, from 0 to 100
+    Volume {
+        volume: u32,
+    },
+    /// Adds songs of a source to the queue, given by id
+    Enqueue {
+        source: String,
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Removes the song at this position of the queue
+    Remove {
+        position: usize,
+    },
+    /// Empties the queue
+    Clear,
+}
+
+impl PlayerCommand {
+    /// The client named in the request, and the request
+    fn request(self) -> (String, PlayerRequest) {
+        let request = match self {
+            PlayerCommand::Status => PlayerRequest::Status,
+            PlayerCommand::Play { position } => PlayerRequest::Play(position),
+            PlayerCommand::Pause => PlayerRequest::Pause,
+            PlayerCommand::Toggle => PlayerRequest::Toggle,
+            PlayerCommand::Stop => PlayerRequest::Stop,
+            PlayerCommand::Next => PlayerRequest::Next,
+            PlayerCommand::Previous => PlayerRequest::Previous,
+            PlayerCommand::Seek { seconds } => {
+                PlayerRequest::Seek(Duration::from_secs_f64(seconds.max(0.)))
+            }
+            PlayerCommand::Volume { volume } => PlayerRequest::SetVolume(volume.min(100)),
+            // the songs are taken from the source the request is sent to
+            PlayerCommand::Enqueue { source, ids } => {
+                return (source, PlayerRequest::Enqueue(ids));
+            }
+            PlayerCommand::Remove { position } => PlayerRequest::Remove(position),
+            PlayerCommand::Clear => PlayerRequest::Clear,
+        };
+        ("player".to_string(), request)
+    }
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    source: String,
+    playlist: Playlist,
+    song: Song,
+}
+
+struct Ctl {
+    reader: Reader,
+    writer: Writer,
+    timeout: Duration,
+}
+
+impl Ctl {
+    async fn send(&mut self, client: &str, ty: RequestType) -> CtlResult<()> {
+        let request = Request::new(client.to_string(), ty);
+        let message = request::prepare_message(serde_json::to_string(&request)?);
+        self.writer.write_all(&message).await?;
+        Ok(())
+    }
+
+    /// Next answer received before `deadline`, `None` on timeout
+    async fn next_answer(&mut self, deadline: Instant) -> CtlResult<Option<Answer>> {
+        match timeout_at(deadline, read_answer(&mut self.reader)).await {
+            Ok(Ok(Some(answer))) => Ok(Some(answer)),
+            Ok(Ok(None)) => Err("The server closed the connection".into()),
+            Ok(Err(err)) => Err(err.into()),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Waits for the first answer of `source` accepted by `accept`.
+    /// Errors sent by the source end the wait, its messages are printed on stderr.
+    async fn expect<T>(
+        &mut self,
+        source: &str,
+        mut accept: impl FnMut(AnswerType) -> Option<T>,
+    ) -> CtlResult<T> {
+        let deadline = Instant::now() + self.timeout;
+        while let Some(answer) = self.next_answer(deadline).await? {
+            if !answer.client.eq_ignore_ascii_case(source) {
+                continue;
+            }
+            match answer.data {
+                AnswerType::Error(err) => return Err(format!("{}: {:?}", source, err).into()),
+                AnswerType::Message(message) => eprintln!("{}: {}", source, message),
+                data => {
+                    if let Some(value) = accept(data) {
+                        return Ok(value);
+                    }
+                }
+            }
+        }
+        Err(format!("No answer from {}", source).into())
+    }
+
+    async fn sources(&mut self) -> CtlResult<Vec<String>> {
+        self.send("all", RequestType::GetAll(ObjRequest::ClientList))
+            .await?;
+        let mut sources = vec![];
+        let mut deadline = Instant::now() + self.timeout;
+        while let Some(answer) = self.next_answer(deadline).await? {
+            if let AnswerType::Client(name) = answer.data {
+                sources.push(name);
+                deadline = Instant::now() + SOURCES_IDLE;
+            }
+        }
+        Ok(sources)
+    }
+
+    async fn playlists(&mut self, source: &str) -> CtlResult<Vec<Playlist>> {
+        self.send(source, RequestType::GetAll(ObjRequest::PlaylistList))
+            .await?;
+        self.expect(source, |data| match data {
+            AnswerType::PlaylistList(playlists) => Some(playlists),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Finds a playlist by id or title
+    async fn find_playlist(&mut self, source: &str, playlist: &str) -> CtlResult<Playlist> {
+        self.playlists(source)
+            .await?
+            .into_iter()
+            .find(|p| p.id == playlist || p.title == playlist)
+            .ok_or_else(|| format!("No playlist {} in {}", playlist, source).into())
+    }
+
+    async fn songs(&mut self, source: &str, playlist: &str) -> CtlResult<Vec<Song>> {
+        let playlist = self.find_playlist(source, playlist).await?;
+        self.send(
+            source,
+            RequestType::GetAll(ObjRequest::Playlist(playlist.id.clone())),
+        )
+        .await?;
+        self.expect(source, |data| match data {
+            AnswerType::Songs(p, songs) if p.id == playlist.id => Some(songs),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn download(&mut self, source: &str, playlist: &str, wait: bool) -> CtlResult<()> {
+        let playlist = self.find_playlist(source, playlist).await?;
+        self.send("all", RequestType::Subscribe(vec![Topic::Download]))
+            .await?;
+        self.send(
+            source,
+            RequestType::Download(ObjRequest::Playlist(playlist.id.clone())),
+        )
+        .await?;
+        loop {
+            // the server sends a first progress event once the download started
+            let (done, total) = self
+                .expect(source, |data| match data {
+                    AnswerType::Event(EventType::DownloadProgress {
+                        playlist: title,
+                        done,
+                        total,
+                    }) if title == playlist.title => Some((done, total)),
+                    _ => None,
+                })
+                .await?;
+            eprintln!("{}: {}/{}", playlist.title, done, total);
+            if !wait || done >= total {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn search(
+        &mut self,
+        query: &str,
+        source: Option<String>,
+    ) -> CtlResult<Vec<SearchResult>> {
+        let sources = match source {
+            Some(source) => vec![source],
+            None => self.sources().await?,
+        };
+        let mut results = vec![];
+        for source in sources {
+            self.send(&source, RequestType::Search(query.to_string()))
+                .await?;
+            let found = self
+                .expect(&source, |data| match data {
+                    AnswerType::SearchResults(found) => Some(found),
+                    _ => None,
+                })
+                .await?;
+            results.extend(found.into_iter().map(|(playlist, song)| SearchResult {
+                source: source.clone(),
+                playlist,
+                song,
+            }));
+        }
+        Ok(results)
+    }
+
+    async fn player(&mut self, command: PlayerCommand) -> CtlResult<PlayerStatus> {
+        let (client, request) = command.request();
+        self.send(&client, RequestType::Player(request)).await?;
+        self.expect(&client, |data| match data {
+            AnswerType::PlayerStatus(status) => Some(status),
+            _ => None,
+        })
+        .await
+    }
+}
+
+/// Runs a single command, printing its result as json on stdout
+pub async fn run(args: CtlArgs, reader: Reader, writer: Writer) -> CtlResult<()> {
+    let mut ctl = Ctl {
+        reader,
+        writer,
+        timeout: Duration::from_secs(args.timeout),
+    };
+    let output = match args.command {
+        CtlCommand::Sources => serde_json::to_string(&ctl.sources().await?)?,
+        CtlCommand::Playlists { source } => serde_json::to_string(&ctl.playlists(&source).await?)?,
+        CtlCommand::Songs { source, playlist } => {
+            serde_json::to_string(&ctl.songs(&source, &playlist).await?)?
+        }
+        CtlCommand::Download {
+            source,
+            playlist,
+            wait,
+        } => {
+            ctl.download(&source, &playlist, wait).await?;
+            return Ok(());
+        }
+        CtlCommand::Search { query, source } => {
+            serde_json::to_string(&ctl.search(&query, source).await?)?
+        }
+        CtlCommand::Player { command } => serde_json::to_string(&ctl.player(command).await?)?,
+    };
+    println!("{}", output);
+    Ok(())
+}
@@ -1,17 +1,35 @@
 use std::collections::HashMap;
-use std::{sync::Arc, future::pending};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tokio::sync::Mutex;
-use zbus::{ConnectionBuilder, dbus_interface};
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value};
+use zbus::{dbus_interface, fdo, ConnectionBuilder, SignalContext};
 
 use crate::app::{App, Event};
+use crate::queue::{QueueEntry, Repeat};
+use crate::url;
 
-struct BaseInterface {
-}
+use self::playlists::PlaylistsInterface;
+use self::track_list::TrackListInterface;
+
+mod playlists;
+mod track_list;
+
+const PATH: &str = "/org/mpris/MediaPlayer2";
+/// Start of the track ids, followed by the id of the entry.
+/// The ids are under a path of our own, the mpris one is reserved by the specification.
+const TRACK_PATH: &str = "/com/github/sofamaniac/yauma/track/";
+/// Track id telling that nothing is playing
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+/// How often the player is compared to what the desktop was told about it
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct BaseInterface {}
 
 #[dbus_interface(name = "org.mpris.MediaPlayer2")]
 impl BaseInterface {
-    
+    #[dbus_interface(property)]
     fn identity(&self) -> String {
         "yauma".to_string()
     }
@@ -32,21 +50,138 @@ impl BaseInterface {
 
     #[dbus_interface(property)]
     fn has_track_list(&self) -> bool {
-        false
+        true
     }
 
     #[dbus_interface(property)]
     fn supported_uri_schemes(&self) -> Vec<String> {
-        Default::default()
+        vec!["file".to_string(), "http".to_string(), "https".to_string()]
     }
 
     #[dbus_interface(property)]
     fn supported_mime_types(&self) -> Vec<String> {
-        Default::default()
+        [
+            "audio/mpeg",
+            "audio/ogg",
+            "audio/webm",
+            "audio/mp4",
+            "audio/flac",
+        ]
+        .iter()
+        .map(|mime| mime.to_string())
+        .collect()
     }
 }
-struct PlayerInterface{
-    app: Arc<Mutex<App>>
+struct PlayerInterface {
+    app: Arc<Mutex<App>>,
+}
+
+/// Object path of `entry`, unique within the queue
+fn track_id(entry: &QueueEntry) -> OwnedObjectPath {
+    track_path(entry.id)
+}
+
+/// Object path of the entry of the queue numbered `id`
+fn track_path(id: u64) -> OwnedObjectPath {
+    let path = format!("{}{}", TRACK_PATH, id);
+    ObjectPath::from_string_unchecked(path).into()
+}
+
+/// Id of the entry of the queue `track_id` stands for
+fn entry_id(track_id: &str) -> Option<u64> {
+    track_id.strip_prefix(TRACK_PATH)?.parse().ok()
+}
+
+fn no_track() -> OwnedObjectPath {
+    ObjectPath::from_static_str_unchecked(NO_TRACK).into()
+}
+
+/// What the player is given to play `uri`, which mpv takes as a plain path for files
+fn uri_path(uri: String) -> fdo::Result<String> {
+    if let Some(rest) = uri.strip_prefix("file://") {
+        // the host is empty, or localhost, for the files of this machine
+        let path = rest.strip_prefix("localhost").unwrap_or(rest);
+        if !path.starts_with('/') {
+            let message = format!("Not a local file {}", uri);
+            return Err(fdo::Error::NotSupported(message));
+        }
+        Ok(url::decode(path))
+    } else if uri.starts_with("http://") || uri.starts_with("https://") {
+        Ok(uri)
+    } else {
+        let message = format!("Unsupported uri {}", uri);
+        Err(fdo::Error::NotSupported(message))
+    }
+}
+
+/// Where the desktop can find the song of `entry`, without the token of the user
+fn track_url(entry: &QueueEntry) -> Option<String> {
+    let song = &entry.song;
+    if song.downloaded && std::path::Path::new(&song.url).exists() {
+        let path = std::fs::canonicalize(&song.url).ok()?;
+        let path = url::encode_path(&path.to_string_lossy());
+        Some(format!("file://{}", path))
+    } else if !song.stream_url.is_empty() {
+        Some(song.stream_url.clone())
+    } else {
+        let remote = song.url.starts_with("http://") || song.url.starts_with("https://");
+        remote.then(|| song.url.clone())
+    }
+}
+
+fn art_url(entry: &QueueEntry) -> Option<String> {
+    match entry.source.as_str() {
+        "Youtube" => Some(format!(
+            "https://i.ytimg.com/vi/{}/hqdefault.jpg",
+            entry.song.id
+        )),
+        _ => None,
+    }
+}
+
+/// The metadata of `entry`, with the keys the mpris specification defines
+fn metadata(app: &App, entry: &QueueEntry) -> HashMap<String, OwnedValue> {
+    let song = &entry.song;
+    let mut res: HashMap<String, OwnedValue> = HashMap::new();
+    let mut insert = |key: &str, value: Value| {
+        res.insert(key.to_string(), value.into());
+    };
+    insert("mpris:trackid", Value::from(track_id(entry).into_inner()));
+    insert("mpris:length", Value::I64(song.duration.as_micros() as i64));
+    insert("xesam:title", Value::from(song.title.clone()));
+    insert("xesam:artist", Value::from(song.artists.clone()));
+    if !song.tags.is_empty() {
+        insert("xesam:genre", Value::from(song.tags.clone()));
+    }
+    if let Some(album) = app.playlist_title(&entry.source, &entry.playlist) {
+        insert("xesam:album", Value::from(album));
+    }
+    if let Some(url) = track_url(entry) {
+        insert("xesam:url", Value::from(url));
+    }
+    if let Some(art) = art_url(entry) {
+        insert("mpris:artUrl", Value::from(art));
+    }
+    res
+}
+
+fn playback_status(app: &App) -> &'static str {
+    if app.player.is_stopped() {
+        "Stopped"
+    } else if app.player.paused() {
+        "Paused"
+    } else {
+        "Playing"
+    }
+}
+
+/// Position in the song playing, in microseconds
+fn position(app: &App) -> i64 {
+    if app.player.is_stopped() {
+        0
+    } else {
+        app.player.get_state().time_pos * 1_000_000
+    }
 }
 
 #[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
@@ -60,86 +195,153 @@ impl PlayerInterface {
     async fn pause(&self) {
         self.app.lock().await.set_pause_val(true);
     }
-    async fn unpause(&self) {
-        self.app.lock().await.set_pause_val(false);
-    }
     async fn play_pause(&self) {
-        self.app.lock().await.player.playpause();
+        self.app.lock().await.play_pause().await;
     }
     async fn play(&self) {
-        self.unpause().await;
+        self.app.lock().await.resume().await;
     }
     async fn stop(&self) {
-        self.app.lock().await.player.stop();
+        self.app.lock().await.stop();
+    }
+
+    /// Seeks `offset` microseconds forward, backward when negative
+    async fn seek(&self, offset: i64) {
+        let mut app = self.app.lock().await;
+        if !app.player.is_stopped() {
+            app.player.seek(offset / 1_000_000);
+        }
+    }
+
+    /// Seeks to `position` microseconds, unless another track plays by now
+    async fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        let mut app = self.app.lock().await;
+        let current = match app.now_playing() {
+            Some(entry) => self::track_id(entry),
+            None => return,
+        };
+        let state = app.player.get_state();
+        let position = position / 1_000_000;
+        let past_end = state.duration > 0 && position > state.duration;
+        if track_id.as_str() != current.as_str() || position < 0 || past_end {
+            return;
+        }
+        app.player.seek(position - state.time_pos);
+    }
+
+    async fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        let path = uri_path(uri)?;
+        self.app.lock().await.open_uri(path).await;
+        Ok(())
     }
-    async fn seek(&self) {}
-    async fn set_position(&self) {}
-    async fn open_uri(&self) {}
 
-    
+    #[dbus_interface(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
     #[dbus_interface(property)]
     async fn playback_status(&self) -> String {
-        let app = self.app.lock().await;
-        if app.player.is_stopped() {
-            "Stopped".to_string()
-        } else if app.player.paused() {
-            "Paused".to_string()
-        } else {
-            "Playing".to_string()
-        }
+        playback_status(&*self.app.lock().await).to_string()
     }
 
     #[dbus_interface(property)]
     async fn loop_status(&self) -> String {
-        if self.app.lock().await.is_auto() {
-            "Playlist".to_string()
-        } else {
-            "None".to_string()
+        match self.app.lock().await.repeat() {
+            Repeat::Off => "None".to_string(),
+            Repeat::One => "Track".to_string(),
+            Repeat::All => "Playlist".to_string(),
         }
     }
 
     #[dbus_interface(property)]
-    fn rate(&self) -> f32 {
+    async fn set_loop_status(&self, status: String) -> zbus::Result<()> {
+        let repeat = match status.as_str() {
+            "None" => Repeat::Off,
+            "Track" => Repeat::One,
+            "Playlist" => Repeat::All,
+            _ => {
+                let message = format!("Unknown loop status {}", status);
+                return Err(fdo::Error::InvalidArgs(message).into());
+            }
+        };
+        self.app.lock().await.set_repeat(repeat);
+        Ok(())
+    }
+
+    #[dbus_interface(property)]
+    async fn shuffle(&self) -> bool {
+        self.app.lock().await.shuffled()
+    }
+
+    #[dbus_interface(property)]
+    async fn set_shuffle(&self, shuffle: bool) {
+        self.app.lock().await.set_shuffled(shuffle);
+    }
+
+    /// Volume from 0 to 1, mpv goes from 0 to 100
+    #[dbus_interface(property)]
+    async fn volume(&self) -> f64 {
+        self.app.lock().await.player.get_volume() as f64 / 100.
+    }
+
+    #[dbus_interface(property)]
+    async fn set_volume(&self, volume: f64) {
+        let volume = (volume.max(0.) * 100.).round() as i64;
+        self.app.lock().await.player.set_volume(volume);
+    }
+
+    #[dbus_interface(property)]
+    fn rate(&self) -> f64 {
         1.0
     }
     #[dbus_interface(property)]
-    async fn position(&self) -> u64 {
-        let state = self.app.lock().await.player.get_state();
-        (state.time_pos * 1000000) as u64
+    fn minimum_rate(&self) -> f64 {
+        1.0
     }
     #[dbus_interface(property)]
-    async fn metadata(&self) -> HashMap<&str, zbus::zvariant::Value> {
-        use zbus::zvariant::Value;
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+    #[dbus_interface(property)]
+    async fn position(&self) -> i64 {
+        position(&*self.app.lock().await)
+    }
+    #[dbus_interface(property)]
+    async fn metadata(&self) -> HashMap<String, OwnedValue> {
         let app = self.app.lock().await;
-        let song = app.get_playing_song_info();
-        let mut res = HashMap::new();
-        res.insert("mpris:trackid", Value::Str(song.id.into()));
-        res.insert("mpris:length", Value::U64(song.duration.as_micros() as u64));
-        res.insert("xesam:title", Value::Str(song.title.into()));
-        res.insert("xesam:artist", Value::Str(song.artists.join(", ").into()));
-        res
+        match app.now_playing() {
+            Some(entry) => metadata(&app, entry),
+            None => {
+                let no_track = Value::from(no_track().into_inner()).into();
+                HashMap::from([("mpris:trackid".to_string(), no_track)])
+            }
+        }
     }
 
-
     #[dbus_interface(property)]
-    fn can_go_next(&self) -> bool {
-        true
+    async fn can_go_next(&self) -> bool {
+        let app = self.app.lock().await;
+        let next = app.queue.current().map_or(0, |current| current + 1);
+        next < app.queue.len() || (app.repeat() != Repeat::Off && app.queue.len() > 0)
     }
     #[dbus_interface(property)]
-    fn can_go_previous(&self) -> bool {
-        true
+    async fn can_go_previous(&self) -> bool {
+        let app = self.app.lock().await;
+        match app.queue.current() {
+            Some(current) => current > 0 || app.repeat() != Repeat::Off,
+            None => false,
+        }
     }
     #[dbus_interface(property)]
-    fn can_play(&self) -> bool {
-        true
+    async fn can_play(&self) -> bool {
+        self.app.lock().await.queue.len() > 0
     }
     #[dbus_interface(property)]
-    fn can_pause(&self) -> bool {
-        true
+    async fn can_pause(&self) -> bool {
+        !self.app.lock().await.player.is_stopped()
     }
     #[dbus_interface(property)]
-    fn can_seek(&self) -> bool {
-        true
+    async fn can_seek(&self) -> bool {
+        !self.app.lock().await.player.is_stopped()
     }
     #[dbus_interface(property)]
     fn can_control(&self) -> bool {
@@ -147,16 +349,218 @@ impl PlayerInterface {
     }
 }
 
-pub async fn start_dbus(app: Arc<Mutex<App>>) -> std::result::Result<(), Box<dyn std::error::Error>> {
+/// What the desktop was last told about the player
+#[derive(PartialEq, Debug)]
+struct Snapshot {
+    status: &'static str,
+    repeat: Repeat,
+    shuffled: bool,
+    volume: i64,
+    /// Id of the entry playing
+    track: Option<u64>,
+    current: Option<usize>,
+    queue_len: usize,
+    /// In seconds
+    position: i64,
+}
+
+impl Snapshot {
+    fn take(app: &App) -> Self {
+        Snapshot {
+            status: playback_status(app),
+            repeat: app.repeat(),
+            shuffled: app.shuffled(),
+            volume: app.player.get_volume(),
+            track: app.now_playing().map(|entry| entry.id),
+            current: app.queue.current(),
+            queue_len: app.queue.len(),
+            position: position(app) / 1_000_000,
+        }
+    }
+}
+
+/// The signals owed to the desktop once the player went from a snapshot to another
+#[derive(Debug, Default, PartialEq)]
+struct Changes {
+    status: bool,
+    repeat: bool,
+    shuffled: bool,
+    volume: bool,
+    metadata: bool,
+    /// Whether there is something to play next, before, or at all
+    navigation: bool,
+    /// Where the playback jumped to, in seconds
+    seeked: Option<i64>,
+}
+
+impl Changes {
+    /// The changes from `last` to `now`, the seeks being told apart
+    /// from the playback by the time `elapsed` between both
+    fn between(last: &Snapshot, now: &Snapshot, elapsed: Duration) -> Self {
+        let moved = now.current != last.current || now.queue_len != last.queue_len;
+        // the position changes all the time while playing, only the jumps are signaled
+        let expected = match last.status {
+            "Playing" => last.position + elapsed.as_secs_f64().round() as i64,
+            _ => last.position,
+        };
+        let jumped = (now.position - expected).abs() > 1;
+        let seeked = now.track == last.track && now.status != "Stopped" && jumped;
+        Changes {
+            status: now.status != last.status,
+            repeat: now.repeat != last.repeat,
+            shuffled: now.shuffled != last.shuffled,
+            volume: now.volume != last.volume,
+            metadata: now.track != last.track,
+            navigation: moved || now.repeat != last.repeat,
+            seeked: seeked.then_some(now.position),
+        }
+    }
+}
+
+/// Signals `changes` to the desktop
+async fn notify_changes(
+    player: &PlayerInterface,
+    ctxt: &SignalContext<'_>,
+    changes: &Changes,
+) -> zbus::Result<()> {
+    if changes.status {
+        player.playback_status_changed(ctxt).await?;
+        player.can_pause_changed(ctxt).await?;
+        player.can_seek_changed(ctxt).await?;
+    }
+    if changes.repeat {
+        player.loop_status_changed(ctxt).await?;
+    }
+    if changes.shuffled {
+        player.shuffle_changed(ctxt).await?;
+    }
+    if changes.volume {
+        player.volume_changed(ctxt).await?;
+    }
+    if changes.metadata {
+        player.metadata_changed(ctxt).await?;
+    }
+    if changes.navigation {
+        player.can_go_next_changed(ctxt).await?;
+        player.can_go_previous_changed(ctxt).await?;
+        player.can_play_changed(ctxt).await?;
+    }
+    if let Some(position) = changes.seeked {
+        PlayerInterface::seeked(ctxt, position * 1_000_000).await?;
+    }
+    Ok(())
+}
+
+pub async fn start_dbus(
+    app: Arc<Mutex<App>>,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
     let base = BaseInterface {};
-    let player = PlayerInterface { app };
-    let _conn = ConnectionBuilder::session()?
+    let player = PlayerInterface {
+        app: Arc::clone(&app),
+    };
+    let track_list = TrackListInterface {
+        app: Arc::clone(&app),
+    };
+    let playlists = PlaylistsInterface {
+        app: Arc::clone(&app),
+    };
+    let conn = ConnectionBuilder::session()?
         .name("org.mpris.MediaPlayer2.yauma")?
-        .serve_at("/org/mpris/MediaPlayer2", base)?
-        .serve_at("/org/mpris/MediaPlayer2", player)?
+        .serve_at(PATH, base)?
+        .serve_at(PATH, player)?
+        .serve_at(PATH, track_list)?
+        .serve_at(PATH, playlists)?
         .build()
         .await?;
-    // Do other things or go to wait forever
-    pending::<()>().await;
-    Ok(())
+    let server = conn.object_server();
+    let player = server.interface::<_, PlayerInterface>(PATH).await?;
+    let track_list = server.interface::<_, TrackListInterface>(PATH).await?;
+    let playlists = server.interface::<_, PlaylistsInterface>(PATH).await?;
+    // the player is also driven from the terminal, so its state is watched for changes
+    // the queue is only read again once it changed, it can hold many tracks
+    let snapshot = |app: &App, last: Option<&track_list::Snapshot>| {
+        (
+            Snapshot::take(app),
+            track_list::Snapshot::take(app, last),
+            playlists::Snapshot::take(app),
+        )
+    };
+    let mut last = snapshot(&*app.lock().await, None);
+    let mut last_time = Instant::now();
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let now = snapshot(&*app.lock().await, Some(&last.1));
+        let elapsed = last_time.elapsed();
+        last_time = Instant::now();
+        if now.0 != last.0 {
+            let changes = Changes::between(&last.0, &now.0, elapsed);
+            let iface = player.get().await;
+            notify_changes(&iface, player.signal_context(), &changes).await?;
+        }
+        if now.1 != last.1 {
+            let iface = track_list.get().await;
+            track_list::notify(&iface, track_list.signal_context(), &last.1, &now.1).await?;
+        }
+        if now.2 != last.2 {
+            let iface = playlists.get().await;
+            playlists::notify(&iface, playlists.signal_context(), &last.2, &now.2).await?;
+        }
+        last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::tests::playing;
+    use crate::player::fake::Clock;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[tokio::test]
+    async fn playback_is_not_a_seek() {
+        let clock = Clock::manual();
+        let mut app = playing(&clock, &["a", "b"]).await;
+        let last = Snapshot::take(&app);
+        assert_eq!(last.status, "Playing");
+        assert_eq!((last.current, last.queue_len), (Some(0), 2));
+        clock.advance(3 * SECOND);
+        let now = Snapshot::take(&app);
+        assert_eq!(now.position, 3);
+        let changes = Changes::between(&last, &now, 3 * SECOND);
+        assert_eq!(changes, Changes::default());
+        app.player.seek(4);
+        let seeked = Snapshot::take(&app);
+        let changes = Changes::between(&now, &seeked, Duration::ZERO);
+        assert_eq!(changes.seeked, Some(7));
+        assert!(!changes.metadata);
+    }
+
+    #[tokio::test]
+    async fn track_and_status_changes() {
+        let clock = Clock::manual();
+        let mut app = playing(&clock, &["a", "b"]).await;
+        let last = Snapshot::take(&app);
+        app.handle_event(Event::Next).await;
+        let next = Snapshot::take(&app);
+        let changes = Changes::between(&last, &next, Duration::ZERO);
+        assert!(changes.metadata && changes.navigation);
+        assert_eq!((changes.status, changes.seeked), (false, None));
+        app.set_pause_val(true);
+        clock.advance(POLL_INTERVAL);
+        let paused = Snapshot::take(&app);
+        let changes = Changes::between(&next, &paused, POLL_INTERVAL);
+        let expected = Changes {
+            status: true,
+            ..Default::default()
+        };
+        assert_eq!(changes, expected);
+        app.set_repeat(Repeat::All);
+        app.set_shuffled(true);
+        let changes = Changes::between(&paused, &Snapshot::take(&app), Duration::ZERO);
+        assert!(changes.repeat && changes.shuffled && changes.navigation);
+        app.stop();
+        let stopped = Snapshot::take(&app);
+        assert_eq!((stopped.status, stopped.track), ("Stopped", None));
+    }
 }
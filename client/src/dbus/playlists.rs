@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use music_server::source_types::Playlist;
+use tokio::sync::Mutex;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath};
+use zbus::{dbus_interface, fdo, SignalContext};
+
+use crate::app::App;
+
+/// Start of the playlist ids, followed by the source and the id of the playlist
+const PLAYLIST_PATH: &str = "/com/github/sofamaniac/yauma/playlist/";
+
+/// Path, name and icon of a playlist
+type PlaylistInfo = (OwnedObjectPath, String, String);
+
+/// The playlists of the server, as the desktop sees them
+pub struct PlaylistsInterface {
+    pub app: Arc<Mutex<App>>,
+}
+
+/// Escapes `name` into an element of an object path, which only takes `[A-Za-z0-9_]`
+fn escape(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => (b as char).to_string(),
+            _ => format!("_{:02x}", b),
+        })
+        .collect()
+}
+
+/// Object path of the playlist `playlist` of `source`
+fn playlist_id(source: &str, playlist: &Playlist) -> OwnedObjectPath {
+    let path = format!(
+        "{}_{}/_{}",
+        PLAYLIST_PATH,
+        escape(source),
+        escape(&playlist.id)
+    );
+    ObjectPath::from_string_unchecked(path).into()
+}
+
+fn info((source, playlist): (&str, &Playlist)) -> PlaylistInfo {
+    (
+        playlist_id(source, playlist),
+        playlist.title.clone(),
+        String::new(),
+    )
+}
+
+/// What `ActivePlaylist` holds when the queue comes from no playlist
+fn no_playlist() -> PlaylistInfo {
+    let path = ObjectPath::from_static_str_unchecked("/").into();
+    (path, String::new(), String::new())
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Playlists")]
+impl PlaylistsInterface {
+    /// Replaces the queue with the playlist and plays it
+    async fn activate_playlist(&self, playlist_id: ObjectPath<'_>) -> fdo::Result<()> {
+        let mut app = self.app.lock().await;
+        let playlist = app
+            .playlists()
+            .find(|(source, playlist)| {
+                self::playlist_id(source, playlist).as_str() == playlist_id.as_str()
+            })
+            .map(|(source, playlist)| (source.to_string(), playlist.id.clone()));
+        let Some((source, id)) = playlist else {
+            let message = format!("Unknown playlist {}", playlist_id);
+            return Err(fdo::Error::InvalidArgs(message));
+        };
+        app.activate_playlist(&source, &id).await;
+        Ok(())
+    }
+
+    /// The playlists from `index`, at most `max_count` of them, in the order given by
+    /// the sources unless `order` is `Alphabetical`
+    async fn get_playlists(
+        &self,
+        index: u32,
+        max_count: u32,
+        order: String,
+        reverse_order: bool,
+    ) -> Vec<PlaylistInfo> {
+        let app = self.app.lock().await;
+        let mut playlists: Vec<PlaylistInfo> = app.playlists().map(info).collect();
+        if order == "Alphabetical" {
+            playlists.sort_by_key(|(_, name, _)| name.to_lowercase());
+        }
+        if reverse_order {
+            playlists.reverse();
+        }
+        playlists
+            .into_iter()
+            .skip(index as usize)
+            .take(max_count as usize)
+            .collect()
+    }
+
+    #[dbus_interface(signal)]
+    async fn playlist_changed(ctxt: &SignalContext<'_>, playlist: PlaylistInfo)
+        -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    async fn playlist_count(&self) -> u32 {
+        self.app.lock().await.playlists().count() as u32
+    }
+
+    #[dbus_interface(property)]
+    fn orderings(&self) -> Vec<String> {
+        vec!["Alphabetical".to_string(), "UserDefined".to_string()]
+    }
+
+    #[dbus_interface(property)]
+    async fn active_playlist(&self) -> (bool, PlaylistInfo) {
+        match self.app.lock().await.active_playlist() {
+            Some(playlist) => (true, info(playlist)),
+            None => (false, no_playlist()),
+        }
+    }
+}
+
+/// The playlists the desktop was last told about
+#[derive(PartialEq)]
+pub struct Snapshot {
+    playlists: Vec<PlaylistInfo>,
+    active: Option<PlaylistInfo>,
+}
+
+impl Snapshot {
+    pub fn take(app: &App) -> Self {
+        Snapshot {
+            playlists: app.playlists().map(info).collect(),
+            active: app.active_playlist().map(info),
+        }
+    }
+}
+
+/// Signals the playlists renamed from `last` to `now`, and the properties that changed
+pub async fn notify(
+    playlists: &PlaylistsInterface,
+    ctxt: &SignalContext<'_>,
+    last: &Snapshot,
+    now: &Snapshot,
+) -> zbus::Result<()> {
+    if now.playlists.len() != last.playlists.len() {
+        playlists.playlist_count_changed(ctxt).await?;
+    }
+    for playlist in now.playlists.iter() {
+        let renamed = last
+            .playlists
+            .iter()
+            .any(|(id, name, _)| id == &playlist.0 && name != &playlist.1);
+        if renamed {
+            PlaylistsInterface::playlist_changed(ctxt, playlist.clone()).await?;
+        }
+    }
+    if now.active != last.active {
+        playlists.active_playlist_changed(ctxt).await?;
+    }
+    Ok(())
+}
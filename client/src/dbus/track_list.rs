@@ -0,0 +1,270 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue};
+use zbus::{dbus_interface, fdo, SignalContext};
+
+use super::{entry_id, metadata, no_track, track_id, track_path, uri_path, NO_TRACK};
+use crate::app::App;
+use crate::queue::QueueEntry;
+
+/// The queue, as the desktop sees it
+pub struct TrackListInterface {
+    pub app: Arc<Mutex<App>>,
+}
+
+/// Position in the queue of the entry `track_id`
+fn find(app: &App, track_id: &str) -> fdo::Result<usize> {
+    entry_id(track_id)
+        .and_then(|id| app.queue.position(id))
+        .ok_or_else(|| fdo::Error::InvalidArgs(format!("Unknown track {}", track_id)))
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.TrackList")]
+impl TrackListInterface {
+    async fn get_tracks_metadata(
+        &self,
+        track_ids: Vec<OwnedObjectPath>,
+    ) -> Vec<HashMap<String, OwnedValue>> {
+        let app = self.app.lock().await;
+        track_ids
+            .iter()
+            .filter_map(|id| find(&app, id.as_str()).ok())
+            .map(|index| metadata(&app, &app.queue.entries()[index]))
+            .collect()
+    }
+
+    /// Queues `uri` after `after_track`, or first when it is the `NoTrack` path
+    async fn add_track(
+        &self,
+        uri: String,
+        after_track: ObjectPath<'_>,
+        set_as_current: bool,
+    ) -> fdo::Result<()> {
+        let path = uri_path(uri)?;
+        let mut app = self.app.lock().await;
+        let at = match after_track.as_str() {
+            NO_TRACK => 0,
+            track_id => find(&app, track_id)? + 1,
+        };
+        app.add_uri(path, at, set_as_current).await;
+        Ok(())
+    }
+
+    async fn remove_track(&self, track_id: ObjectPath<'_>) -> fdo::Result<()> {
+        let mut app = self.app.lock().await;
+        let index = find(&app, track_id.as_str())?;
+        app.remove_at(index);
+        Ok(())
+    }
+
+    async fn go_to(&self, track_id: ObjectPath<'_>) -> fdo::Result<()> {
+        let mut app = self.app.lock().await;
+        let index = find(&app, track_id.as_str())?;
+        app.go_to(index).await;
+        Ok(())
+    }
+
+    #[dbus_interface(signal)]
+    async fn track_list_replaced(
+        ctxt: &SignalContext<'_>,
+        tracks: Vec<OwnedObjectPath>,
+        current_track: OwnedObjectPath,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn track_added(
+        ctxt: &SignalContext<'_>,
+        metadata: HashMap<String, OwnedValue>,
+        after_track: OwnedObjectPath,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn track_removed(ctxt: &SignalContext<'_>, track_id: OwnedObjectPath)
+        -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn track_metadata_changed(
+        ctxt: &SignalContext<'_>,
+        track_id: OwnedObjectPath,
+        metadata: HashMap<String, OwnedValue>,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    async fn tracks(&self) -> Vec<OwnedObjectPath> {
+        self.app
+            .lock()
+            .await
+            .queue
+            .entries()
+            .iter()
+            .map(track_id)
+            .collect()
+    }
+
+    #[dbus_interface(property)]
+    fn can_edit_tracks(&self) -> bool {
+        true
+    }
+}
+
+/// Playlist a track was queued from, whose title is the album of the track
+type Album = (String, String);
+
+/// The tracks the desktop was last told about, without their metadata
+#[derive(PartialEq)]
+pub struct Snapshot {
+    /// Version of the queue the tracks were read at
+    version: u64,
+    /// Ids of the entries, with the version their song was last updated at
+    tracks: Vec<(u64, u64)>,
+    current: Option<u64>,
+    albums: HashMap<Album, Option<String>>,
+}
+
+impl Snapshot {
+    /// Reads the tracks of the queue again only when it changed since `last`,
+    /// the titles of their playlists otherwise
+    pub fn take(app: &App, last: Option<&Snapshot>) -> Self {
+        let version = app.queue.version();
+        let (tracks, albums) = match last {
+            Some(last) if last.version == version => {
+                (last.tracks.clone(), last.albums.keys().cloned().collect())
+            }
+            _ => {
+                let entries = app.queue.entries();
+                let tracks = entries.iter().map(|e| (e.id, e.revision)).collect();
+                let albums: HashSet<_> = entries.iter().map(album).collect();
+                (tracks, albums)
+            }
+        };
+        Snapshot {
+            version,
+            tracks,
+            current: app.queue.current_entry().map(|entry| entry.id),
+            albums: albums
+                .into_iter()
+                .map(|(source, id)| {
+                    let title = app.playlist_title(&source, &id);
+                    ((source, id), title)
+                })
+                .collect(),
+        }
+    }
+
+    fn ids(&self) -> Vec<u64> {
+        self.tracks.iter().map(|(id, _)| *id).collect()
+    }
+}
+
+fn album(entry: &QueueEntry) -> Album {
+    (entry.source.clone(), entry.playlist.clone())
+}
+
+/// Signals the tracks added and removed from `last` to `now`, or the whole list
+/// when the tracks kept moved or none was kept, along with the metadata that changed.
+/// Only the metadata of the tracks added or changed is read from the app.
+pub async fn notify(
+    track_list: &TrackListInterface,
+    ctxt: &SignalContext<'_>,
+    last: &Snapshot,
+    now: &Snapshot,
+) -> zbus::Result<()> {
+    let (last_ids, now_ids) = (last.ids(), now.ids());
+    let last_set: HashSet<u64> = last_ids.iter().copied().collect();
+    let mut added = vec![];
+    if last_ids != now_ids {
+        track_list.tracks_invalidate(ctxt).await?;
+        let now_set: HashSet<u64> = now_ids.iter().copied().collect();
+        let kept: Vec<_> = now_ids.iter().filter(|id| last_set.contains(id)).collect();
+        let kept_before: Vec<_> = last_ids.iter().filter(|id| now_set.contains(id)).collect();
+        if kept.is_empty() || kept != kept_before {
+            let tracks = now_ids.into_iter().map(track_path).collect();
+            let current = now.current.map_or_else(no_track, track_path);
+            return TrackListInterface::track_list_replaced(ctxt, tracks, current).await;
+        }
+        for id in last_ids.iter().filter(|id| !now_set.contains(id)) {
+            TrackListInterface::track_removed(ctxt, track_path(*id)).await?;
+        }
+        added = (0..now_ids.len())
+            .filter(|&i| !last_set.contains(&now_ids[i]))
+            .collect();
+    }
+    let revisions: HashMap<u64, u64> = last.tracks.iter().copied().collect();
+    let updated: HashSet<u64> = now
+        .tracks
+        .iter()
+        .filter(|(id, revision)| revisions.get(id).is_some_and(|last| last != revision))
+        .map(|(id, _)| *id)
+        .collect();
+    let renamed: HashSet<&Album> = now
+        .albums
+        .iter()
+        .filter(|(album, title)| last.albums.get(*album).is_some_and(|last| last != *title))
+        .map(|(album, _)| album)
+        .collect();
+    if added.is_empty() && updated.is_empty() && renamed.is_empty() {
+        return Ok(());
+    }
+    // the metadata is built at once, the signals are sent once the app is released
+    let (added, changed) = {
+        let app = track_list.app.lock().await;
+        let entries: HashMap<u64, &QueueEntry> =
+            app.queue.entries().iter().map(|e| (e.id, e)).collect();
+        let added: Vec<_> = added
+            .into_iter()
+            .filter_map(|i| {
+                let entry = entries.get(&now_ids[i])?;
+                let after = i
+                    .checked_sub(1)
+                    .map_or_else(no_track, |i| track_path(now_ids[i]));
+                Some((metadata(&app, entry), after))
+            })
+            .collect();
+        let changed: Vec<_> = now
+            .tracks
+            .iter()
+            .filter_map(|(id, _)| entries.get(id))
+            .filter(|entry| {
+                let added = !last_set.contains(&entry.id);
+                !added && (updated.contains(&entry.id) || renamed.contains(&album(entry)))
+            })
+            .map(|entry| (track_id(entry), metadata(&app, entry)))
+            .collect();
+        (added, changed)
+    };
+    for (metadata, after) in added {
+        TrackListInterface::track_added(ctxt, metadata, after).await?;
+    }
+    for (id, metadata) in changed {
+        TrackListInterface::track_metadata_changed(ctxt, id, metadata).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::tests::playing;
+    use crate::player::fake::Clock;
+
+    #[tokio::test]
+    async fn snapshot_follows_the_queue() {
+        let mut app = playing(&Clock::manual(), &["a", "b"]).await;
+        let last = Snapshot::take(&app, None);
+        let ids = last.ids();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(last.current, Some(ids[0]));
+        assert!(Snapshot::take(&app, Some(&last)) == last);
+        let entry = app.queue.entries()[1].clone();
+        app.queue.update_song(&entry.source, &entry.song);
+        let updated = Snapshot::take(&app, Some(&last));
+        assert_eq!(updated.ids(), ids);
+        assert_ne!(updated.tracks[1].1, last.tracks[1].1);
+        assert_eq!(updated.tracks[0], last.tracks[0]);
+        app.remove_at(0);
+        let removed = Snapshot::take(&app, Some(&updated));
+        assert_eq!(removed.ids(), vec![ids[1]]);
+    }
+}
@@ -1,31 +1,31 @@
+use clap::{Parser, Subcommand};
 use crossterm::{
     event::{self, poll, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use music_server::request::{get_answer, Answer};
-use std::{error::Error, io, sync::Arc, time::Duration};
+use std::{error::Error, io, path::PathBuf, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
-use tokio::{
-    io::AsyncReadExt,
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
-};
 use tui::{
     backend::{Backend, CrosstermBackend},
-    layout::{Alignment, Constraint, Direction, Layout},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::Span,
-    widgets::{Block, BorderType, Borders, Gauge, List, ListState, Paragraph, Wrap},
+    widgets::{Block, BorderType, Borders, Clear, Gauge, List, ListState, Paragraph, Wrap},
     Frame, Terminal,
 };
 
 mod app;
+mod config;
+mod connection;
+mod ctl;
 mod dbus;
 mod player;
+mod queue;
+mod search;
+mod url;
 use app::App;
+use connection::Reader;
 
 async fn start_ui(app: &Arc<Mutex<App>>) -> Result<(), Box<dyn Error>> {
     // setup terminal
@@ -49,46 +49,78 @@ async fn start_ui(app: &Arc<Mutex<App>>) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn listen(app: &Arc<Mutex<App>>, stream: &mut OwnedReadHalf) -> Result<(), std::io::Error> {
+async fn listen(app: &Arc<Mutex<App>>, stream: &mut Reader) -> Result<(), std::io::Error> {
     app.lock().await.request_sources().await;
-    loop {
-        stream.readable().await?;
-
-        // an answer is preceded by its size
-        let mut size = [0; 8];
-        stream.read_exact(&mut size).await;
-        let size = usize::from_be_bytes(size);
-        if size == 0 {
-            break Ok(());
-        }
-        let mut buf = vec![0; size];
-        stream.read_exact(&mut buf).await;
-        let message = match std::str::from_utf8(&buf) {
-            Ok(val) => val,
-            Err(_) => continue,
-        };
-        let answer: Answer = match get_answer(message.to_string()).await {
-            Ok(val) => val,
-            Err(err) => {
-                eprintln!("error while parsing answer {}", err);
-                continue;
-            }
-        };
+    while let Some(answer) = connection::read_answer(stream).await? {
         app.lock().await.handle_answer(answer).await;
     }
+    Ok(())
+}
+
+#[derive(Parser)]
+#[command(version, about = "Terminal client for the music server")]
+struct Cli {
+    /// Server to connect to, either a configured name or an address
+    /// (`host:port`, `tls:host:port` or `unix:/path/to/socket`)
+    #[arg(long, global = true)]
+    server: Option<String>,
+    /// Token to log in with, overrides the configured credentials
+    #[arg(long, global = true)]
+    token: Option<String>,
+    /// Configuration file to use instead of the default one
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Plays nothing, only pretending to, for running without mpv or an audio device
+    #[arg(long)]
+    fake_player: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs a single command against the server and prints the result as json
+    Ctl(ctl::CtlArgs),
+    /// Lists the configured servers
+    Servers,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let stream = match TcpStream::connect("127.0.0.1:8080").await {
+    let cli = Cli::parse();
+    if let Some(path) = cli.config {
+        config::set_config_path(path);
+    }
+    let config = config::get_config();
+    if let Some(Command::Servers) = cli.command {
+        for (name, server) in config.servers.iter() {
+            let default = if *name == config.default_server { "*" } else { "" };
+            println!("{}{}\t{}", name, default, server.address);
+        }
+        return Ok(());
+    }
+    let mut server = config.server(cli.server.as_deref());
+    if let Some(token) = cli.token {
+        server.token = token;
+    }
+    let (mut rx, tx) = match connection::connect(&server).await {
         Ok(stream) => stream,
         Err(err) => {
-            println!("Cannot connect to the server {}", err);
+            println!("Cannot connect to the server {} {}", server.address, err);
             return Err(err.into());
         }
     };
-    let (mut rx, tx) = stream.into_split();
-    let app = Arc::new(Mutex::new(App::new(tx)));
+    if let Some(Command::Ctl(args)) = cli.command {
+        return ctl::run(args, rx, tx).await;
+    }
+    let player = if cli.fake_player {
+        player::Player::with_backend(Box::new(player::fake::FakeBackend::new()))
+    } else {
+        player::Player::new()
+    };
+    let queue = queue::Queue::load(config::queue_path());
+    let credentials = server.credentials();
+    let app = Arc::new(Mutex::new(App::new(tx, credentials, player, queue)));
     let app_clone = Arc::clone(&app);
     tokio::spawn(async move { listen(&app_clone, &mut rx).await });
     let app_clone = Arc::clone(&app);
@@ -100,12 +132,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
 async fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &Arc<Mutex<App>>) -> io::Result<()> {
     loop {
         let mut app = app.lock().await;
+        app.tick().await;
         let player_state = app.player.get_state();
         terminal.draw(|f| ui(f, &app, player_state))?;
 
         // avoid to block refresh
         if poll(Duration::from_millis(50))? {
             if let Event::Key(key) = event::read()? {
+                if app.is_typing() {
+                    let input = match key.code {
+                        KeyCode::Char(c) => Some(search::Input::Char(c)),
+                        KeyCode::Backspace => Some(search::Input::Backspace),
+                        KeyCode::Up => Some(search::Input::Up),
+                        KeyCode::Down => Some(search::Input::Down),
+                        KeyCode::Enter => Some(search::Input::Enter),
+                        KeyCode::Esc => Some(search::Input::Escape),
+                        _ => None,
+                    };
+                    if let Some(input) = input {
+                        app.handle_input(input);
+                    }
+                    continue;
+                }
                 match key.code {
                     KeyCode::Char('q') => return Ok(()),
                     KeyCode::Char('j') => {
@@ -135,8 +183,20 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &Arc<Mutex<App>>)
                     KeyCode::Enter => match app.current_panel {
                         app::Panel::Sources => app.current_panel = app::Panel::Playlists,
                         app::Panel::Playlists => app.current_panel = app::Panel::Songs,
-                        app::Panel::Songs => app.handle_event(app::Event::Play).await,
+                        app::Panel::Songs | app::Panel::Queue => {
+                            app.handle_event(app::Event::Play).await
+                        }
                     },
+                    KeyCode::Tab => {
+                        app.handle_event(app::Event::Move(app::Direction::QueuePanel))
+                            .await
+                    }
+                    KeyCode::Char('e') => app.handle_event(app::Event::Enqueue).await,
+                    KeyCode::Char('E') => app.handle_event(app::Event::PlayNext).await,
+                    KeyCode::Char('x') => app.handle_event(app::Event::Remove).await,
+                    KeyCode::Char('C') => app.handle_event(app::Event::ClearQueue).await,
+                    KeyCode::Char('[') => app.handle_event(app::Event::MoveEntry(-1)).await,
+                    KeyCode::Char(']') => app.handle_event(app::Event::MoveEntry(1)).await,
                     KeyCode::Char('d') => app.handle_event(app::Event::VolumeDown).await,
                     KeyCode::Char('f') => app.handle_event(app::Event::VolumeUp).await,
                     KeyCode::Char('T') => app.handle_event(app::Event::Download).await,
@@ -144,6 +204,11 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &Arc<Mutex<App>>)
                     KeyCode::Char('>') => app.handle_event(app::Event::Next).await,
                     KeyCode::Char('a') => app.handle_event(app::Event::Auto).await,
                     KeyCode::Char('y') => app.handle_event(app::Event::Shuffle).await,
+                    KeyCode::Char('r') => app.handle_event(app::Event::Repeat).await,
+                    KeyCode::Char('/') => app.handle_event(app::Event::Filter).await,
+                    KeyCode::Char('n') => app.handle_event(app::Event::Match(1)).await,
+                    KeyCode::Char('N') => app.handle_event(app::Event::Match(-1)).await,
+                    KeyCode::Char('s') => app.handle_event(app::Event::Search).await,
                     KeyCode::Right => app.handle_event(app::Event::SeekForward).await,
                     KeyCode::Left => app.handle_event(app::Event::SeekBackward).await,
                     _ => (),
@@ -180,7 +245,14 @@ fn ui<B: Backend>(
 
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
-        .constraints([Constraint::Percentage(20), Constraint::Percentage(80)].as_ref())
+        .constraints(
+            [
+                Constraint::Percentage(20),
+                Constraint::Percentage(50),
+                Constraint::Percentage(30),
+            ]
+            .as_ref(),
+        )
         .split(main_chunks[0]);
 
     let left_chunks = Layout::default()
@@ -196,7 +268,7 @@ fn ui<B: Backend>(
 
     let source_widget = app.get_sources_widget();
     let mut source_state = match app.current_panel {
-        app::Panel::Sources => app.state.clone(),
+        app::Panel::Sources => app.get_sources_state(),
         _ => ListState::default(),
     };
     f.render_stateful_widget(source_widget, left_chunks[0], &mut source_state);
@@ -221,15 +293,27 @@ fn ui<B: Backend>(
     };
     f.render_stateful_widget(songs_widget, chunks[1], &mut songs_state);
 
+    let queue_widget = app.get_queue_widget();
+    let mut queue_state = match app.current_panel {
+        app::Panel::Queue => app.get_queue_state(),
+        _ => ListState::default(),
+    };
+    f.render_stateful_widget(queue_widget, chunks[2], &mut queue_state);
+
     let percentage = if player_state.duration == 0 {
         0
     } else {
         (player_state.time_pos * 100) / player_state.duration
     };
     let percentage = std::cmp::min(percentage, 100);
+    // mpv only knows the title of the files it plays, not the one of the streams
+    let title = match app.now_playing() {
+        Some(entry) => entry.song.title.clone(),
+        None => player_state.title,
+    };
     let player_info = format!(
         "{} - {}/{}",
-        player_state.title,
+        title,
         duration_to_string(Duration::from_secs(player_state.time_pos as u64)),
         duration_to_string(Duration::from_secs(player_state.duration as u64))
     );
@@ -238,7 +322,33 @@ fn ui<B: Backend>(
         .block(Block::default().borders(Borders::ALL).title(player_info))
         .gauge_style(Style::default().fg(Color::White).bg(Color::Black))
         .percent(percentage as u16);
-    f.render_widget(player_widget, main_chunks[1])
+    f.render_widget(player_widget, main_chunks[1]);
+
+    if let Some((search_widget, mut search_state)) = app.get_search_widget() {
+        let area = centered_rect(60, 60, size);
+        f.render_widget(Clear, area);
+        f.render_stateful_widget(search_widget, area, &mut search_state);
+    }
+}
+
+/// The area of `percent_x` by `percent_y` percents of `r` at its center
+fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(r);
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(vertical[1])[1]
 }
 
 fn duration_to_string(dur: Duration) -> String {
@@ -1,10 +1,24 @@
-use libmpv::{FileState, Mpv};
-use music_server::source_types::Song;
+pub mod fake;
+pub mod mpv;
+
+/// Plays the songs for the player, so that the client can run without mpv.
+/// A backend has no playlist of its own: the playlist is `queue::Queue`, which decides
+/// what plays next, repeats and shuffles, and hands the backend one song at a time.
+pub trait PlayerBackend: Send {
+    /// Plays `url`, in place of the song playing
+    fn load(&mut self, url: &str);
+    fn paused(&self) -> bool;
+    fn set_paused(&mut self, paused: bool);
+    fn stop(&mut self);
+    /// Seeks `dt` seconds forward, backward when negative
+    fn seek(&mut self, dt: i64);
+    fn volume(&self) -> i64;
+    fn set_volume(&mut self, volume: i64);
+    fn state(&self) -> State;
+}
 
 pub struct Player {
-    player: Mpv,
-    shuffled: bool,
-    in_playlist: bool,
+    backend: Box<dyn PlayerBackend>,
     stopped: bool,
 }
 
@@ -18,107 +32,51 @@ pub struct State {
 
 impl Player {
     pub fn new() -> Self {
-        let player = Mpv::new().unwrap();
-        player.set_property("video", false).unwrap();
-        player.set_property("ytdl", true).unwrap();
+        Self::with_backend(Box::new(mpv::MpvBackend::new()))
+    }
+
+    pub fn with_backend(backend: Box<dyn PlayerBackend>) -> Self {
         Player {
-            player,
-            shuffled: false,
-            in_playlist: false,
+            backend,
             stopped: true,
         }
     }
 
     pub fn get_state(&self) -> State {
-        let duration = self.player.get_property("duration").unwrap_or_default();
-        let time_pos = self.player.get_property("time-pos").unwrap_or_default();
-        let volume = self.player.get_property("volume").unwrap_or_default();
-        let title = self.player.get_property("media-title").unwrap_or_default();
-        let path = self.player.get_property("path").unwrap_or_default();
-        State {
-            duration,
-            time_pos,
-            volume,
-            title,
-            path
-        }
+        self.backend.state()
     }
 
     pub fn paused(&self) -> bool {
-        self.player.get_property("pause").unwrap()
+        self.backend.paused()
     }
 
     pub fn playpause(&mut self) {
-        if self.paused() {
-            self.player.unpause();
-        } else {
-            self.player.pause();
-        }
+        let paused = self.paused();
+        self.backend.set_paused(!paused);
     }
 
     pub fn play(&mut self, url: &str) {
-        // It is necessary to surround the url with quotes to avoid errors
-        match self.player.command("loadfile", &[&format!("\"{}\"", url)]) {
-            Ok(_) => self.stopped = false,
-            Err(e) => eprintln!("error {:?}", e),
-        };
+        self.backend.load(url);
+        // a song started while paused is meant to be heard
+        self.backend.set_paused(false);
+        self.stopped = false;
     }
 
     pub fn get_volume(&self) -> i64 {
-        self.player.get_property("volume").unwrap()
+        self.backend.volume()
     }
 
     pub fn incr_volume(&mut self, dv: i64) {
-        let volume = self.get_volume();
-        let volume = std::cmp::min(volume + dv, 100);
-        let volume = std::cmp::max(volume, 0);
-        self.player.set_property("volume", volume);
-    }
-
-    pub fn shuffle(&mut self) {
-        if !self.in_playlist {
-            return;
-        }
-        if self.shuffled {
-            self.player.command("playlist-unshuffle", &[]).unwrap();
-        } else {
-            self.player.command("playlist-shuffle", &[]).unwrap();
-        }
-        self.shuffled = !self.shuffled;
-    }
-
-    pub fn set_auto(&mut self, playlist: &[&str]) {
-        self.player.playlist_clear().unwrap();
-        if !self.in_playlist {
-            let files: Vec<(&str, FileState, Option<_>)> = playlist
-                .iter()
-                .cloned()
-                .map(|s| (s, FileState::AppendPlay, None))
-                .collect();
-            self.player.playlist_load_files(&files).unwrap();
-            self.stopped = false;
-        }
-        self.in_playlist = !self.in_playlist;
-    }
-
-    pub fn next(&self) {
-        self.player.playlist_next_weak().unwrap();
-    }
-
-    pub fn prev(&self) {
-        self.player.playlist_previous_weak().unwrap();
-    }
-
-    pub fn is_shuffled(&self) -> bool {
-        self.shuffled
+        self.set_volume(self.get_volume() + dv);
     }
 
-    pub fn is_in_playlist(&self) -> bool {
-        self.in_playlist
+    /// Sets the volume, from 0 to 100
+    pub fn set_volume(&mut self, volume: i64) {
+        self.backend.set_volume(volume.clamp(0, 100));
     }
 
     pub fn stop(&mut self) {
-        self.player.command("stop", &[]).unwrap();
+        self.backend.stop();
         self.stopped = true;
     }
 
@@ -127,6 +85,6 @@ impl Player {
     }
 
     pub fn seek(&mut self, dt: i64) {
-        self.player.seek_forward(dt as f64).unwrap();
+        self.backend.seek(dt);
     }
 }
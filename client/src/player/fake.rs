@@ -0,0 +1,168 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{PlayerBackend, State};
+
+/// How long every track lasts, as nothing tells the duration of the urls
+const TRACK_DURATION: Duration = Duration::from_secs(180);
+
+/// The time of the fake backend: the real one, or one moved forward by hand
+#[derive(Clone)]
+pub enum Clock {
+    System(Instant),
+    Manual(Arc<Mutex<Duration>>),
+}
+
+impl Clock {
+    pub fn system() -> Self {
+        Clock::System(Instant::now())
+    }
+
+    /// A clock that only moves with `advance`, shared by its clones
+    pub fn manual() -> Self {
+        Clock::Manual(Default::default())
+    }
+
+    /// Time since the clock was created
+    fn now(&self) -> Duration {
+        match self {
+            Clock::System(start) => start.elapsed(),
+            Clock::Manual(now) => *now.lock().expect("poisoned lock"),
+        }
+    }
+
+    /// Moves a manual clock `step` forward, the system one moves on its own
+    pub fn advance(&self, step: Duration) {
+        if let Clock::Manual(now) = self {
+            *now.lock().expect("poisoned lock") += step;
+        }
+    }
+}
+
+/// Plays nothing but keeps the time as mpv would,
+/// for running the client without mpv or an audio device
+pub struct FakeBackend {
+    clock: Clock,
+    /// How long every track lasts
+    duration: Duration,
+    /// The url playing, until it ends
+    track: Option<String>,
+    /// Position in the track when the clock was last settled
+    position: Duration,
+    /// Time of the clock when it was last settled, `None` while paused
+    since: Option<Duration>,
+    paused: bool,
+    volume: i64,
+}
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        Self::with_clock(Clock::system(), TRACK_DURATION)
+    }
+
+    /// A backend following `clock`, whose tracks last `duration`
+    pub fn with_clock(clock: Clock, duration: Duration) -> Self {
+        FakeBackend {
+            since: Some(clock.now()),
+            clock,
+            duration,
+            track: None,
+            position: Duration::ZERO,
+            paused: false,
+            volume: 100,
+        }
+    }
+
+    /// The position in the track playing, `None` once it ended
+    fn clock(&self) -> Option<Duration> {
+        self.track.as_ref()?;
+        let played = self.since.map(|since| self.clock.now() - since);
+        let position = self.position + played.unwrap_or_default();
+        (position < self.duration).then_some(position)
+    }
+
+    /// Saves the state of the clock before changing it
+    fn settle(&mut self) {
+        match self.clock() {
+            Some(position) => self.position = position,
+            None => {
+                self.track = None;
+                self.position = Duration::ZERO;
+            }
+        }
+        if self.since.is_some() {
+            self.since = Some(self.clock.now());
+        }
+    }
+}
+
+impl PlayerBackend for FakeBackend {
+    fn load(&mut self, url: &str) {
+        self.settle();
+        self.track = Some(url.to_string());
+        self.position = Duration::ZERO;
+    }
+
+    fn paused(&self) -> bool {
+        self.paused
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.settle();
+        self.paused = paused;
+        self.since = if paused { None } else { Some(self.clock.now()) };
+    }
+
+    fn stop(&mut self) {
+        self.settle();
+        self.track = None;
+        self.position = Duration::ZERO;
+    }
+
+    fn seek(&mut self, dt: i64) {
+        self.settle();
+        if self.track.is_none() {
+            return;
+        }
+        let step = Duration::from_secs(dt.unsigned_abs());
+        self.position = if dt < 0 {
+            self.position.saturating_sub(step)
+        } else {
+            self.position + step
+        };
+        self.settle();
+    }
+
+    fn volume(&self) -> i64 {
+        self.volume
+    }
+
+    fn set_volume(&mut self, volume: i64) {
+        self.volume = volume;
+    }
+
+    fn state(&self) -> State {
+        let Some(position) = self.clock() else {
+            return State {
+                duration: 0,
+                time_pos: 0,
+                volume: self.volume,
+                title: String::new(),
+                path: String::new(),
+            };
+        };
+        let path = self.track.clone().unwrap_or_default();
+        let title = Path::new(&path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        State {
+            duration: self.duration.as_secs() as i64,
+            time_pos: position.as_secs() as i64,
+            volume: self.volume,
+            title,
+            path,
+        }
+    }
+}
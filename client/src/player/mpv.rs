@@ -0,0 +1,69 @@
+use libmpv::Mpv;
+
+use super::{PlayerBackend, State};
+
+/// Plays the songs through libmpv
+pub struct MpvBackend {
+    player: Mpv,
+}
+
+impl MpvBackend {
+    pub fn new() -> Self {
+        let player = Mpv::new().unwrap();
+        player.set_property("video", false).unwrap();
+        player.set_property("ytdl", true).unwrap();
+        MpvBackend { player }
+    }
+}
+
+impl PlayerBackend for MpvBackend {
+    fn load(&mut self, url: &str) {
+        // It is necessary to surround the url with quotes to avoid errors
+        if let Err(e) = self.player.command("loadfile", &[&format!("\"{}\"", url)]) {
+            eprintln!("error {:?}", e);
+        }
+    }
+
+    fn paused(&self) -> bool {
+        self.player.get_property("pause").unwrap()
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        let _ = if paused {
+            self.player.pause()
+        } else {
+            self.player.unpause()
+        };
+    }
+
+    fn stop(&mut self) {
+        self.player.command("stop", &[]).unwrap();
+    }
+
+    fn seek(&mut self, dt: i64) {
+        self.player.seek_forward(dt as f64).unwrap();
+    }
+
+    fn volume(&self) -> i64 {
+        self.player.get_property("volume").unwrap()
+    }
+
+    fn set_volume(&mut self, volume: i64) {
+        let _ = self.player.set_property("volume", volume);
+    }
+
+    fn state(&self) -> State {
+        let duration = self.player.get_property("duration").unwrap_or_default();
+        let time_pos = self.player.get_property("time-pos").unwrap_or_default();
+        let volume = self.player.get_property("volume").unwrap_or_default();
+        let title = self.player.get_property("media-title").unwrap_or_default();
+        let path = self.player.get_property("path").unwrap_or_default();
+        State {
+            duration,
+            time_pos,
+            volume,
+            title,
+            path,
+        }
+    }
+}
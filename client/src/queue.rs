@@ -0,0 +1,627 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+
+use music_server::source_types::Song;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+/// A song to play, from any source
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueEntry {
+    /// Tells apart the copies of a song, given by the queue
+    #[serde(default)]
+    pub id: u64,
+    /// Version of the queue the song of the entry was last updated at
+    #[serde(skip)]
+    pub revision: u64,
+    pub source: String,
+    /// Id of the playlist the song was queued from
+    pub playlist: String,
+    pub song: Song,
+}
+
+impl QueueEntry {
+    pub fn new(source: String, playlist: String, song: Song) -> Self {
+        QueueEntry {
+            id: 0,
+            revision: 0,
+            source,
+            playlist,
+            song,
+        }
+    }
+}
+
+/// What plays once the current entry ended
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Repeat {
+    /// The next entry, nothing after the last one
+    #[default]
+    Off,
+    /// The current entry again
+    One,
+    /// The next entry, the first one after the last one
+    All,
+}
+
+impl Repeat {
+    /// The mode following this one, to go through them with a single key
+    pub fn cycle(self) -> Self {
+        match self {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Off,
+        }
+    }
+}
+
+/// The songs to play in order, saved to `path` on every change
+#[derive(Serialize, Deserialize, Default)]
+pub struct Queue {
+    entries: Vec<QueueEntry>,
+    current: Option<usize>,
+    #[serde(default)]
+    repeat: Repeat,
+    /// Positions of the entries in the order they had before being shuffled,
+    /// `None` when the queue is not shuffled
+    #[serde(default)]
+    unshuffled: Option<Vec<usize>>,
+    /// Id of the next entry added
+    #[serde(default)]
+    next_id: u64,
+    /// Bumped on every change, so that the watchers of the queue know when to look again
+    #[serde(skip)]
+    version: u64,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Queue {
+    /// Loads the queue saved at `path`, an empty queue when there is none
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let saved = path
+            .as_ref()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| serde_json::from_reader::<_, Queue>(BufReader::new(file)).ok());
+        let mut queue = Queue {
+            path,
+            ..saved.unwrap_or_default()
+        };
+        // an edited file may not say where every entry was before the shuffle
+        if queue
+            .unshuffled
+            .as_ref()
+            .is_some_and(|unshuffled| unshuffled.len() != queue.entries.len())
+        {
+            queue.unshuffled = Some((0..queue.entries.len()).collect());
+        }
+        if queue.entries.iter().any(|entry| entry.id == 0) {
+            let entries = std::mem::take(&mut queue.entries);
+            queue.entries = queue.number(entries);
+        }
+        queue
+    }
+
+    /// Saves the queue after a change
+    fn changed(&mut self) {
+        self.version += 1;
+        self.save();
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            // losing the queue is not worth interrupting the playback
+            if let Ok(file) = File::create(path) {
+                let _ = serde_json::to_writer(BufWriter::new(file), self);
+            }
+        }
+    }
+
+    /// Gives their ids to new entries
+    fn number(&mut self, mut entries: Vec<QueueEntry>) -> Vec<QueueEntry> {
+        for entry in entries.iter_mut() {
+            self.next_id += 1;
+            entry.id = self.next_id;
+        }
+        entries
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn current_entry(&self) -> Option<&QueueEntry> {
+        self.entries.get(self.current?)
+    }
+
+    /// Position of the entry with the id `id`
+    pub fn position(&self, id: u64) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.id == id)
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+        self.changed();
+    }
+
+    pub fn shuffled(&self) -> bool {
+        self.unshuffled.is_some()
+    }
+
+    /// Shuffles the entries after putting the current one first, so that it keeps playing,
+    /// or puts them back in the order they had
+    pub fn set_shuffled(&mut self, shuffled: bool) {
+        if shuffled == self.shuffled() {
+            return;
+        }
+        if shuffled {
+            self.unshuffled = Some((0..self.entries.len()).collect());
+            self.shuffle();
+        } else if let Some(unshuffled) = self.unshuffled.take() {
+            let mut order: Vec<usize> = (0..self.entries.len()).collect();
+            order.sort_by_key(|&i| unshuffled[i]);
+            self.reorder(&order);
+        }
+        self.changed();
+    }
+
+    fn shuffle(&mut self) {
+        let mut order: Vec<usize> = (0..self.entries.len())
+            .filter(|&i| Some(i) != self.current)
+            .collect();
+        order.shuffle(&mut rand::thread_rng());
+        if let Some(current) = self.current {
+            order.insert(0, current);
+        }
+        self.reorder(&order);
+    }
+
+    /// Puts the entry at `order[i]` at `i`, along with its position before the shuffle
+    fn reorder(&mut self, order: &[usize]) {
+        let mut entries: Vec<Option<QueueEntry>> = self.entries.drain(..).map(Some).collect();
+        self.entries = order.iter().filter_map(|&i| entries[i].take()).collect();
+        if let Some(unshuffled) = self.unshuffled.take() {
+            self.unshuffled = Some(order.iter().map(|&i| unshuffled[i]).collect());
+        }
+        self.current = self
+            .current
+            .and_then(|current| order.iter().position(|&i| i == current));
+    }
+
+    /// Replaces the entries, shuffling them when the queue is shuffled
+    pub fn replace(&mut self, entries: Vec<QueueEntry>) {
+        self.entries = self.number(entries);
+        self.current = None;
+        if self.shuffled() {
+            self.unshuffled = Some((0..self.entries.len()).collect());
+            self.shuffle();
+        }
+        self.changed();
+    }
+
+    /// Adds `entries` at the end of the queue
+    pub fn enqueue(&mut self, entries: Vec<QueueEntry>) {
+        let start = self.entries.len();
+        let entries = self.number(entries);
+        self.entries.extend(entries);
+        if let Some(unshuffled) = self.unshuffled.as_mut() {
+            unshuffled.extend(start..self.entries.len());
+        }
+        self.changed();
+    }
+
+    /// Adds `entries` right after the current one, returning the position of the first one.
+    /// Once unshuffled, they come right after the current one as well.
+    pub fn play_next(&mut self, entries: Vec<QueueEntry>) -> usize {
+        let at = self.current.map_or(0, |current| current + 1);
+        self.insert(at, entries)
+    }
+
+    /// Adds `entries` at `at`, after the entry before it once unshuffled as well,
+    /// returning the position of the first one
+    pub fn insert(&mut self, at: usize, entries: Vec<QueueEntry>) -> usize {
+        let at = at.min(self.entries.len());
+        let count = entries.len();
+        let entries = self.number(entries);
+        self.entries.splice(at..at, entries);
+        if let Some(unshuffled) = self.unshuffled.as_mut() {
+            let rank = at.checked_sub(1).map_or(0, |before| unshuffled[before] + 1);
+            for r in unshuffled.iter_mut().filter(|r| **r >= rank) {
+                *r += count;
+            }
+            unshuffled.splice(at..at, rank..rank + count);
+        }
+        self.current = self.current.map(|current| {
+            if current >= at {
+                current + count
+            } else {
+                current
+            }
+        });
+        self.changed();
+        at
+    }
+
+    /// Removes the entry at `index`, returning whether it was the current one
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index >= self.entries.len() {
+            return false;
+        }
+        self.entries.remove(index);
+        if let Some(unshuffled) = self.unshuffled.as_mut() {
+            let rank = unshuffled.remove(index);
+            for r in unshuffled.iter_mut().filter(|r| **r > rank) {
+                *r -= 1;
+            }
+        }
+        let was_current = self.current == Some(index);
+        self.current = match self.current {
+            Some(current) if current == index => None,
+            Some(current) if current > index => Some(current - 1),
+            current => current,
+        };
+        self.changed();
+        was_current
+    }
+
+    /// Moves the entry at `from` to `to`, the current entry stays the current one
+    pub fn move_entry(&mut self, from: usize, to: usize) {
+        if from >= self.entries.len() || to >= self.entries.len() {
+            return;
+        }
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+        if let Some(unshuffled) = self.unshuffled.as_mut() {
+            let rank = unshuffled.remove(from);
+            unshuffled.insert(to, rank);
+        }
+        self.current = self.current.map(|current| {
+            if current == from {
+                to
+            } else if from < current && current <= to {
+                current - 1
+            } else if to <= current && current < from {
+                current + 1
+            } else {
+                current
+            }
+        });
+        self.changed();
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.current = None;
+        if let Some(unshuffled) = self.unshuffled.as_mut() {
+            unshuffled.clear();
+        }
+        self.changed();
+    }
+
+    /// Makes the entry at `index` the current one
+    pub fn jump(&mut self, index: usize) -> Option<&QueueEntry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+        self.current = Some(index);
+        self.changed();
+        self.current_entry()
+    }
+
+    /// Moves on to the next entry, `None` at the end of the queue unless it repeats
+    pub fn next(&mut self) -> Option<&QueueEntry> {
+        let next = self.current.map_or(0, |current| current + 1);
+        if next >= self.entries.len() && self.repeat != Repeat::Off {
+            return self.jump(0);
+        }
+        self.jump(next)
+    }
+
+    /// Goes back to the previous entry, `None` at the start of the queue unless it repeats
+    pub fn previous(&mut self) -> Option<&QueueEntry> {
+        match self.current? {
+            0 if self.repeat != Repeat::Off => self.jump(self.entries.len().checked_sub(1)?),
+            current => self.jump(current.checked_sub(1)?),
+        }
+    }
+
+    /// What plays once the current entry ended, according to the repeat mode
+    pub fn advance(&mut self) -> Option<&QueueEntry> {
+        match (self.repeat, self.current) {
+            (Repeat::One, Some(current)) => self.jump(current),
+            _ => self.next(),
+        }
+    }
+
+    /// Replaces every copy of `song` from `source`
+    pub fn update_song(&mut self, source: &str, song: &Song) {
+        let mut changed = false;
+        let revision = self.version + 1;
+        for entry in self.entries.iter_mut() {
+            if entry.source == source && entry.song.id == song.id {
+                entry.song = song.clone();
+                entry.revision = revision;
+                changed = true;
+            }
+        }
+        if changed {
+            self.changed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URLS: [&str; 5] = ["a", "b", "c", "d", "e"];
+
+    fn entry(url: &str) -> QueueEntry {
+        let song = Song {
+            id: url.to_string(),
+            url: url.to_string(),
+            ..Default::default()
+        };
+        QueueEntry::new(String::new(), String::new(), song)
+    }
+
+    fn queue(urls: &[&str]) -> Queue {
+        let mut queue = Queue::load(None);
+        queue.enqueue(urls.iter().map(|url| entry(url)).collect());
+        queue
+    }
+
+    fn urls(queue: &Queue) -> Vec<&str> {
+        queue
+            .entries()
+            .iter()
+            .map(|e| e.song.url.as_str())
+            .collect()
+    }
+
+    /// A shuffled queue of `URLS` playing its third entry
+    fn shuffled() -> Queue {
+        let mut queue = queue(&URLS);
+        queue.jump(2);
+        queue.set_shuffled(true);
+        assert_eq!(queue.current(), Some(0));
+        assert_eq!(urls(&queue)[0], "c");
+        queue
+    }
+
+    #[test]
+    fn unshuffle() {
+        let mut queue = shuffled();
+        let mut sorted = urls(&queue);
+        sorted.sort();
+        assert_eq!(sorted, URLS);
+        queue.set_shuffled(false);
+        assert_eq!(urls(&queue), URLS);
+        assert_eq!(queue.current(), Some(2));
+    }
+
+    #[test]
+    fn insert_while_shuffled() {
+        let mut queue = shuffled();
+        let before = urls(&queue)[2].to_string();
+        assert_eq!(queue.insert(3, vec![entry("x"), entry("y")]), 3);
+        assert_eq!(&urls(&queue)[3..5], ["x", "y"]);
+        queue.set_shuffled(false);
+        // the new entries come right after the one they were inserted after
+        let mut expected: Vec<&str> = URLS.to_vec();
+        let at = expected.iter().position(|url| *url == before).unwrap() + 1;
+        expected.splice(at..at, ["x", "y"]);
+        assert_eq!(urls(&queue), expected);
+    }
+
+    #[test]
+    fn insert_first_while_shuffled() {
+        let mut queue = shuffled();
+        queue.insert(0, vec![entry("x")]);
+        assert_eq!(queue.current(), Some(1));
+        queue.set_shuffled(false);
+        assert_eq!(urls(&queue), ["x", "a", "b", "c", "d", "e"]);
+        assert_eq!(queue.current(), Some(3));
+    }
+
+    #[test]
+    fn play_next_while_shuffled() {
+        let mut queue = shuffled();
+        assert_eq!(queue.play_next(vec![entry("x")]), 1);
+        queue.set_shuffled(false);
+        assert_eq!(urls(&queue), ["a", "b", "c", "x", "d", "e"]);
+    }
+
+    #[test]
+    fn remove_while_shuffled() {
+        let mut queue = shuffled();
+        let removed = urls(&queue)[3].to_string();
+        assert!(!queue.remove(3));
+        queue.set_shuffled(false);
+        let expected: Vec<&str> = URLS.into_iter().filter(|url| *url != removed).collect();
+        assert_eq!(urls(&queue), expected);
+        assert_eq!(urls(&queue)[queue.current().unwrap()], "c");
+    }
+
+    #[test]
+    fn move_while_shuffled() {
+        let mut queue = shuffled();
+        queue.move_entry(4, 1);
+        queue.move_entry(0, 3);
+        assert_eq!(urls(&queue)[3], "c");
+        // the moved entries keep their place in the order before the shuffle
+        queue.set_shuffled(false);
+        assert_eq!(urls(&queue), URLS);
+        assert_eq!(queue.current(), Some(2));
+    }
+
+    #[test]
+    fn insert_keeps_the_current_entry() {
+        let mut queue = queue(&URLS);
+        queue.jump(2);
+        assert_eq!(queue.insert(1, vec![entry("x"), entry("y")]), 1);
+        assert_eq!(queue.current(), Some(4));
+        queue.insert(5, vec![entry("z")]);
+        assert_eq!(queue.current(), Some(4));
+        assert_eq!(queue.insert(100, vec![entry("w")]), 8);
+        assert_eq!(queue.current_entry().unwrap().song.url, "c");
+    }
+
+    #[test]
+    fn remove_keeps_the_current_entry() {
+        let mut queue = queue(&URLS);
+        queue.jump(2);
+        assert!(!queue.remove(0));
+        assert_eq!(queue.current(), Some(1));
+        assert!(!queue.remove(3));
+        assert!(!queue.remove(10));
+        assert_eq!(queue.current(), Some(1));
+        assert!(queue.remove(1));
+        assert_eq!(queue.current(), None);
+        assert_eq!(urls(&queue), ["b", "d"]);
+    }
+
+    #[test]
+    fn move_keeps_the_current_entry() {
+        let mut queue = queue(&URLS);
+        queue.jump(2);
+        // from < current <= to
+        queue.move_entry(0, 2);
+        assert_eq!(urls(&queue), ["b", "c", "a", "d", "e"]);
+        assert_eq!(queue.current(), Some(1));
+        // to <= current < from
+        queue.move_entry(4, 1);
+        assert_eq!(urls(&queue), ["b", "e", "c", "a", "d"]);
+        assert_eq!(queue.current(), Some(2));
+        queue.move_entry(3, 4);
+        assert_eq!(queue.current(), Some(2));
+        queue.move_entry(2, 0);
+        assert_eq!(queue.current(), Some(0));
+        queue.move_entry(0, 5);
+        assert_eq!(queue.current_entry().unwrap().song.url, "c");
+    }
+
+    #[test]
+    fn ends_of_the_queue() {
+        let mut queue = queue(&["a", "b"]);
+        assert_eq!(queue.next().unwrap().song.url, "a");
+        assert!(queue.previous().is_none());
+        queue.next();
+        assert!(queue.next().is_none());
+        assert!(queue.advance().is_none());
+        assert_eq!(queue.current(), Some(1));
+
+        queue.set_repeat(Repeat::All);
+        assert_eq!(queue.next().unwrap().song.url, "a");
+        assert_eq!(queue.previous().unwrap().song.url, "b");
+        assert_eq!(queue.advance().unwrap().song.url, "a");
+
+        queue.set_repeat(Repeat::One);
+        assert_eq!(queue.advance().unwrap().song.url, "a");
+        assert_eq!(queue.previous().unwrap().song.url, "b");
+        assert_eq!(queue.next().unwrap().song.url, "a");
+        assert_eq!(queue.current(), Some(0));
+
+        queue.clear();
+        assert!(queue.next().is_none() && queue.previous().is_none());
+        assert!(queue.advance().is_none());
+    }
+
+    #[test]
+    fn update_song() {
+        let mut queue = queue(&["a", "b", "a"]);
+        let mut song = entry("a").song;
+        song.title = "A".to_string();
+        queue.update_song("", &song);
+        let titles: Vec<_> = queue
+            .entries()
+            .iter()
+            .map(|e| e.song.title.as_str())
+            .collect();
+        assert_eq!(titles, ["A", "", "A"]);
+        assert!(queue.entries()[0].revision > queue.entries()[1].revision);
+    }
+
+    /// A file of the temporary directory for the test `name`, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let file = format!("yauma-{}-{}.json", name, std::process::id());
+            TempFile(std::env::temp_dir().join(file))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn saved_and_loaded() {
+        let file = TempFile::new("saved-queue");
+        let mut queue = Queue::load(Some(file.0.clone()));
+        queue.enqueue(URLS.iter().map(|url| entry(url)).collect());
+        queue.jump(3);
+        queue.set_repeat(Repeat::All);
+        queue.set_shuffled(true);
+        let removed = urls(&queue)[4].to_string();
+        queue.remove(4);
+
+        let mut loaded = Queue::load(Some(file.0.clone()));
+        assert_eq!(urls(&loaded), urls(&queue));
+        let ids = |queue: &Queue| queue.entries().iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(&loaded), ids(&queue));
+        assert_eq!(loaded.current(), Some(0));
+        assert_eq!(loaded.repeat(), Repeat::All);
+        assert!(loaded.shuffled());
+        assert_eq!(loaded.next_id, 5);
+        loaded.enqueue(vec![entry("f")]);
+        assert_eq!(loaded.entries().last().unwrap().id, 6);
+        loaded.set_shuffled(false);
+        let mut expected = URLS.to_vec();
+        expected.retain(|url| *url != removed);
+        expected.push("f");
+        assert_eq!(urls(&loaded), expected);
+        assert_eq!(loaded.current_entry().unwrap().song.url, "d");
+    }
+
+    #[test]
+    fn missing_ids_are_given() {
+        let file = TempFile::new("numbered-queue");
+        let mut numbered = entry("b");
+        numbered.id = 4;
+        let saved = Queue {
+            entries: vec![entry("a"), numbered, entry("c")],
+            current: Some(1),
+            next_id: 4,
+            ..Default::default()
+        };
+        std::fs::write(&file.0, serde_json::to_string(&saved).unwrap()).unwrap();
+        let loaded = Queue::load(Some(file.0.clone()));
+        let ids: Vec<_> = loaded.entries().iter().map(|e| e.id).collect();
+        assert_eq!(ids, [5, 6, 7]);
+        assert_eq!(loaded.next_id, 7);
+        assert_eq!(urls(&loaded), ["a", "b", "c"]);
+        assert_eq!(loaded.current(), Some(1));
+        assert!(!loaded.shuffled());
+    }
+}
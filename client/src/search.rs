@@ -0,0 +1,89 @@
+use music_server::source_types::{Playlist, Song};
+use tui::widgets::ListState;
+
+/// Bonus of a character found right after the previous one
+const CONSECUTIVE: i64 = 5;
+/// Bonus of a character found at the start of a word
+const WORD_START: i64 = 8;
+
+/// How well `text` matches `query`, whose characters have to be found in `text` in order,
+/// ignoring the case. `None` when they are not all found, higher is better.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut from = 0;
+    let mut last = None;
+    for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let found = from + text[from..].iter().position(|t| *t == c)?;
+        score += 1;
+        if last.is_some_and(|last| last + 1 == found) {
+            score += CONSECUTIVE;
+        }
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += WORD_START;
+        }
+        // the characters skipped make the match looser
+        score -= (found - from).min(3) as i64;
+        last = Some(found);
+        from = found + 1;
+    }
+    Some(score)
+}
+
+/// The best score of `texts`
+fn best_score<'a>(query: &str, texts: impl Iterator<Item = &'a String>) -> Option<i64> {
+    texts.filter_map(|text| fuzzy_score(query, text)).max()
+}
+
+/// How well `song` matches `query`, by its title, artists or tags
+pub fn song_score(query: &str, song: &Song) -> Option<i64> {
+    let title = std::iter::once(&song.title);
+    best_score(
+        query,
+        title.chain(song.artists.iter()).chain(song.tags.iter()),
+    )
+}
+
+/// How well `playlist` matches `query`, by its title or tags
+pub fn playlist_score(query: &str, playlist: &Playlist) -> Option<i64> {
+    best_score(
+        query,
+        std::iter::once(&playlist.title).chain(playlist.tags.iter()),
+    )
+}
+
+/// The query typed after `/`, narrowing the focused panel to the items matching it
+pub struct Filter {
+    pub query: String,
+    /// Selection of the panel before filtering it, given back when the filter is cancelled
+    pub previous: Option<usize>,
+}
+
+/// A playlist, or a song of it, found by the search
+pub struct Found {
+    pub source: usize,
+    pub playlist: usize,
+    pub song: Option<usize>,
+    /// What the search shows of it
+    pub label: String,
+    pub score: i64,
+}
+
+/// The popup searching every playlist loaded
+#[derive(Default)]
+pub struct Search {
+    pub query: String,
+    /// Best results first
+    pub results: Vec<Found>,
+    pub state: ListState,
+}
+
+/// Keys given to the filter and the search while typing their query
+pub enum Input {
+    Char(char),
+    Backspace,
+    Up,
+    Down,
+    Enter,
+    Escape,
+}
@@ -0,0 +1,68 @@
+//! Percent encoding of the parts of the urls built or read by the client
+
+/// Encodes `value` so that it can be put in a query
+pub fn encode(value: &str) -> String {
+    encode_except(value, b"")
+}
+
+/// Encodes the path `path`, keeping its separators
+pub fn encode_path(path: &str) -> String {
+    encode_except(path, b"/")
+}
+
+fn encode_except(value: &str, kept: &[u8]) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b if kept.contains(&b) => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Decodes the `%XX` sequences of `value`, the invalid ones are kept as is
+pub fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        assert_eq!(encode("a+b/c d&=é"), "a%2Bb%2Fc%20d%26%3D%C3%A9");
+        let encoded = encode_path("/music/AC DC/#1+é.mp3");
+        assert_eq!(encoded, "/music/AC%20DC/%231%2B%C3%A9.mp3");
+    }
+
+    #[test]
+    fn decoding() {
+        assert_eq!(decode("/music/AC%20DC/%231.mp3"), "/music/AC DC/#1.mp3");
+        assert_eq!(decode("%C3%A9+%zz%"), "é+%zz%");
+        let path = "/tmp/50% off?/ü.ogg";
+        assert_eq!(decode(&encode_path(path)), path);
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use md5::{Digest, Md5};
+use music_server::request::{AnswerType, Credentials, ErrorType, Permission, Request};
+use rustls_pemfile::Item;
+use tokio_rustls::{rustls, TlsAcceptor};
+
+use crate::config::{self, ClientAccess, Config, TlsConfig, UserConfig, DEFAULT_USER};
+
+/// Who is behind a connection
+#[derive(Clone)]
+pub struct Identity {
+    /// Name of the configured client
+    pub name: String,
+    pub user: UserConfig,
+    pub permission: Permission,
+}
+
+/// Identity of a connection that did not log in, `None` when it has to
+pub fn anonymous() -> Option<Identity> {
+    let config = config::get_config();
+    if !config.clients.is_empty() {
+        return None;
+    }
+    Some(Identity {
+        name: "anonymous".to_string(),
+        user: config.user(DEFAULT_USER)?,
+        permission: Permission::Admin,
+    })
+}
+
+/// Finds the configured client matching `credentials`
+pub fn login(credentials: &Credentials) -> Result<Identity, String> {
+    let config = config::get_config();
+    if config.clients.is_empty() {
+        return anonymous().ok_or_else(|| "No default user".to_string());
+    }
+    let client = find_client(&config.clients, credentials)
+        .ok_or_else(|| "Invalid credentials".to_string())?;
+    identity(&config, client)
+}
+
+/// Finds the client called `name` whose password, followed by `salt`, hashes to `token`.
+/// This is how the subsonic clients log in without sending the password.
+pub fn login_salted(name: &str, token: &str, salt: &str) -> Result<Identity, String> {
+    let config = config::get_config();
+    if config.clients.is_empty() {
+        return anonymous().ok_or_else(|| "No default user".to_string());
+    }
+    let client = find_salted(&config.clients, name, token, salt)
+        .ok_or_else(|| "Invalid credentials".to_string())?;
+    identity(&config, client)
+}
+
+fn find_client<'a>(
+    clients: &'a [ClientAccess],
+    credentials: &Credentials,
+) -> Option<&'a ClientAccess> {
+    clients.iter().find(|client| match credentials {
+        Credentials::Token(token) => !client.token.is_empty() && same(&client.token, token),
+        Credentials::Password { user, password } => {
+            !client.password.is_empty() && client.name == *user && same(&client.password, password)
+        }
+    })
+}
+
+fn find_salted<'a>(
+    clients: &'a [ClientAccess],
+    name: &str,
+    token: &str,
+    salt: &str,
+) -> Option<&'a ClientAccess> {
+    clients.iter().find(|client| {
+        let hash = Md5::digest(format!("{}{}", client.password, salt));
+        !client.password.is_empty()
+            && client.name == name
+            && same(&format!("{:x}", hash), &token.to_lowercase())
+    })
+}
+
+fn identity(config: &Config, client: &ClientAccess) -> Result<Identity, String> {
+    let user = if client.user.is_empty() {
+        DEFAULT_USER
+    } else {
+        &client.user
+    };
+    let user = config
+        .user(user)
+        .ok_or_else(|| format!("Unknown user {}", user))?;
+    Ok(Identity {
+        name: client.name.clone(),
+        user,
+        permission: client.permission,
+    })
+}
+
+/// Compares secrets in a time that does not depend on where they differ
+fn same(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub fn denied(request: &Request) -> AnswerType {
+    AnswerType::Error(ErrorType::PermissionDenied(format!(
+        "{:?} requires the {:?} permission",
+        request.ty,
+        request.ty.permission()
+    )))
+}
+
+pub fn tls_acceptor(tls: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidData, err);
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(&tls.certificate)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(&tls.key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                Some(rustls::PrivateKey(key))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("No private key in {}", tls.key)))?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|err| invalid(err.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(name: &str, token: &str, password: &str, permission: Permission) -> ClientAccess {
+        ClientAccess {
+            name: name.to_string(),
+            token: token.to_string(),
+            password: password.to_string(),
+            permission,
+            user: String::new(),
+        }
+    }
+
+    fn clients() -> Vec<ClientAccess> {
+        vec![
+            client("alice", "alice-token", "sesame", Permission::Admin),
+            client("bob", "", "hunter2", Permission::Play),
+            client("carol", "carol-token", "", Permission::Read),
+        ]
+    }
+
+    fn password(user: &str, password: &str) -> Credentials {
+        Credentials::Password {
+            user: user.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    /// Name of the client logging in with `credentials`
+    fn login(clients: &[ClientAccess], credentials: Credentials) -> Option<&str> {
+        find_client(clients, &credentials).map(|client| client.name.as_str())
+    }
+
+    fn login_salted<'a>(
+        clients: &'a [ClientAccess],
+        name: &str,
+        token: &str,
+        salt: &str,
+    ) -> Option<&'a str> {
+        find_salted(clients, name, token, salt).map(|client| client.name.as_str())
+    }
+
+    #[test]
+    fn token() {
+        let clients = clients();
+        let token = |token: &str| Credentials::Token(token.to_string());
+        assert_eq!(login(&clients, token("carol-token")), Some("carol"));
+        assert_eq!(login(&clients, token("carol-tokem")), None);
+        assert_eq!(login(&clients, token("carol")), None);
+        // bob has no token, which does not let an empty one in
+        assert_eq!(login(&clients, token("")), None);
+    }
+
+    #[test]
+    fn password_login() {
+        let clients = clients();
+        assert_eq!(login(&clients, password("bob", "hunter2")), Some("bob"));
+        assert_eq!(login(&clients, password("bob", "hunter3")), None);
+        assert_eq!(login(&clients, password("bob", "sesame")), None);
+        assert_eq!(login(&clients, password("dave", "hunter2")), None);
+        // carol has no password, which does not let an empty one in
+        assert_eq!(login(&clients, password("carol", "")), None);
+    }
+
+    #[test]
+    fn salted() {
+        let clients = clients();
+        // the example of the subsonic api documentation
+        let (token, salt) = ("26719a1196d2a940705a59634eb18eab", "c19b2d");
+        assert_eq!(login_salted(&clients, "alice", token, salt), Some("alice"));
+        let upper = token.to_uppercase();
+        assert_eq!(login_salted(&clients, "alice", &upper, salt), Some("alice"));
+        assert_eq!(login_salted(&clients, "alice", token, "c19b2e"), None);
+        assert_eq!(login_salted(&clients, "bob", token, salt), None);
+        assert_eq!(login_salted(&clients, "dave", token, salt), None);
+        let empty = format!("{:x}", Md5::digest(salt));
+        assert_eq!(login_salted(&clients, "carol", &empty, salt), None);
+    }
+
+    #[test]
+    fn identities() {
+        let config = Config {
+            users: vec![UserConfig {
+                name: "bob".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut bob = client("bob", "", "hunter2", Permission::Download);
+        let identity_of = |client: &ClientAccess| identity(&config, client);
+        let default = identity_of(&bob).unwrap();
+        assert_eq!(default.user.name, DEFAULT_USER);
+        assert_eq!(default.permission, Permission::Download);
+        bob.user = "bob".to_string();
+        assert_eq!(identity_of(&bob).unwrap().user.name, "bob");
+        bob.user = "dave".to_string();
+        let unknown = identity_of(&bob).err();
+        assert_eq!(unknown.as_deref(), Some("Unknown user dave"));
+    }
+
+    #[test]
+    fn permission_levels() {
+        use Permission::*;
+        assert!(Read < Play && Play < Download && Download < Admin);
+        let mut levels = [Admin, Read, Download, Play];
+        levels.sort();
+        assert_eq!(levels, [Read, Play, Download, Admin]);
+        assert_eq!(Permission::default(), Read);
+    }
+
+    #[test]
+    fn same_secrets() {
+        assert!(same("secret", "secret"));
+        assert!(!same("secret", "secreT"));
+        assert!(!same("secret", "secret2"));
+        assert!(!same("secret", ""));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use music_server::request::{AuthStatus, EventType};
+use tokio::sync::{watch, OwnedMutexGuard};
+
+use crate::events;
+
+struct SourceAuth {
+    status: watch::Sender<AuthStatus>,
+    /// Hands the code to the flows waiting for it, `None` when no flow is pending
+    code: Option<watch::Sender<Option<String>>>,
+    /// Held while a source authenticates so that the connections of a user run a single flow
+    flow: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Default for SourceAuth {
+    fn default() -> Self {
+        SourceAuth {
+            status: watch::channel(AuthStatus::default()).0,
+            code: None,
+            flow: Default::default(),
+        }
+    }
+}
+
+/// A source of a user
+type Key = (String, String);
+
+fn key(user: &str, source: &str) -> Key {
+    (user.to_string(), source.to_string())
+}
+
+/// Authentication state of the sources of every user, shared by all connections so that
+/// any of them, including ones without a TUI, can complete a flow started elsewhere
+static STATES: OnceLock<Mutex<HashMap<Key, SourceAuth>>> = OnceLock::new();
+
+fn states() -> MutexGuard<'static, HashMap<Key, SourceAuth>> {
+    STATES
+        .get_or_init(Default::default)
+        .lock()
+        .expect("poisoned lock")
+}
+
+pub fn status(user: &str, source: &str) -> AuthStatus {
+    states()
+        .get(&key(user, source))
+        .map(|s| s.status.borrow().clone())
+        .unwrap_or_default()
+}
+
+pub fn set_status(user: &str, source: &str, status: AuthStatus) {
+    let mut states = states();
+    let state = states.entry(key(user, source)).or_default();
+    if !matches!(status, AuthStatus::Pending(_)) {
+        state.code = None;
+    }
+    state.status.send_replace(status);
+}
+
+/// Waits for the pending flow, if any, to end and returns how it ended
+pub async fn outcome(user: &str, source: &str) -> AuthStatus {
+    let mut status = states()
+        .entry(key(user, source))
+        .or_default()
+        .status
+        .subscribe();
+    let done = status
+        .wait_for(|status| !matches!(status, AuthStatus::Pending(_)))
+        .await
+        .map(|status| status.clone());
+    // the sender lives as long as the server
+    done.unwrap_or_default()
+}
+
+/// Waits for the other flows of the source to end, the next one starts once the guard is dropped
+pub async fn lock(user: &str, source: &str) -> OwnedMutexGuard<()> {
+    let flow = states().entry(key(user, source)).or_default().flow.clone();
+    flow.lock_owned().await
+}
+
+/// Url of the flow waiting for the user, if any
+pub fn pending_url(user: &str, source: &str) -> Option<String> {
+    match status(user, source) {
+        AuthStatus::Pending(url) => Some(url),
+        _ => None,
+    }
+}
+
+/// The code a flow waits for
+pub struct PendingCode {
+    code: watch::Receiver<Option<String>>,
+    /// Whether the flow was started by this call, rather than already waiting for the user
+    pub started: bool,
+}
+
+impl PendingCode {
+    /// Waits for what the user sent with `complete`
+    pub async fn wait(mut self) -> Result<String, String> {
+        let code = self.code.wait_for(Option::is_some).await;
+        code.map(|code| code.clone().unwrap_or_default())
+            .map_err(|_| "Authentication aborted".to_string())
+    }
+}
+
+/// Starts waiting for the user to visit `url`, or joins the flow already waiting for it
+/// so that every caller gets the code the user sends.
+pub fn request_code(user: &str, source: &str, url: String) -> PendingCode {
+    let code = {
+        let mut states = states();
+        let state = states.entry(key(user, source)).or_default();
+        match &state.code {
+            Some(code) => {
+                return PendingCode {
+                    code: code.subscribe(),
+                    started: false,
+                }
+            }
+            None => {
+                let (tx, rx) = watch::channel(None);
+                state.status.send_replace(AuthStatus::Pending(url.clone()));
+                state.code = Some(tx);
+                rx
+            }
+        }
+    };
+    println!(
+        "Authentication required for {} of {}: {}",
+        source, user, url
+    );
+    events::publish(user, source, EventType::AuthRequired(url));
+    PendingCode {
+        code,
+        started: true,
+    }
+}
+
+/// Hands the code (or redirect url) provided by the user to the waiting flows
+pub fn complete(user: &str, source: &str, input: String) -> Result<(), String> {
+    let code = states()
+        .get_mut(&key(user, source))
+        .and_then(|s| s.code.take());
+    match code {
+        Some(code) => code
+            .send(Some(input))
+            .map_err(|_| "The authentication flow was aborted".to_string()),
+        None => Err(format!("No authentication pending for {}", source)),
+    }
+}
+
+/// Extracts the code from a redirect url, `input` is returned as is if it is not one
+pub fn parse_code(input: &str) -> String {
+    let input = input.trim();
+    let query = match input.split_once('?') {
+        Some((_, query)) => query,
+        None => return input.to_string(),
+    };
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == "code")
+        .map(|(_, value)| percent_decode(value))
+        .unwrap_or_else(|| input.to_string())
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
@@ -0,0 +1,311 @@
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use music_server::request::{Answer, AnswerType, AuthStatus, EventType, Request};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::config::{self, UserConfig, DEFAULT_USER};
+use crate::source::{self, Playlist, Song, Source};
+use crate::{auth, db, events};
+
+#[derive(Parser)]
+#[command(version, about = "Serves youtube and spotify playlists to music clients")]
+pub struct Cli {
+    /// Configuration file to use instead of the default one
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// User whose library the maintenance commands work on
+    #[arg(long, global = true, default_value = DEFAULT_USER)]
+    pub user: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Starts the server (default)
+    Serve(ServeArgs),
+    /// Authenticates a source, prompting for the code on the terminal
+    Auth { source: String },
+    /// Fetches the playlists that changed, from every source if none is given
+    Sync { source: Option<String> },
+    /// Downloads a playlist, given by id or title
+    Download { source: String, playlist: String },
+    /// Writes the stored playlists and songs as json on stdout
+    Export,
+    /// Reads playlists and songs written by `export` from stdin
+    Import,
+    /// Database maintenance
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Prints the library content
+    #[command(subcommand)]
+    List(ListCommand),
+}
+
+#[derive(Args, Default)]
+pub struct ServeArgs {
+    /// Port of the bare ip addresses, overrides the configuration
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Address to listen on, can be repeated, overrides the configuration.
+    /// Either an ip address, `host:port` or `unix:/path/to/socket`
+    #[arg(long)]
+    pub bind: Vec<String>,
+    /// Address serving the http api, can be repeated, overrides the configuration
+    #[arg(long)]
+    pub http: Vec<String>,
+    /// Address speaking the mpd protocol, can be repeated, overrides the configuration
+    #[arg(long)]
+    pub mpd: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Checks the database and the downloaded files
+    Check {
+        /// Marks the songs whose file is missing as not downloaded
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Forgets the youtube videos picked for the songs of other sources, of every source
+    /// if none is given, so that they are searched again. The matches set by hand are kept.
+    Rematch {
+        source: Option<String>,
+        /// Also marks the songs as not downloaded, so that the next download uses the new match
+        #[arg(long)]
+        redownload: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ListCommand {
+    /// Lists the stored playlists
+    Playlists,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedPlaylist {
+    source: String,
+    etag: String,
+    playlist: Playlist,
+    songs: Vec<Song>,
+}
+
+type CliResult = Result<(), Box<dyn std::error::Error>>;
+
+pub fn run(command: Command, user: &str) -> CliResult {
+    let user = config::get_config()
+        .user(user)
+        .ok_or_else(|| format!("Unknown user {}", user))?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        match command {
+            Command::Serve(_) => unreachable!("serve is handled by main"),
+            Command::Auth { source } => authenticate(&source, user).await,
+            Command::Sync { source } => sync(source, user).await,
+            Command::Download { source, playlist } => download(&source, &playlist, user).await,
+            Command::Export => export(&user.name),
+            Command::Import => import(&user.name).await,
+            Command::Db(DbCommand::Check { fix }) => check(fix),
+            Command::Db(DbCommand::Rematch { source, redownload }) => rematch(source, redownload),
+            Command::List(ListCommand::Playlists) => list_playlists(&user.name),
+        }
+    })
+}
+
+/// Creates a source of `user` outside of any connection, its answers are printed on stderr
+async fn open_source(
+    name: &str,
+    user: UserConfig,
+) -> Result<(Box<dyn Source>, broadcast::Sender<Request>), String> {
+    let (broad_tx, broad_rx) = broadcast::channel::<Request>(16);
+    let (mpsc_tx, mut mpsc_rx) = mpsc::channel::<Answer>(100);
+    tokio::spawn(async move {
+        while let Some(answer) = mpsc_rx.recv().await {
+            if let AnswerType::Message(message) = answer.data {
+                eprintln!("{}: {}", answer.client, message);
+            }
+        }
+    });
+    // the request channel must stay open for the source to keep working
+    match source::new_source(name, user, broad_rx, mpsc_tx).await {
+        Some(source) => Ok((source, broad_tx)),
+        None => Err(format!(
+            "Unknown or unavailable source {}, expected one of {}",
+            name,
+            source::SOURCES.join(", ")
+        )),
+    }
+}
+
+/// Prints the authentication urls of `user` and sends back the codes typed by the user
+async fn prompt_auth(user: String) {
+    let mut events = events::subscribe();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok((event_user, answer)) = events.recv().await {
+        if event_user != user {
+            continue;
+        }
+        if let AnswerType::Event(EventType::AuthRequired(url)) = answer.data {
+            eprintln!("Please direct your browser to {}", url);
+            eprintln!("then paste the code or the url you were redirected to:");
+            if let Ok(Some(line)) = lines.next_line().await {
+                if let Err(err) = auth::complete(&user, &answer.client, line) {
+                    eprintln!("{}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Prints the download progress
+async fn print_progress() {
+    let mut events = events::subscribe();
+    while let Ok((_, answer)) = events.recv().await {
+        if let AnswerType::Event(EventType::DownloadProgress {
+            playlist,
+            done,
+            total,
+        }) = answer.data
+        {
+            eprintln!("{}: {}/{}", playlist, done, total);
+        }
+    }
+}
+
+async fn authenticate(name: &str, user: UserConfig) -> CliResult {
+    tokio::spawn(prompt_auth(user.name.clone()));
+    let (mut source, _requests) = open_source(name, user).await?;
+    source.authenticate().await.await?;
+    let name = source.get_name();
+    match auth::status(&source.get_user(), &name) {
+        AuthStatus::Authenticated => println!("{} authenticated", name),
+        status => return Err(format!("{}: {:?}", name, status).into()),
+    }
+    Ok(())
+}
+
+async fn sync(name: Option<String>, user: UserConfig) -> CliResult {
+    let names = match name {
+        Some(name) => vec![name],
+        None => source::SOURCES.iter().map(|s| s.to_string()).collect(),
+    };
+    tokio::spawn(prompt_auth(user.name.clone()));
+    tokio::spawn(print_progress());
+    for name in names {
+        let (mut source, _requests) = open_source(&name, user.clone()).await?;
+        source.authenticate().await.await?;
+        for playlist in source.sync().await {
+            println!("{}\t{}\t{}", source.get_name(), playlist.id, playlist.title);
+        }
+    }
+    Ok(())
+}
+
+async fn download(name: &str, playlist: &str, user: UserConfig) -> CliResult {
+    tokio::spawn(prompt_auth(user.name.clone()));
+    let (mut source, _requests) = open_source(name, user).await?;
+    tokio::spawn(print_progress());
+    source.authenticate().await.await?;
+    let id = source
+        .get_all_playlists()
+        .await
+        .into_iter()
+        .find(|p| p.id == playlist || p.title == playlist)
+        .map(|p| p.id)
+        .ok_or_else(|| format!("No playlist {} in {}", playlist, source.get_name()))?;
+    let mut playlist = source
+        .get_playlist_by_id(&id)
+        .await
+        .map_err(|err| err.to_string())?;
+    let songs = playlist.get_songs().await;
+    source
+        .download_songs(&songs, playlist.to_playlist().title)
+        .await
+        .await?;
+    Ok(())
+}
+
+fn export(user: &str) -> CliResult {
+    let mut exported = vec![];
+    for (source, playlist, etag) in db::list_playlists(user)? {
+        let songs = db::get_playlist_songs(&playlist.id, &source, user)?;
+        exported.push(ExportedPlaylist {
+            source,
+            etag,
+            playlist,
+            songs,
+        });
+    }
+    println!("{}", serde_json::to_string_pretty(&exported)?);
+    Ok(())
+}
+
+async fn import(user: &str) -> CliResult {
+    let mut json = String::new();
+    tokio::io::stdin().read_to_string(&mut json).await?;
+    let imported: Vec<ExportedPlaylist> = serde_json::from_str(&json)?;
+    for playlist in imported {
+        println!("{}\t{}", playlist.source, playlist.playlist.title);
+        db::add_playlist(
+            &playlist.source,
+            user,
+            playlist.playlist,
+            &playlist.songs,
+            &playlist.etag,
+        )?;
+    }
+    Ok(())
+}
+
+fn check(fix: bool) -> CliResult {
+    let problems = db::integrity_check()?;
+    for problem in problems.iter() {
+        println!("database: {}", problem);
+    }
+    let mut missing = 0;
+    for (source, song) in db::get_all_songs()? {
+        if song.downloaded && !Path::new(&song.url).exists() {
+            missing += 1;
+            println!("missing file: {} ({}) {}", song.title, source, song.url);
+            if fix {
+                db::reset_downloaded(&song.id, &source)?;
+            }
+        }
+    }
+    println!(
+        "{} database problem(s), {} missing file(s)",
+        problems.len(),
+        missing
+    );
+    if problems.is_empty() && (missing == 0 || fix) {
+        Ok(())
+    } else {
+        Err("the library is inconsistent".into())
+    }
+}
+
+fn rematch(source: Option<String>, redownload: bool) -> CliResult {
+    let forgotten = db::forget_automatic_matches(source.as_deref())?;
+    if redownload {
+        for (source, id) in forgotten.iter() {
+            // the song may not be stored, when it was only streamed
+            let _ = db::reset_downloaded(id, source);
+        }
+    }
+    println!("{} match(es) forgotten", forgotten.len());
+    Ok(())
+}
+
+fn list_playlists(user: &str) -> CliResult {
+    for (source, playlist, _) in db::list_playlists(user)? {
+        println!(
+            "{}\t{}\t{}\t{}",
+            source, playlist.id, playlist.title, playlist.size
+        );
+    }
+    Ok(())
+}
@@ -1,13 +1,103 @@
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use music_server::request::Permission;
 use serde::{Deserialize, Serialize};
 
+use crate::title_parser::TitleParserConfig;
+
+/// User owning the top level credentials, and the libraries created before there were users
+pub const DEFAULT_USER: &str = "default";
+
+// toml cannot write a value after a table: the tables, and the lists of structs
+// that become tables once filled, must stay at the end
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub data_location: String,
     pub secrets_location: String,
-    pub port: u32,
+    /// Port of the `listen` entries that are bare ip addresses
+    pub port: u16,
+    /// Addresses to listen on: ip addresses, `host:port` or `unix:/path/to/socket`
+    pub listen: Vec<String>,
+    /// Port of the `http_listen` entries that are bare ip addresses
+    pub http_port: u16,
+    /// Addresses serving the http api, in the same format as `listen`, none when empty
+    pub http_listen: Vec<String>,
+    /// Address of the http api as seen by the clients, like `https://music.example.com`,
+    /// used in the stream urls. The first `http_listen` address is used when empty.
+    pub http_public_url: String,
+    /// Plays songs on the machine of the server, through mpv, when the clients
+    /// or the mpd frontend ask for it
+    pub playback: bool,
+    /// Port of the `mpd_listen` entries that are bare ip addresses
+    pub mpd_port: u16,
+    /// Addresses speaking the Music Player Daemon protocol, none when empty.
+    /// The clients give their token, or `name:password`, with the `password` command.
+    pub mpd_listen: Vec<String>,
     pub yt_dlp_output_template: String,
     pub spotify_id: String,
     pub spotify_secret: String,
+    /// Number of youtube results considered when matching a spotify song
+    pub resolver_candidates: u32,
+    /// Also list the uploads of the youtube channels the user is subscribed to
+    pub youtube_subscriptions: bool,
+    /// Seconds between two background syncs of the playlists, 0 to disable them
+    pub sync_interval: u64,
+    /// Ids of the playlists of the default user whose new songs are downloaded
+    /// by the background sync
+    pub pinned_playlists: Vec<String>,
+    /// Where google redirects after authentication,
+    /// the resulting url is then sent back to the server
+    pub youtube_redirect_uri: String,
+    /// Users other than the default one, which uses the top level credentials
+    pub users: Vec<UserConfig>,
+    /// Clients allowed to connect, every client has every permission when empty
+    pub clients: Vec<ClientAccess>,
+    /// How artists and titles are extracted from youtube video titles
+    pub title_parser: TitleParserConfig,
+    /// Serves the tcp addresses over tls when set
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClientAccess {
+    pub name: String,
+    /// Token the client logs in with, empty to only allow the password
+    #[serde(default)]
+    pub token: String,
+    /// Password the client logs in with along with `name`, empty to only allow the token
+    #[serde(default)]
+    pub password: String,
+    pub permission: Permission,
+    /// User whose library the client accesses, the default user when empty
+    #[serde(default)]
+    pub user: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct UserConfig {
+    pub name: String,
+    /// Where the user's tokens are stored, a folder named after the user
+    /// in the top level `secrets_location` when empty
+    #[serde(default)]
+    pub secrets_location: String,
+    /// Spotify application of the user, the top level one is used when empty
+    #[serde(default)]
+    pub spotify_id: String,
+    #[serde(default)]
+    pub spotify_secret: String,
+    /// Ids of the playlists of the user whose new songs are downloaded by the background sync
+    #[serde(default)]
+    pub pinned_playlists: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TlsConfig {
+    /// PEM file containing the certificate chain
+    pub certificate: String,
+    /// PEM file containing the private key
+    pub key: String,
 }
 
 impl std::default::Default for Config {
@@ -16,13 +106,103 @@ impl std::default::Default for Config {
             data_location: "data".to_string(),
             secrets_location: "data/secrets".to_string(),
             port: 8080,
+            listen: vec!["127.0.0.1".to_string()],
+            http_port: 8081,
+            http_listen: vec![],
+            http_public_url: "".to_string(),
+            playback: false,
+            mpd_port: 6600,
+            mpd_listen: vec![],
             yt_dlp_output_template: "%(title)s.%(ext)s".to_string(),
             spotify_id: "".to_string(),
             spotify_secret: "".to_string(),
+            resolver_candidates: 5,
+            youtube_subscriptions: false,
+            sync_interval: 3600,
+            pinned_playlists: vec![],
+            youtube_redirect_uri: "http://localhost".to_string(),
+            users: vec![],
+            clients: vec![],
+            title_parser: Default::default(),
+            tls: None,
         }
     }
 }
 
+impl Config {
+    /// Settings of the user called `name`, with the top level values filled in
+    pub fn user(&self, name: &str) -> Option<UserConfig> {
+        let or = |value: &str, default: &str| {
+            if value.is_empty() {
+                default.to_string()
+            } else {
+                value.to_string()
+            }
+        };
+        if name == DEFAULT_USER {
+            return Some(UserConfig {
+                name: name.to_string(),
+                secrets_location: self.secrets_location.clone(),
+                spotify_id: self.spotify_id.clone(),
+                spotify_secret: self.spotify_secret.clone(),
+                pinned_playlists: self.pinned_playlists.clone(),
+            });
+        }
+        self.users
+            .iter()
+            .find(|u| u.name == name)
+            .map(|user| UserConfig {
+                name: user.name.clone(),
+                secrets_location: or(
+                    &user.secrets_location,
+                    &format!("{}/{}", self.secrets_location, user.name),
+                ),
+                spotify_id: or(&user.spotify_id, &self.spotify_id),
+                spotify_secret: or(&user.spotify_secret, &self.spotify_secret),
+                pinned_playlists: user.pinned_playlists.clone(),
+            })
+    }
+}
+
+/// Configuration file given on the command line, the default location is used otherwise
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
+
 pub fn get_config() -> Config {
-    confy::load("music_server", None).unwrap()
+    match CONFIG_PATH.get() {
+        Some(path) => confy::load_path(path).unwrap(),
+        None => confy::load("music_server", None).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_of_each_user() {
+        let config = Config {
+            pinned_playlists: vec!["top".to_string()],
+            users: vec![
+                UserConfig {
+                    name: "bob".to_string(),
+                    pinned_playlists: vec!["bob's".to_string()],
+                    ..Default::default()
+                },
+                UserConfig {
+                    name: "carol".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let pins = |name: &str| config.user(name).map(|user| user.pinned_playlists);
+        assert_eq!(pins(DEFAULT_USER), Some(vec!["top".to_string()]));
+        assert_eq!(pins("bob"), Some(vec!["bob's".to_string()]));
+        assert_eq!(pins("carol"), Some(vec![]));
+        assert_eq!(pins("dave"), None);
+    }
 }
@@ -1,6 +1,7 @@
 #![warn(clippy::unwrap_used)]
 
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use rusqlite::{Connection, Statement};
 use serde::{Deserialize, Serialize};
@@ -33,6 +34,41 @@ fn to_json<T: Serialize>(obj: &T) -> String {
     serde_json::to_string(obj).expect("Could not serialize object")
 }
 
+const CREATE_PLAYLIST_TABLE: &str = "CREATE TABLE IF NOT EXISTS TblPlaylist (
+    uid INTEGER PRIMARY KEY,
+    id TEXT NOT NULL,
+    title TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    etag TEXT NOT NULL,
+    source TEXT NOT NULL,
+    user TEXT NOT NULL,
+    unique (id, source, user))";
+
+/// Gives the playlists stored before there were users to the default user.
+/// The unique constraint changes, so the table is rebuilt, keeping the uids.
+fn migrate_playlist_users(conn: &Connection) -> Result<()> {
+    let query = "SELECT COUNT(*) FROM pragma_table_info('TblPlaylist') WHERE name = 'user'";
+    let has_user: i32 = conn.query_row(query, (), |row| row.get(0))?;
+    if has_user > 0 {
+        return Ok(());
+    }
+    println!(
+        "Migrating the playlists to the user {}",
+        config::DEFAULT_USER
+    );
+    conn.execute_batch(&format!(
+        "BEGIN;
+        ALTER TABLE TblPlaylist RENAME TO TblPlaylistOld;
+        {};
+        INSERT INTO TblPlaylist (uid, id, title, size, etag, source, user)
+            SELECT uid, id, title, size, etag, source, '{}' FROM TblPlaylistOld;
+        DROP TABLE TblPlaylistOld;
+        COMMIT;",
+        CREATE_PLAYLIST_TABLE,
+        config::DEFAULT_USER
+    ))
+}
+
 pub fn init() -> Result<()> {
     let conn = Connection::open(get_db_path())?;
     conn.execute(
@@ -44,17 +80,15 @@ pub fn init() -> Result<()> {
             unique (id, source))",
         (),
     )?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS TblPlaylist (
-            uid INTEGER PRIMARY KEY,
-            id TEXT NOT NULL,
-            title TEXT NOT NULL,
-            size INTEGER NOT NULL,
-            etag TEXT NOT NULL,
-            source TEXT NOT NULL,
-            unique (id, source))",
+    let playlist_table_exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'TblPlaylist'",
         (),
+        |row| row.get(0),
     )?;
+    if playlist_table_exists {
+        migrate_playlist_users(&conn)?;
+    }
+    conn.execute(CREATE_PLAYLIST_TABLE, ())?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS TblPlaylistSongs (
             uidPlaylist INTEGER NOT NULL,
@@ -62,49 +96,93 @@ pub fn init() -> Result<()> {
             unique (uidPlaylist, uidSong))",
         (),
     )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS TblSongMatch (
+            id TEXT NOT NULL,
+            source TEXT NOT NULL,
+            videoId TEXT NOT NULL,
+            manual INTEGER NOT NULL,
+            unique (id, source))",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS TblHistory (
+            uid INTEGER PRIMARY KEY,
+            user TEXT NOT NULL,
+            source TEXT NOT NULL,
+            songId TEXT NOT NULL,
+            playedAt INTEGER NOT NULL)",
+        (),
+    )?;
 
     Ok(())
 }
 
-pub fn playlist_needs_update(id: &str, source: &str, etag: &str) -> bool {
+pub fn playlist_needs_update(id: &str, source: &str, user: &str, etag: &str) -> bool {
     // returns true if the db is inaccessible
     let conn = match Connection::open(get_db_path()) {
         Ok(val) => val,
         Err(_) => return true
     };
-    let query = "SELECT * FROM TblPlaylist WHERE source = ?1 AND id = ?2 AND etag = ?3";
+    let query =
+        "SELECT * FROM TblPlaylist WHERE source = ?1 AND id = ?2 AND etag = ?3 AND user = ?4";
     let mut stmt = prepare(&conn, query);
-    stmt.exists(rusqlite::params![source, id, etag]).unwrap_or(true)
+    stmt.exists(rusqlite::params![source, id, etag, user])
+        .unwrap_or(true)
+}
+
+/// Keeps the download of a song, which is shared between the users,
+/// when it is stored again from a freshly fetched playlist
+fn keep_download(conn: &Connection, source: &str, song: &Song) -> Result<Song> {
+    let query = "SELECT song FROM TblSong WHERE source = ?1 AND id = ?2";
+    let mut stmt = prepare(conn, query);
+    let mut rows = stmt.query_map((source, &song.id), |row| row.get::<_, String>(0))?;
+    let mut song = song.clone();
+    if let Some(json) = rows.next().transpose()? {
+        let stored: Song = from_json(&json);
+        if stored.downloaded && !song.downloaded {
+            song.downloaded = true;
+            song.url = stored.url;
+        }
+    }
+    Ok(song)
 }
 
 pub fn add_playlist(
     source: &str,
+    user: &str,
     playlist: Playlist,
     songs: &[Song],
     etag: &str,
 ) -> Result<()> {
     let conn = Connection::open(get_db_path())?;
     conn.execute(
-        "REPLACE INTO TblPlaylist (uid, id, title, size, etag, source) VALUES ((SELECT uid FROM TblPlaylist WHERE id = ?1 AND source = ?5), ?1, ?2, ?3, ?4, ?5)",
+        "REPLACE INTO TblPlaylist (uid, id, title, size, etag, source, user) VALUES ((SELECT uid FROM TblPlaylist WHERE id = ?1 AND source = ?5 AND user = ?6), ?1, ?2, ?3, ?4, ?5, ?6)",
         (
             &playlist.id,
             playlist.title,
             playlist.size,
             etag,
             source,
+            user,
         ),
     )?;
-    let query = "SELECT uid FROM TblPlaylist WHERE  source = ?1 AND id = ?2";
+    let query = "SELECT uid FROM TblPlaylist WHERE  source = ?1 AND id = ?2 AND user = ?3";
     let mut stmt = prepare(&conn, query);
-    let uid_playlist: i32 =
-        stmt.query_row((source, playlist.id), |row| row.get(0))?;
+    let uid_playlist: i32 = stmt.query_row((source, playlist.id, user), |row| row.get(0))?;
+    // the playlist is fully rewritten so that removed songs do not linger
+    conn.execute(
+        "DELETE FROM TblPlaylistSongs WHERE uidPlaylist = ?1",
+        (uid_playlist,),
+    )?;
     for s in songs.iter() {
+        let song = keep_download(&conn, source, s)?;
         conn.execute(
             "REPLACE INTO TblSong (uid, id, source, song) VALUES ((SELECT uid FROM TblSong WHERE source = ?2 AND id = ?1), ?1, ?2, ?3)",
             (
                 &s.id,
                 source,
-                to_json(&s),
+                to_json(&song),
             ),
         )?;
         let query = "SELECT uid FROM TblSong WHERE  source = ?1 AND id = ?2";
@@ -133,6 +211,27 @@ pub fn update_songs(songs: &[Song], source: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn get_song(id: &str, source: &str) -> Result<Option<Song>> {
+    let conn = Connection::open(get_db_path())?;
+    let query = "SELECT song FROM TblSong WHERE source = ?1 AND id = ?2";
+    let mut stmt = prepare(&conn, query);
+    let mut rows = stmt.query_map((source, id), |row| row.get::<_, String>(0))?;
+    Ok(rows.next().transpose()?.map(|json| from_json(&json)))
+}
+
+/// The song `id` of `source`, when it is in a playlist or in the history of `user`
+pub fn get_user_song(id: &str, source: &str, user: &str) -> Result<Option<Song>> {
+    let conn = Connection::open(get_db_path())?;
+    let query = "SELECT song FROM TblSong WHERE source = ?1 AND id = ?2 AND (
+        uid IN (SELECT uidSong FROM TblPlaylistSongs JOIN TblPlaylist
+            ON TblPlaylist.uid = TblPlaylistSongs.uidPlaylist WHERE TblPlaylist.user = ?3)
+        OR EXISTS (SELECT 1 FROM TblHistory
+            WHERE user = ?3 AND source = ?1 AND songId = ?2))";
+    let mut stmt = prepare(&conn, query);
+    let mut rows = stmt.query_map((source, id, user), |row| row.get::<_, String>(0))?;
+    Ok(rows.next().transpose()?.map(|json| from_json(&json)))
+}
+
 pub fn remove_downloaded(songs: &[Song], source: &str) -> Result<Vec<Song>> {
     let conn = Connection::open(get_db_path())?;
     let mut res = vec![];
@@ -151,11 +250,11 @@ pub fn remove_downloaded(songs: &[Song], source: &str) -> Result<Vec<Song>> {
     Ok(res)
 }
 
-pub fn get_playlist_songs(id: &str, source: &str) -> Result<Vec<Song>> {
+pub fn get_playlist_songs(id: &str, source: &str, user: &str) -> Result<Vec<Song>> {
     let conn = Connection::open(get_db_path())?;
-    let query = "SELECT uid FROM TblPlaylist WHERE source = ?1 AND id = ?2";
+    let query = "SELECT uid FROM TblPlaylist WHERE source = ?1 AND id = ?2 AND user = ?3";
     let mut stmt = prepare(&conn, query);
-    let uid_playlist = stmt.query_row((source, id), |row| row.get::<_, i32>(0))?;
+    let uid_playlist = stmt.query_row((source, id, user), |row| row.get::<_, i32>(0))?;
     let query = "SELECT uidSong FROM TblPlaylistSongs WHERE uidPlaylist = ?1";
     let mut stmt = prepare(&conn, query);
     let res = stmt.query_map(rusqlite::params![uid_playlist], |row| row.get(0))?;
@@ -173,11 +272,12 @@ pub fn get_playlist_songs(id: &str, source: &str) -> Result<Vec<Song>> {
     Ok(songs)
 }
 
-pub fn load_playlist(id: &str, source: &str) -> Result<Playlist> {
+pub fn load_playlist(id: &str, source: &str, user: &str) -> Result<Playlist> {
     let conn = Connection::open(get_db_path())?;
-    let stmt = "SELECT uid, title, size, etag FROM TblPlaylist WHERE source = ?1 AND id = ?2";
+    let stmt =
+        "SELECT uid, title, size, etag FROM TblPlaylist WHERE source = ?1 AND id = ?2 AND user = ?3";
     let mut stmt = prepare(&conn, stmt);
-    stmt.query_row((source, id), |row| {
+    stmt.query_row((source, id, user), |row| {
         Ok(Playlist {
             title: row.get(1)?,
             tags: Default::default(),
@@ -186,3 +286,125 @@ pub fn load_playlist(id: &str, source: &str) -> Result<Playlist> {
         })
     })
 }
+
+pub fn get_song_match(id: &str, source: &str) -> Result<Option<String>> {
+    let conn = Connection::open(get_db_path())?;
+    let query = "SELECT videoId FROM TblSongMatch WHERE source = ?1 AND id = ?2";
+    let mut stmt = prepare(&conn, query);
+    let mut rows = stmt.query_map((source, id), |row| row.get::<_, String>(0))?;
+    rows.next().transpose()
+}
+
+pub fn set_song_match(id: &str, source: &str, video_id: &str, manual: bool) -> Result<()> {
+    let conn = Connection::open(get_db_path())?;
+    conn.execute(
+        "REPLACE INTO TblSongMatch (id, source, videoId, manual) VALUES (?1, ?2, ?3, ?4)",
+        (id, source, video_id, manual),
+    )?;
+    Ok(())
+}
+
+/// Forgets the matches picked by the resolver, of every source if `source` is `None`,
+/// so that the songs are searched again. The matches set by hand are kept.
+/// Returns the source and id of the songs whose match was forgotten.
+pub fn forget_automatic_matches(source: Option<&str>) -> Result<Vec<(String, String)>> {
+    let conn = Connection::open(get_db_path())?;
+    let query = "SELECT source, id FROM TblSongMatch WHERE manual = 0 AND ?1 IN (source, '')";
+    let mut stmt = prepare(&conn, query);
+    let source = source.unwrap_or_default();
+    let forgotten = stmt
+        .query_map([source], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(String, String)>>>()?;
+    conn.execute(
+        "DELETE FROM TblSongMatch WHERE manual = 0 AND ?1 IN (source, '')",
+        [source],
+    )?;
+    Ok(forgotten)
+}
+
+/// Marks a song as not downloaded so that the next download fetches it again
+pub fn reset_downloaded(id: &str, source: &str) -> Result<()> {
+    let conn = Connection::open(get_db_path())?;
+    let query = "SELECT song FROM TblSong WHERE source = ?1 AND id = ?2";
+    let mut stmt = prepare(&conn, query);
+    let json = stmt.query_row((source, id), |row| row.get::<_, String>(0))?;
+    let mut song: Song = from_json(&json);
+    song.downloaded = false;
+    update_songs(&[song], source)
+}
+
+/// Every playlist stored for `user` with its source and etag
+pub fn list_playlists(user: &str) -> Result<Vec<(String, Playlist, String)>> {
+    let conn = Connection::open(get_db_path())?;
+    let query = "SELECT source, id, title, size, etag FROM TblPlaylist WHERE user = ?1
+        ORDER BY source, title";
+    let mut stmt = prepare(&conn, query);
+    let rows = stmt.query_map((user,), |row| {
+        let playlist = Playlist {
+            title: row.get(2)?,
+            tags: Default::default(),
+            id: row.get(1)?,
+            size: row.get(3)?,
+        };
+        Ok((row.get(0)?, playlist, row.get(4)?))
+    })?;
+    rows.collect()
+}
+
+/// Every stored song with its source
+pub fn get_all_songs() -> Result<Vec<(String, Song)>> {
+    let conn = Connection::open(get_db_path())?;
+    let query = "SELECT source, song FROM TblSong";
+    let mut stmt = prepare(&conn, query);
+    let rows = stmt.query_map((), |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    let mut songs = vec![];
+    for row in rows {
+        let (source, json) = row?;
+        songs.push((source, from_json(&json)));
+    }
+    Ok(songs)
+}
+
+/// Problems reported by sqlite, empty if the database is sound
+pub fn integrity_check() -> Result<Vec<String>> {
+    let conn = Connection::open(get_db_path())?;
+    let mut stmt = prepare(&conn, "PRAGMA integrity_check");
+    let rows = stmt.query_map((), |row| row.get::<_, String>(0))?;
+    let messages: Vec<String> = rows.collect::<Result<_>>()?;
+    Ok(messages.into_iter().filter(|m| m != "ok").collect())
+}
+
+pub fn add_history(user: &str, source: &str, song_id: &str) -> Result<()> {
+    let conn = Connection::open(get_db_path())?;
+    let played_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    conn.execute(
+        "INSERT INTO TblHistory (user, source, songId, playedAt) VALUES (?1, ?2, ?3, ?4)",
+        (user, source, song_id, played_at),
+    )?;
+    Ok(())
+}
+
+/// The last `limit` songs of `source` played by `user` with the time they were played at,
+/// the most recent first
+pub fn get_history(user: &str, source: &str, limit: u32) -> Result<Vec<(Song, u64)>> {
+    let conn = Connection::open(get_db_path())?;
+    let query = "SELECT s.song, h.playedAt FROM TblHistory h
+        JOIN TblSong s ON s.source = h.source AND s.id = h.songId
+        WHERE h.user = ?1 AND h.source = ?2
+        ORDER BY h.playedAt DESC, h.uid DESC LIMIT ?3";
+    let mut stmt = prepare(&conn, query);
+    let rows = stmt.query_map((user, source, limit), |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+    })?;
+    let mut history = vec![];
+    for row in rows {
+        let (json, played_at) = row?;
+        history.push((from_json(&json), played_at));
+    }
+    Ok(history)
+}
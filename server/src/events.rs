@@ -0,0 +1,49 @@
+use std::sync::OnceLock;
+
+use music_server::request::{Answer, AnswerType, EventType};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+
+/// Events shared by every connection, as opposed to the answers of a connection's own sources.
+/// Each event is sent along with the user it concerns, empty when it concerns every user.
+static EVENTS: OnceLock<broadcast::Sender<(String, Answer)>> = OnceLock::new();
+
+fn sender() -> &'static broadcast::Sender<(String, Answer)> {
+    EVENTS.get_or_init(|| broadcast::channel(64).0)
+}
+
+/// Sends `event` to every connection of `user` subscribed to its topic
+pub fn publish(user: &str, source: &str, event: EventType) {
+    let answer = Answer::new(source.to_string(), AnswerType::Event(event));
+    // an error only means that nobody is listening
+    let _ = sender().send((user.to_string(), answer));
+}
+
+/// Sends `event` to every connection subscribed to its topic, whatever its user
+pub fn publish_all(source: &str, event: EventType) {
+    publish("", source, event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<(String, Answer)> {
+    sender().subscribe()
+}
+
+/// Forwards the events of `user` to a connection until it is closed
+pub async fn forward(out: mpsc::Sender<Answer>, user: String) {
+    let mut events = subscribe();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok((event_user, answer)) => {
+                    let concerned = event_user == user || event_user.is_empty();
+                    if concerned && out.send(answer).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            _ = out.closed() => break,
+        }
+    }
+}
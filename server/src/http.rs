@@ -0,0 +1,640 @@
+//! Http api giving access to the sources with json endpoints instead of the length prefixed
+//! stream, along with a server-sent events stream for the events
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{FromRequestParts, Path, Query};
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{async_trait, Json, Router};
+use futures::{SinkExt, Stream, StreamExt};
+use music_server::request::{
+    Answer, AnswerType, AuthRequest, Credentials, ErrorType, EventType, ObjRequest, Permission,
+    Request, RequestType, Topic,
+};
+use music_server::source_types::{Playlist, Song};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{timeout_at, Instant};
+
+use crate::access::{self, Identity};
+use crate::{db, events, source, stream, subsonic};
+
+/// How long a source has to answer
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for more sources once one answered
+const SOURCES_IDLE: Duration = Duration::from_secs(1);
+
+/// Serves the http api on an accepted connection
+pub async fn serve<S>(stream: S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    hyper::server::conn::Http::new()
+        .serve_connection(stream, router())
+        .with_upgrades()
+        .await
+        .map_err(io::Error::other)
+}
+
+fn router() -> Router {
+    Router::new()
+        .route("/sources", get(sources))
+        .route("/sources/:source/playlists", get(playlists))
+        .route("/sources/:source/playlists/:id", get(songs))
+        .route("/sources/:source/playlists/:id/download", post(download))
+        .route("/sources/:source/songs/:id/match", put(set_match))
+        .route("/sources/:source/history", get(history).post(played))
+        .route("/sources/:source/auth", get(auth_status).post(auth_begin))
+        .route("/sources/:source/auth/complete", post(auth_complete))
+        .route("/search", get(search))
+        .route("/events", get(events))
+        .route("/ws", get(websocket))
+        .route("/stream/:source/:id", get(stream_song))
+        .nest("/rest", subsonic::router())
+}
+
+/// Answered as `{"error": message}`
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<ErrorType> for ApiError {
+    fn from(err: ErrorType) -> Self {
+        match err {
+            ErrorType::PermissionDenied(err) => ApiError(StatusCode::FORBIDDEN, err),
+            ErrorType::SourceError(err) => ApiError(StatusCode::NOT_FOUND, err.to_string()),
+            ErrorType::PlayerError(err) => ApiError(StatusCode::CONFLICT, err),
+        }
+    }
+}
+
+type ApiResult<T> = Result<T, ApiError>;
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+    /// Name of the clients logging in with a password, along with `password`
+    user: Option<String>,
+    password: Option<String>,
+}
+
+/// The client making the request, identified by the `Authorization: Bearer` header
+/// or, for the browsers' event sources and the players that cannot set it,
+/// the `token` parameter or the `user` and `password` ones
+struct Client(Identity);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Client {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> ApiResult<Self> {
+        let header = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let credentials = match header {
+            Some(token) => Some(Credentials::Token(token)),
+            None => match Query::<TokenQuery>::from_request_parts(parts, state).await {
+                Ok(Query(TokenQuery {
+                    token: Some(token), ..
+                })) => Some(Credentials::Token(token)),
+                Ok(Query(TokenQuery {
+                    user: Some(user),
+                    password: Some(password),
+                    ..
+                })) => Some(Credentials::Password { user, password }),
+                _ => None,
+            },
+        };
+        let identity = match credentials {
+            Some(credentials) => access::login(&credentials).ok(),
+            None => access::anonymous(),
+        };
+        identity
+            .map(Client)
+            .ok_or_else(|| ApiError(StatusCode::UNAUTHORIZED, "Login required".to_string()))
+    }
+}
+
+/// Sources of a user, shared by the http clients of that user
+#[derive(Clone)]
+struct Bridge {
+    requests: broadcast::Sender<Request>,
+    answers: broadcast::Sender<Answer>,
+    /// The last status of each source, for the event streams opened after it was sent
+    statuses: Arc<std::sync::Mutex<HashMap<String, Answer>>>,
+}
+
+/// The bridge of each user, behind its own lock so that starting the sources of a user
+/// does not hold the requests of the others
+static BRIDGES: OnceLock<std::sync::Mutex<HashMap<String, BridgeSlot>>> = OnceLock::new();
+
+/// The bridge of a user, empty until its sources started
+type BridgeSlot = Arc<Mutex<Option<Bridge>>>;
+
+/// The bridge to the sources of the user of `identity`, starting them the first time
+async fn bridge(identity: &Identity) -> ApiResult<Bridge> {
+    let slot = {
+        let mut bridges = BRIDGES
+            .get_or_init(Default::default)
+            .lock()
+            .expect("poisoned lock");
+        bridges
+            .entry(identity.user.name.clone())
+            .or_default()
+            .clone()
+    };
+    // kept locked while the sources start so that they are only started once
+    let mut slot = slot.lock().await;
+    if let Some(bridge) = slot.as_ref() {
+        return Ok(bridge.clone());
+    }
+    let (requests, _) = broadcast::channel(16);
+    let (answers, _) = broadcast::channel(100);
+    let (mpsc_tx, mut mpsc_rx) = mpsc::channel::<Answer>(100);
+    let bridge = Bridge {
+        requests: requests.clone(),
+        answers: answers.clone(),
+        statuses: Default::default(),
+    };
+    // not kept when no source started, the next request tries again
+    crate::client_spawning(identity.user.clone(), requests, mpsc_tx)
+        .await
+        .map_err(|err| {
+            ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("Cannot start the sources: {}", err),
+            )
+        })?;
+    let statuses = bridge.statuses.clone();
+    tokio::spawn(async move {
+        while let Some(mut answer) = mpsc_rx.recv().await {
+            stream::add_stream_urls(&mut answer);
+            if let AnswerType::Event(EventType::SourceStatus(_)) = &answer.data {
+                let mut statuses = statuses.lock().expect("poisoned lock");
+                statuses.insert(answer.client.clone(), answer.clone());
+            }
+            // an error only means that no http request is waiting
+            let _ = answers.send(answer);
+        }
+    });
+    *slot = Some(bridge.clone());
+    Ok(bridge)
+}
+
+impl Bridge {
+    fn send(&self, identity: &Identity, source: &str, ty: RequestType) -> ApiResult<()> {
+        let mut request = Request::new(source.to_string(), ty);
+        request.permission = identity.permission;
+        // checked here as the sources answer every client of the user at once
+        if !request.allowed() {
+            return Err(ApiError::from(match access::denied(&request) {
+                AnswerType::Error(err) => err,
+                _ => unreachable!("denied always answers an error"),
+            }));
+        }
+        self.requests.send(request).map_err(|_| {
+            ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The sources are not running".to_string(),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Sends `ty` to `source` and waits for its first answer accepted by `accept`
+    async fn ask<T>(
+        &self,
+        identity: &Identity,
+        source: &str,
+        ty: RequestType,
+        mut accept: impl FnMut(AnswerType) -> Option<T>,
+    ) -> ApiResult<T> {
+        let mut answers = self.answers.subscribe();
+        self.send(identity, source, ty)?;
+        let deadline = Instant::now() + ANSWER_TIMEOUT;
+        loop {
+            let answer = match timeout_at(deadline, answers.recv()).await {
+                Ok(Ok(answer)) => answer,
+                Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) | Err(_) => {
+                    return Err(ApiError(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        format!("No answer from {}", source),
+                    ))
+                }
+            };
+            if answer.client != source {
+                continue;
+            }
+            match answer.data {
+                AnswerType::Error(err) => return Err(err.into()),
+                data => {
+                    if let Some(value) = accept(data) {
+                        return Ok(value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Names of the running sources
+    async fn sources(&self, identity: &Identity) -> ApiResult<Vec<String>> {
+        let mut answers = self.answers.subscribe();
+        self.send(identity, "all", RequestType::GetAll(ObjRequest::ClientList))?;
+        let mut sources = vec![];
+        let mut deadline = Instant::now() + ANSWER_TIMEOUT;
+        loop {
+            match timeout_at(deadline, answers.recv()).await {
+                Ok(Ok(Answer {
+                    data: AnswerType::Client(name),
+                    ..
+                })) => {
+                    if !sources.contains(&name) {
+                        sources.push(name);
+                    }
+                    deadline = Instant::now() + SOURCES_IDLE;
+                }
+                Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) | Err(_) => break,
+            }
+        }
+        Ok(sources)
+    }
+}
+
+/// The name of `source` as known by the sources, the case is ignored
+fn source_name(source: &str) -> ApiResult<&'static str> {
+    source::SOURCES
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(source))
+        .copied()
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("Unknown source {}", source)))
+}
+
+async fn sources(Client(identity): Client) -> ApiResult<Json<Vec<String>>> {
+    let sources = bridge(&identity).await?.sources(&identity).await?;
+    Ok(Json(sources))
+}
+
+async fn playlists(
+    Client(identity): Client,
+    Path(source): Path<String>,
+) -> ApiResult<Json<Vec<Playlist>>> {
+    let source = source_name(&source)?;
+    let request = RequestType::GetAll(ObjRequest::PlaylistList);
+    let playlists = bridge(&identity)
+        .await?
+        .ask(&identity, source, request, |data| match data {
+            AnswerType::PlaylistList(playlists) => Some(playlists),
+            _ => None,
+        })
+        .await?;
+    Ok(Json(playlists))
+}
+
+async fn songs(
+    Client(identity): Client,
+    Path((source, id)): Path<(String, String)>,
+) -> ApiResult<Json<Vec<Song>>> {
+    let source = source_name(&source)?;
+    let request = RequestType::GetAll(ObjRequest::Playlist(id.clone()));
+    let songs = bridge(&identity)
+        .await?
+        .ask(&identity, source, request, |data| match data {
+            AnswerType::Songs(playlist, songs) if playlist.id == id => Some(songs),
+            _ => None,
+        })
+        .await?;
+    Ok(Json(songs))
+}
+
+/// Starts the download, its progress is sent on the event stream
+async fn download(
+    Client(identity): Client,
+    Path((source, id)): Path<(String, String)>,
+) -> ApiResult<StatusCode> {
+    let source = source_name(&source)?;
+    let request = RequestType::Download(ObjRequest::Playlist(id));
+    bridge(&identity).await?.send(&identity, source, request)?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Deserialize)]
+struct SongMatch {
+    video_id: String,
+}
+
+/// Sets the youtube video downloaded for a song
+async fn set_match(
+    Client(identity): Client,
+    Path((source, id)): Path<(String, String)>,
+    Json(song_match): Json<SongMatch>,
+) -> ApiResult<Json<serde_json::Value>> {
+    let source = source_name(&source)?;
+    let request = RequestType::Set(ObjRequest::SongMatch(id, song_match.video_id));
+    let message = bridge(&identity)
+        .await?
+        .ask(&identity, source, request, |data| match data {
+            AnswerType::Message(message) => Some(message),
+            _ => None,
+        })
+        .await?;
+    Ok(Json(json!({ "message": message })))
+}
+
+#[derive(Serialize)]
+struct HistoryEntry {
+    song: Song,
+    /// Seconds since the unix epoch
+    played_at: u64,
+}
+
+async fn history(
+    Client(identity): Client,
+    Path(source): Path<String>,
+) -> ApiResult<Json<Vec<HistoryEntry>>> {
+    let source = source_name(&source)?;
+    let request = RequestType::GetAll(ObjRequest::History);
+    let history = bridge(&identity)
+        .await?
+        .ask(&identity, source, request, |data| match data {
+            AnswerType::History(history) => Some(history),
+            _ => None,
+        })
+        .await?;
+    let history = history
+        .into_iter()
+        .map(|(song, played_at)| HistoryEntry { song, played_at })
+        .collect();
+    Ok(Json(history))
+}
+
+#[derive(Deserialize)]
+struct Played {
+    id: String,
+}
+
+/// Adds a song to the history
+async fn played(
+    Client(identity): Client,
+    Path(source): Path<String>,
+    Json(played): Json<Played>,
+) -> ApiResult<StatusCode> {
+    let source = source_name(&source)?;
+    let request = RequestType::Played(played.id);
+    bridge(&identity).await?.send(&identity, source, request)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Converts the answer of an authentication request
+fn auth_answer(answer: AnswerType) -> ApiResult<Json<serde_json::Value>> {
+    match answer {
+        AnswerType::AuthStatus(status) => Ok(Json(json!({ "status": status }))),
+        AnswerType::AuthUrl(url) => Ok(Json(json!({ "url": url }))),
+        AnswerType::Message(message) => Ok(Json(json!({ "message": message }))),
+        AnswerType::Error(err) => Err(err.into()),
+        answer => Err(ApiError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unexpected answer {:?}", answer),
+        )),
+    }
+}
+
+/// Authentication requests that do not need the source, see `handle_auth`
+fn local_auth(identity: &Identity, source: &str, auth: AuthRequest) -> Option<AnswerType> {
+    let mut request = Request::new(source.to_string(), RequestType::Auth(auth));
+    request.permission = identity.permission;
+    crate::handle_auth(&request, &identity.user.name)
+}
+
+async fn auth_status(
+    Client(identity): Client,
+    Path(source): Path<String>,
+) -> ApiResult<Json<serde_json::Value>> {
+    let source = source_name(&source)?;
+    let answer = local_auth(&identity, source, AuthRequest::Status);
+    auth_answer(answer.expect("the status is always known"))
+}
+
+/// Starts an authentication flow, answers the url to visit or the status
+/// when the source needs no url
+async fn auth_begin(
+    Client(identity): Client,
+    Path(source): Path<String>,
+) -> ApiResult<Json<serde_json::Value>> {
+    let source = source_name(&source)?;
+    if let Some(answer) = local_auth(&identity, source, AuthRequest::Begin) {
+        return auth_answer(answer);
+    }
+    let request = RequestType::Auth(AuthRequest::Begin);
+    let answer = bridge(&identity)
+        .await?
+        .ask(&identity, source, request, |data| match data {
+            AnswerType::AuthUrl(_) | AnswerType::AuthStatus(_) => Some(data),
+            _ => None,
+        })
+        .await?;
+    auth_answer(answer)
+}
+
+#[derive(Deserialize)]
+struct AuthCode {
+    /// The code, or the url the user was redirected to
+    code: String,
+}
+
+async fn auth_complete(
+    Client(identity): Client,
+    Path(source): Path<String>,
+    Json(auth): Json<AuthCode>,
+) -> ApiResult<Json<serde_json::Value>> {
+    let source = source_name(&source)?;
+    let answer = local_auth(&identity, source, AuthRequest::Complete(auth.code));
+    auth_answer(answer.expect("completing never needs the source"))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    /// Only searches this source
+    source: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    source: String,
+    playlist: Playlist,
+    song: Song,
+}
+
+/// Searches the songs of the loaded playlists
+async fn search(
+    Client(identity): Client,
+    Query(query): Query<SearchQuery>,
+) -> ApiResult<Json<Vec<SearchResult>>> {
+    let bridge = bridge(&identity).await?;
+    let sources = match query.source {
+        Some(source) => vec![source_name(&source)?.to_string()],
+        None => bridge.sources(&identity).await?,
+    };
+    let mut results = vec![];
+    for source in sources {
+        let request = RequestType::Search(query.q.clone());
+        let found = bridge
+            .ask(&identity, &source, request, |data| match data {
+                AnswerType::SearchResults(found) => Some(found),
+                _ => None,
+            })
+            .await?;
+        results.extend(found.into_iter().map(|(playlist, song)| SearchResult {
+            source: source.clone(),
+            playlist,
+            song,
+        }));
+    }
+    Ok(Json(results))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Comma separated topics, all of them when missing
+    topics: Option<String>,
+}
+
+/// Streams the events of the user, each named after its topic with the answer as data
+async fn events(
+    Client(identity): Client,
+    Query(query): Query<EventsQuery>,
+) -> ApiResult<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    let topics: Vec<Topic> = match query.topics {
+        None => vec![
+            Topic::SourceStatus,
+            Topic::Playlist,
+            Topic::Download,
+            Topic::Auth,
+        ],
+        Some(topics) => topics
+            .split(',')
+            .map(|topic| {
+                serde_json::from_value(json!(topic.trim())).map_err(|_| {
+                    ApiError(StatusCode::BAD_REQUEST, format!("Unknown topic {}", topic))
+                })
+            })
+            .collect::<ApiResult<_>>()?,
+    };
+    let (tx, rx) = mpsc::channel::<Answer>(32);
+    tokio::spawn(events::forward(tx.clone(), identity.user.name.clone()));
+    // the status of the sources is sent by the sources themselves
+    let bridge = bridge(&identity).await?;
+    let mut answers = bridge.answers.subscribe();
+    let statuses: Vec<Answer> = {
+        let statuses = bridge.statuses.lock().expect("poisoned lock");
+        statuses.values().cloned().collect()
+    };
+    tokio::spawn(async move {
+        for answer in statuses {
+            if tx.send(answer).await.is_err() {
+                return;
+            }
+        }
+        loop {
+            tokio::select! {
+                answer = answers.recv() => match answer {
+                    Ok(answer) => {
+                        if matches!(answer.data, AnswerType::Event(EventType::SourceStatus(_)))
+                            && tx.send(answer).await.is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tx.closed() => break,
+            }
+        }
+    });
+    let stream = futures::stream::unfold(rx, |mut rx| async {
+        rx.recv().await.map(|answer| (answer, rx))
+    })
+    .filter_map(move |mut answer| {
+        stream::add_stream_urls(&mut answer);
+        let event = match &answer.data {
+            AnswerType::Event(event) if topics.contains(&event.topic()) => Event::default()
+                .event(format!("{:?}", event.topic()))
+                .json_data(&answer)
+                .ok(),
+            _ => None,
+        };
+        futures::future::ready(event.map(Ok))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Speaks the stream protocol over a websocket, one request or answer per message.
+/// The connection logs in with a `Login` request, as a stream connection does.
+async fn websocket(upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(|socket: WebSocket| async move {
+        let (sink, stream) = socket.split();
+        let incoming = stream
+            .take_while(|message| {
+                futures::future::ready(
+                    matches!(message, Ok(message) if !matches!(message, Message::Close(_))),
+                )
+            })
+            .filter_map(|message| {
+                futures::future::ready(match message {
+                    Ok(Message::Text(text)) => Some(text),
+                    Ok(Message::Binary(data)) => String::from_utf8(data).ok(),
+                    _ => None,
+                })
+            });
+        let outgoing = sink
+            .with(|json: String| futures::future::ready(Ok::<_, axum::Error>(Message::Text(json))))
+            .sink_map_err(io::Error::other);
+        if let Err(err) = crate::session_handler(incoming, outgoing).await {
+            println!("Error on websocket {}", err);
+        }
+    })
+}
+
+/// Streams a song with support for range requests when it is stored on the server,
+/// fetching it with yt-dlp otherwise
+async fn stream_song(
+    Client(identity): Client,
+    Path((source, id)): Path<(String, String)>,
+    request: axum::http::Request<Body>,
+) -> ApiResult<Response> {
+    let source = source_name(&source)?;
+    if identity.permission < Permission::Play {
+        let err = format!("{:?} is not allowed to play songs", identity.permission);
+        return Err(ApiError(StatusCode::FORBIDDEN, err));
+    }
+    // only the songs of the user's library are streamed
+    let song = db::get_user_song(&id, source, &identity.user.name)
+        .map_err(|err| ApiError(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("Unknown song {}", id)))?;
+    stream::respond(source, &song, request)
+        .await
+        .map_err(|err| ApiError(StatusCode::BAD_GATEWAY, err.to_string()))
+}
@@ -1,20 +1,51 @@
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
+mod access;
+mod auth;
+mod cli;
 mod config;
 mod db;
+mod events;
+mod http;
+mod mpd;
+mod player;
+mod resolver;
 mod source;
+mod stream;
+mod subsonic;
+mod sync;
+mod title_parser;
 mod utils;
 
+use crate::access::Identity;
+use crate::cli::{Cli, Command};
+use crate::config::UserConfig;
 use crate::source::{spotify, youtube, Source};
-use music_server::request::{self, handle_request, Answer, Request};
-use tokio::io::AsyncReadExt;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::runtime::Builder;
+use clap::Parser;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use music_server::request::{
+    self, handle_request, Answer, AnswerType, AuthRequest, ErrorType, EventType, PlayerRequest,
+    Request, RequestType, Topic,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::runtime::{Builder, Handle};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
 use tokio::sync::broadcast;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Notify};
 
-fn start_server() {
+fn start_server(
+    listen: Vec<ListenAddr>,
+    http_listen: Vec<ListenAddr>,
+    mpd_listen: Vec<ListenAddr>,
+) {
     println!("Starting server");
     let acceptor_runtime = Builder::new_multi_thread()
         .worker_threads(1)
@@ -41,46 +72,240 @@ fn start_server() {
         .build()
         .unwrap();
 
+    let config = config::get_config();
+    let tls = match config.tls {
+        Some(tls) => match access::tls_acceptor(&tls) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                println!("Cannot load the tls certificate: {}", err);
+                return;
+            }
+        },
+        None => None,
+    };
+    let listen: Vec<_> = listen
+        .into_iter()
+        .map(|addr| (addr, Protocol::Stream))
+        .chain(http_listen.into_iter().map(|addr| (addr, Protocol::Http)))
+        .chain(mpd_listen.into_iter().map(|addr| (addr, Protocol::Mpd)))
+        .collect();
+    if config.clients.is_empty() && listen.iter().any(|(addr, _)| addr.is_public()) {
+        println!("Warning: no clients are configured, anyone on the network can use the server");
+    }
+
     acceptor_runtime.block_on(async {
-        let config = config::get_config();
-        let port = config.port;
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
-            .await
-            .unwrap();
+        sync::start();
+        let mut accepting = vec![];
+        for (addr, protocol) in listen {
+            // the mpd clients do not speak tls
+            let tls = match protocol {
+                Protocol::Mpd => None,
+                _ => tls.clone(),
+            };
+            match addr
+                .accept_loop(request_runtime.handle().clone(), tls, protocol)
+                .await
+            {
+                Ok(task) => {
+                    println!("Listening on {} ({:?})", addr, protocol);
+                    accepting.push(task);
+                }
+                Err(err) => println!("Cannot listen on {}: {}", addr, err),
+            }
+        }
+        if accepting.is_empty() {
+            println!("No address to listen on");
+        }
+        futures::future::join_all(accepting).await;
+    })
+}
+
+/// What is spoken on a listening address
+#[derive(Clone, Copy, Debug)]
+enum Protocol {
+    /// Length prefixed json requests and answers
+    Stream,
+    /// The http api
+    Http,
+    /// The Music Player Daemon protocol
+    Mpd,
+}
+
+impl Protocol {
+    async fn serve<S>(self, stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        match self {
+            Protocol::Stream => stream_handler(stream).await,
+            Protocol::Http => http::serve(stream).await,
+            Protocol::Mpd => mpd::serve(stream).await,
+        }
+    }
+}
+
+/// Address the server listens on
+enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Bare ip addresses and host names use `port`
+    fn parse(addr: &str, port: u16) -> Self {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return ListenAddr::Unix(PathBuf::from(path));
+        }
+        match addr.parse::<IpAddr>() {
+            Ok(ip) => ListenAddr::Tcp(SocketAddr::new(ip, port).to_string()),
+            Err(_) if !addr.contains(':') => ListenAddr::Tcp(format!("{}:{}", addr, port)),
+            Err(_) => ListenAddr::Tcp(addr.to_string()),
+        }
+    }
+
+    /// Whether the address may be reachable from other machines
+    fn is_public(&self) -> bool {
+        match self {
+            ListenAddr::Tcp(addr) => addr
+                .parse::<SocketAddr>()
+                .map(|addr| !addr.ip().is_loopback())
+                .unwrap_or(true),
+            ListenAddr::Unix(_) => false,
+        }
+    }
 
+    /// Binds the address and spawns the task accepting its connections.
+    /// Tcp connections go through `tls` when it is given.
+    async fn accept_loop(
+        &self,
+        runtime: Handle,
+        tls: Option<TlsAcceptor>,
+        protocol: Protocol,
+    ) -> io::Result<JoinHandle<()>> {
+        match self {
+            ListenAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                Ok(tokio::spawn(async move {
+                    loop {
+                        match (listener.accept().await, tls.clone()) {
+                            (Ok((socket, _)), Some(tls)) => {
+                                runtime.spawn(async move {
+                                    match tls.accept(socket).await {
+                                        Ok(stream) => protocol.serve(stream).await,
+                                        Err(err) => {
+                                            println!("Tls handshake failed {}", err);
+                                            Ok(())
+                                        }
+                                    }
+                                });
+                            }
+                            (Ok((socket, _)), None) => {
+                                runtime.spawn(protocol.serve(socket));
+                            }
+                            (Err(err), _) => println!("Error while accepting {}", err),
+                        }
+                    }
+                }))
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                // a socket left by a previous run would prevent binding
+                if let Ok(metadata) = std::fs::metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                let listener = UnixListener::bind(path)?;
+                Ok(tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((socket, _)) => {
+                                runtime.spawn(protocol.serve(socket));
+                            }
+                            Err(err) => println!("Error while accepting {}", err),
+                        }
+                    }
+                }))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// State of a connection, shared by its reading and writing tasks
+struct Session {
+    /// Topics the connection subscribed to
+    subscriptions: HashSet<Topic>,
+    /// `None` until the connection logged in
+    identity: Option<Identity>,
+}
+
+type SharedSession = Arc<Mutex<Session>>;
+
+/// Messages of a length prefixed stream, until it is closed
+fn read_frames(stream_rx: impl AsyncRead + Send + Unpin) -> impl Stream<Item = String> + Send {
+    futures::stream::unfold(stream_rx, |mut stream_rx| async move {
         loop {
-            let (socket, _) = listener.accept().await.unwrap();
-            let _g = request_runtime.enter();
-            request_runtime.spawn(stream_handler(socket));
+            let mut size = [0; 8];
+            stream_rx.read_exact(&mut size).await.ok()?;
+            let size = usize::from_be_bytes(size);
+            if size == 0 {
+                // socket was closed
+                return None;
+            }
+            let mut buf = vec![0; size];
+            stream_rx.read_exact(&mut buf).await.ok()?;
+            match String::from_utf8(buf) {
+                Ok(message) => return Some((message, stream_rx)),
+                Err(err) => println!("Error while reading {}", err), // TODO inform client
+            }
         }
     })
 }
 
+/// Writes each message with its size in front
+fn write_frames(
+    stream_tx: impl AsyncWrite + Send + Unpin,
+) -> impl Sink<String, Error = io::Error> + Send {
+    futures::sink::unfold(stream_tx, |mut stream_tx, json: String| async move {
+        stream_tx.write_all(&request::prepare_message(json)).await?;
+        Ok(stream_tx)
+    })
+}
+
 async fn stream_read(
-    mut stream_rx: OwnedReadHalf,
+    incoming: impl Stream<Item = String>,
     broad_tx: broadcast::Sender<Request>,
+    mpsc_tx: mpsc::Sender<Answer>,
+    session: SharedSession,
+    subscribed: Arc<Notify>,
 ) -> Result<(), std::io::Error> {
-    loop {
-        stream_rx.readable().await?;
-        // Creating the buffer **after** the `await` prevents it from
-        // being stored in the async task.
-        let mut size = [0; 8];
-        stream_rx.read_exact(&mut size).await;
-        let size = usize::from_be_bytes(size);
-        if size == 0 {
-            // socket was closed
-            break Ok(());
-        }
-        let mut buf = vec![0; size];
-        stream_rx.read_exact(&mut buf).await;
-        let message = match std::str::from_utf8(&buf) {
-            Ok(mes) => mes,
-            Err(err) => {
-                println!("Error while reading {}", err);
-                continue;
-            } // TODO inform client
-        };
-        let request: Request = match handle_request(message.to_owned()).await {
+    futures::pin_mut!(incoming);
+    // the player requests are run in order, next to the reading of the other requests
+    let (player_tx, mut player_rx) = mpsc::unbounded_channel::<(String, PlayerRequest)>();
+    let player_answers = mpsc_tx.clone();
+    tokio::spawn(async move {
+        while let Some((client, request)) = player_rx.recv().await {
+            let answer = player::handle(&client, request).await;
+            let _ = player_answers.send(Answer::new(client, answer)).await;
+        }
+    });
+    while let Some(message) = incoming.next().await {
+        let mut request: Request = match handle_request(message.clone()).await {
             Ok(req) => req,
             Err(e) => {
                 println!("Error while handling request : {} {}", e, message);
@@ -89,59 +314,271 @@ async fn stream_read(
             }
         };
         println!("{:?}", request);
+        if let RequestType::Login(credentials) = &request.ty {
+            let current = session.lock().expect("poisoned lock").identity.clone();
+            let answer = match (access::login(credentials), current) {
+                // a server without clients logs every connection in as anonymous on its own
+                (Ok(identity), Some(current))
+                    if identity.name == current.name && identity.user.name == current.user.name =>
+                {
+                    AnswerType::LoggedIn(current.permission)
+                }
+                // the sources of a connection belong to a single user
+                (Ok(_), Some(_)) => {
+                    AnswerType::Error(ErrorType::PermissionDenied("Already logged in".to_string()))
+                }
+                (Ok(identity), None) => {
+                    println!(
+                        "{} logged in as {} with the {:?} permission",
+                        identity.name, identity.user.name, identity.permission
+                    );
+                    let permission = identity.permission;
+                    let user = identity.user.clone();
+                    session.lock().expect("poisoned lock").identity = Some(identity);
+                    start_sources(user, broad_tx.clone(), mpsc_tx.clone()).await;
+                    AnswerType::LoggedIn(permission)
+                }
+                (Err(err), _) => AnswerType::Error(ErrorType::PermissionDenied(err)),
+            };
+            let _ = mpsc_tx.send(Answer::new(request.client, answer)).await;
+            continue;
+        }
+        let identity = session.lock().expect("poisoned lock").identity.clone();
+        let user = match identity {
+            Some(identity) => {
+                request.permission = identity.permission;
+                identity.user.name
+            }
+            None => {
+                let answer =
+                    AnswerType::Error(ErrorType::PermissionDenied("Login required".to_string()));
+                let _ = mpsc_tx.send(Answer::new(request.client, answer)).await;
+                continue;
+            }
+        };
+        if let RequestType::Subscribe(topics) = request.ty {
+            session.lock().expect("poisoned lock").subscriptions = topics.into_iter().collect();
+            subscribed.notify_one();
+            continue;
+        }
+        if let Some(answer) = handle_auth(&request, &user) {
+            let _ = mpsc_tx.send(Answer::new(request.client, answer)).await;
+            continue;
+        }
+        // the player belongs to the server rather than to a source
+        if let RequestType::Player(player_request) = &request.ty {
+            if request.allowed() {
+                let _ = player_tx.send((request.client, player_request.clone()));
+            } else {
+                let answer = access::denied(&request);
+                let _ = mpsc_tx.send(Answer::new(request.client, answer)).await;
+            }
+            continue;
+        }
         broad_tx.send(request);
     }
+    Ok(())
+}
+
+/// Answers the authentication requests that do not need the source,
+/// so that they are not blocked by a source waiting for the user
+fn handle_auth(request: &Request, user: &str) -> Option<AnswerType> {
+    let source = &request.client;
+    if matches!(request.ty, RequestType::Auth(_)) && !request.allowed() {
+        return Some(access::denied(request));
+    }
+    match &request.ty {
+        RequestType::Auth(AuthRequest::Status) => {
+            Some(AnswerType::AuthStatus(auth::status(user, source)))
+        }
+        RequestType::Auth(AuthRequest::Begin) => {
+            auth::pending_url(user, source).map(AnswerType::AuthUrl)
+        }
+        RequestType::Auth(AuthRequest::Complete(input)) => {
+            let message = match auth::complete(user, source, input.clone()) {
+                Ok(_) => format!("Authenticating {}", source),
+                Err(err) => err,
+            };
+            Some(AnswerType::Message(message))
+        }
+        _ => None,
+    }
 }
 
 async fn stream_write(
-    stream_tx: OwnedWriteHalf,
+    outgoing: impl Sink<String, Error = io::Error>,
     mut mpsc_rx: mpsc::Receiver<Answer>,
+    session: SharedSession,
+    subscribed: Arc<Notify>,
 ) -> Result<(), std::io::Error> {
+    futures::pin_mut!(outgoing);
+    // the last status of each source, given to the connection when it subscribes to them
+    let mut statuses: HashMap<String, Answer> = HashMap::new();
+    let mut statuses_sent = false;
     loop {
-        match mpsc_rx.recv().await {
-            None => break Ok(()),
-            Some(message) => {
-                let json = serde_json::to_string(&message).unwrap();
-                let message = request::prepare_message(json);
-                stream_tx.writable().await?;
-                stream_tx.try_write(&message)?;
+        let received = tokio::select! {
+            message = mpsc_rx.recv() => match message {
+                None => break Ok(()),
+                message => message,
+            },
+            _ = subscribed.notified() => None,
+        };
+        let mut messages = vec![];
+        {
+            let session = session.lock().expect("poisoned lock");
+            if let Some(message) = received {
+                if let AnswerType::Event(EventType::SourceStatus(_)) = &message.data {
+                    statuses.insert(message.client.clone(), message.clone());
+                }
+                // nothing but the login failures reaches a connection that did not log in
+                let denied = matches!(
+                    message.data,
+                    AnswerType::Error(ErrorType::PermissionDenied(_))
+                );
+                let unsubscribed = match &message.data {
+                    AnswerType::Event(event) => !session.subscriptions.contains(&event.topic()),
+                    _ => false,
+                };
+                if (session.identity.is_some() || denied) && !unsubscribed {
+                    messages.push(message);
+                }
             }
+            let statuses_subscribed =
+                session.identity.is_some() && session.subscriptions.contains(&Topic::SourceStatus);
+            if statuses_subscribed && !statuses_sent {
+                messages.retain(|message| {
+                    !matches!(message.data, AnswerType::Event(EventType::SourceStatus(_)))
+                });
+                messages.extend(statuses.values().cloned());
+            }
+            statuses_sent = statuses_subscribed;
+        }
+        for mut message in messages {
+            stream::add_stream_urls(&mut message);
+            let json = serde_json::to_string(&message).unwrap();
+            outgoing.send(json).await?;
         }
     }
 }
-async fn client_spawning(broad_tx: broadcast::Sender<Request>, mpsc_tx: mpsc::Sender<Answer>) {
+
+/// Starts the sources of `user` and forwards them its events
+async fn start_sources(
+    user: UserConfig,
+    broad_tx: broadcast::Sender<Request>,
+    mpsc_tx: mpsc::Sender<Answer>,
+) {
+    tokio::spawn(events::forward(mpsc_tx.clone(), user.name.clone()));
+    if let Ok(Err(err)) = tokio::spawn(client_spawning(user, broad_tx, mpsc_tx)).await {
+        println!("Cannot start the sources: {}", err);
+    }
+}
+
+/// Starts the sources of `user`, fails when they cannot reach their api
+async fn client_spawning(
+    user: UserConfig,
+    broad_tx: broadcast::Sender<Request>,
+    mpsc_tx: mpsc::Sender<Answer>,
+) -> io::Result<()> {
     // We assume that the API are always up
-    if online::tokio::check(None).await.is_ok() {
-        let mut youtube_client =
-            youtube::Client::new("Youtube", broad_tx.subscribe(), mpsc_tx.clone())
-                .await
-                .unwrap();
-        let mut spotify_client =
-            spotify::Client::new("Spotify", broad_tx.subscribe(), mpsc_tx.clone()).await;
-        tokio::spawn(async move {
-            spotify_client.authenticate().await;
-            spotify_client.fetch_all_playlists().await;
-            spotify_client.listen().await;
-        });
-        tokio::spawn(async move {
-            youtube_client.init().await;
-            youtube_client.listen().await;
-        });
+    if online::tokio::check(None).await.is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "the server is offline",
+        ));
     }
+    let mut youtube_client = youtube::Client::new(
+        "Youtube",
+        user.clone(),
+        broad_tx.subscribe(),
+        mpsc_tx.clone(),
+    )
+    .await?;
+    let mut spotify_client =
+        spotify::Client::new("Spotify", user, broad_tx.subscribe(), mpsc_tx.clone()).await;
+    tokio::spawn(async move { spotify_client.run().await });
+    tokio::spawn(async move { youtube_client.run().await });
+    Ok(())
+}
+
+async fn stream_handler<S>(stream: S) -> Result<(), std::io::Error>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (rx, tx) = tokio::io::split(stream);
+    session_handler(read_frames(rx), write_frames(tx)).await
 }
 
-async fn stream_handler(stream: TcpStream) -> Result<(), std::io::Error> {
-    let (rx, tx) = stream.into_split();
+/// Serves the requests of a connection, whatever carries its messages
+async fn session_handler<I, O>(incoming: I, outgoing: O) -> Result<(), std::io::Error>
+where
+    I: Stream<Item = String> + Send + 'static,
+    O: Sink<String, Error = io::Error> + Send + 'static,
+{
     let (broad_tx, _) = broadcast::channel::<Request>(16);
     let (mpsc_tx, mpsc_rx) = mpsc::channel::<Answer>(100);
-    tokio::spawn(client_spawning(broad_tx.clone(), mpsc_tx)).await;
-    tokio::spawn(stream_write(tx, mpsc_rx));
-    tokio::spawn(stream_read(rx, broad_tx));
+    let identity = access::anonymous();
+    // the sources of a connection that has to log in start with the login
+    if let Some(identity) = &identity {
+        start_sources(identity.user.clone(), broad_tx.clone(), mpsc_tx.clone()).await;
+    }
+    let session = Arc::new(Mutex::new(Session {
+        subscriptions: Default::default(),
+        identity,
+    }));
+    // wakes the writing task up when the subscriptions change
+    let subscribed = Arc::new(Notify::new());
+    tokio::spawn(stream_write(
+        outgoing,
+        mpsc_rx,
+        session.clone(),
+        subscribed.clone(),
+    ));
+    tokio::spawn(stream_read(
+        incoming, broad_tx, mpsc_tx, session, subscribed,
+    ));
     Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    if let Some(path) = cli.config {
+        config::set_config_path(path);
+    }
     db::init().expect("Failed to initialize db");
-    start_server();
-    Ok(())
+    match cli.command.unwrap_or(Command::Serve(Default::default())) {
+        Command::Serve(args) => {
+            let config = config::get_config();
+            let port = args.port.unwrap_or(config.port);
+            let listen = if args.bind.is_empty() {
+                config.listen
+            } else {
+                args.bind
+            };
+            let http_listen = if args.http.is_empty() {
+                config.http_listen
+            } else {
+                args.http
+            };
+            let http_listen: Vec<_> = http_listen
+                .iter()
+                .map(|a| ListenAddr::parse(a, config.http_port))
+                .collect();
+            let mpd_listen = if args.mpd.is_empty() {
+                config.mpd_listen
+            } else {
+                args.mpd
+            };
+            stream::set_base_url(&http_listen);
+            start_server(
+                listen.iter().map(|a| ListenAddr::parse(a, port)).collect(),
+                http_listen,
+                mpd_listen
+                    .iter()
+                    .map(|a| ListenAddr::parse(a, config.mpd_port))
+                    .collect(),
+            );
+            Ok(())
+        }
+        command => cli::run(command, &cli.user),
+    }
 }
@@ -0,0 +1,1278 @@
+//! The Music Player Daemon protocol, so that mpd clients can browse the library
+//! and control the server's player.
+//!
+//! The downloaded files make up the music directory, the stored playlists are the playlists
+//! of the sources, and the songs that are not downloaded have a `source://id` uri.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use music_server::request::{AnswerType, Credentials, EventType, Permission, PlayerState};
+use music_server::source_types::{Playlist, Song};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::access::{self, Identity};
+use crate::player::{self, Change};
+use crate::{config, db, events, source};
+
+const GREETING: &str = "OK MPD 0.23.5\n";
+
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "consume",
+    "count",
+    "currentsong",
+    "decoders",
+    "delete",
+    "deleteid",
+    "find",
+    "findadd",
+    "getvol",
+    "idle",
+    "list",
+    "listall",
+    "listallinfo",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "load",
+    "lsinfo",
+    "move",
+    "moveid",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "password",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlist",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "plchangesposid",
+    "previous",
+    "random",
+    "repeat",
+    "replay_gain_status",
+    "rescan",
+    "search",
+    "searchadd",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "single",
+    "stats",
+    "status",
+    "stop",
+    "tagtypes",
+    "update",
+    "urlhandlers",
+    "volume",
+];
+
+/// Error codes of the `ACK` lines
+#[derive(Clone, Copy)]
+enum AckCode {
+    Arg = 2,
+    Password = 3,
+    Permission = 4,
+    Unknown = 5,
+    NoExist = 50,
+    System = 52,
+}
+
+struct Ack(AckCode, String);
+
+impl Ack {
+    fn new(code: AckCode, message: impl Into<String>) -> Self {
+        Ack(code, message.into())
+    }
+}
+
+type MpdResult = Result<(), Ack>;
+
+/// Serves the mpd protocol on an accepted connection
+pub async fn serve<S>(stream: S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(GREETING.as_bytes()).await?;
+    let mut connection = Connection {
+        identity: access::anonymous(),
+    };
+    // the commands of the list being received, and whether each of them is acknowledged
+    let mut list: Option<(Vec<String>, bool)> = None;
+    while let Some(line) = lines.next_line().await? {
+        if let Some((commands, _)) = list.as_mut() {
+            if line != "command_list_end" {
+                commands.push(line);
+                continue;
+            }
+            let (commands, list_ok) = list.take().expect("a list is being received");
+            let out = connection.run_list(&commands, list_ok).await;
+            writer.write_all(out.as_bytes()).await?;
+            continue;
+        }
+        match line.as_str() {
+            "command_list_begin" => list = Some((vec![], false)),
+            "command_list_ok_begin" => list = Some((vec![], true)),
+            "close" => break,
+            _ if line == "idle" || line.starts_with("idle ") => {
+                let subsystems = match parse_args(&line) {
+                    Ok(args) => args.into_iter().skip(1).collect(),
+                    Err(_) => vec![],
+                };
+                if let Err(Ack(code, message)) = connection.check_permission("idle") {
+                    let ack = format!("ACK [{}@0] {{idle}} {}\n", code as u32, message);
+                    writer.write_all(ack.as_bytes()).await?;
+                    continue;
+                }
+                let user = connection.user().to_string();
+                match idle(&subsystems, &user, &mut lines).await? {
+                    Some(out) => writer.write_all(out.as_bytes()).await?,
+                    // anything but noidle while idle closes the connection
+                    None => break,
+                }
+            }
+            // not idle, there is nothing to interrupt
+            "noidle" => (),
+            _ => {
+                let out = connection.run_list(&[line], false).await;
+                writer.write_all(out.as_bytes()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits for a change of the player or the library, answers `None` if the client
+/// sent something else than `noidle` meanwhile
+async fn idle<R>(
+    subsystems: &[String],
+    user: &str,
+    lines: &mut tokio::io::Lines<BufReader<R>>,
+) -> io::Result<Option<String>>
+where
+    R: AsyncRead + Unpin,
+{
+    let wanted =
+        |subsystem: &str| subsystems.is_empty() || subsystems.iter().any(|s| s == subsystem);
+    let mut changes = player::subscribe();
+    let mut events = events::subscribe();
+    loop {
+        let subsystem = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) if line == "noidle" => return Ok(Some("OK\n".to_string())),
+                _ => return Ok(None),
+            },
+            change = changes.recv() => match change {
+                Ok(Change::Queue) => "playlist",
+                Ok(Change::Playback) => "player",
+                Ok(Change::Volume) => "mixer",
+                Ok(Change::Options) => "options",
+                Err(RecvError::Lagged(_)) => "player",
+                Err(RecvError::Closed) => continue,
+            },
+            event = events.recv() => match event {
+                Ok((event_user, answer)) if event_user == user => match answer.data {
+                    AnswerType::Event(EventType::PlaylistUpdated(..)) => "stored_playlist",
+                    AnswerType::Event(EventType::SongDownloaded(_)) => "database",
+                    _ => continue,
+                },
+                _ => continue,
+            },
+        };
+        if wanted(subsystem) {
+            return Ok(Some(format!("changed: {}\nOK\n", subsystem)));
+        }
+    }
+}
+
+struct Connection {
+    /// `None` until the client sent its password
+    identity: Option<Identity>,
+}
+
+impl Connection {
+    /// Runs `commands` and answers their output, stopping at the first error
+    async fn run_list(&mut self, commands: &[String], list_ok: bool) -> String {
+        let mut out = String::new();
+        for (index, line) in commands.iter().enumerate() {
+            let (command, res) = match parse_args(line) {
+                Ok(args) => {
+                    let command = args.first().cloned().unwrap_or_default();
+                    let res = self.run(&args, &mut out).await;
+                    (command, res)
+                }
+                Err(ack) => (String::new(), Err(ack)),
+            };
+            if let Err(Ack(code, message)) = res {
+                out.push_str(&format!(
+                    "ACK [{}@{}] {{{}}} {}\n",
+                    code as u32, index, command, message
+                ));
+                return out;
+            }
+            if list_ok {
+                out.push_str("list_OK\n");
+            }
+        }
+        out.push_str("OK\n");
+        out
+    }
+
+    fn check_permission(&self, command: &str) -> MpdResult {
+        let Some(needed) = permission(command) else {
+            return Ok(());
+        };
+        if needed == Permission::Play && !player::enabled() {
+            return Err(Ack::new(
+                AckCode::System,
+                "playback is disabled on the server",
+            ));
+        }
+        match &self.identity {
+            Some(identity) if identity.permission >= needed => Ok(()),
+            _ => Err(Ack::new(
+                AckCode::Permission,
+                format!("you don't have permission for \"{}\"", command),
+            )),
+        }
+    }
+
+    /// Name of the user whose library is browsed, only called once logged in
+    fn user(&self) -> &str {
+        self.identity
+            .as_ref()
+            .map(|identity| identity.user.name.as_str())
+            .unwrap_or_default()
+    }
+
+    async fn run(&mut self, args: &[String], out: &mut String) -> MpdResult {
+        let (command, args) = args
+            .split_first()
+            .ok_or_else(|| Ack::new(AckCode::Unknown, "No command given"))?;
+        self.check_permission(command)?;
+        let player = player::player();
+        match command.as_str() {
+            "ping" => (),
+            "password" => {
+                let password = arg(args, 0)?;
+                // tokens may contain a `:` too, so the whole argument is tried first
+                let identity = access::login(&token(password))
+                    .or_else(|err| match password.split_once(':') {
+                        Some((user, password)) => access::login(&Credentials::Password {
+                            user: user.to_string(),
+                            password: password.to_string(),
+                        }),
+                        None => Err(err),
+                    })
+                    .map_err(|_| Ack::new(AckCode::Password, "incorrect password"))?;
+                self.identity = Some(identity);
+            }
+            "commands" => {
+                for command in COMMANDS {
+                    pair(out, "command", command);
+                }
+            }
+            "notcommands" | "decoders" => (),
+            "tagtypes" => {
+                // the tags cannot be chosen, only listed
+                if args.is_empty() {
+                    for tag in ["Artist", "Title"] {
+                        pair(out, "tagtype", tag);
+                    }
+                }
+            }
+            "urlhandlers" => {
+                for handler in ["http://", "https://"] {
+                    pair(out, "handler", handler);
+                }
+                for source in source::SOURCES {
+                    pair(out, "handler", &format!("{}://", source.to_lowercase()));
+                }
+            }
+            "outputs" => {
+                pair(out, "outputid", "0");
+                pair(out, "outputname", "mpv");
+                pair(out, "plugin", "mpv");
+                pair(out, "outputenabled", "1");
+            }
+            "replay_gain_status" => pair(out, "replay_gain_mode", "off"),
+            // the sources keep the library up to date
+            "update" | "rescan" => pair(out, "updating_db", "1"),
+            "status" => {
+                let player = player.lock().await;
+                pair(out, "volume", &player.volume().to_string());
+                pair(out, "repeat", flag(player.repeat));
+                pair(out, "random", "0");
+                pair(out, "single", flag(player.single));
+                pair(out, "consume", flag(player.consume));
+                pair(out, "playlist", &player.version().to_string());
+                pair(out, "playlistlength", &player.queue().len().to_string());
+                let state = match player.state() {
+                    PlayerState::Stop => "stop",
+                    PlayerState::Play => "play",
+                    PlayerState::Pause => "pause",
+                };
+                pair(out, "state", state);
+                if let Some(current) = player.current() {
+                    pair(out, "song", &current.to_string());
+                    pair(out, "songid", &player.queue()[current].id.to_string());
+                    if player.state() != PlayerState::Stop {
+                        let elapsed = player.elapsed().as_secs_f64();
+                        let duration = player.queue()[current].song.duration.as_secs_f64();
+                        pair(out, "time", &format!("{:.0}:{:.0}", elapsed, duration));
+                        pair(out, "elapsed", &format!("{:.3}", elapsed));
+                        pair(out, "duration", &format!("{:.3}", duration));
+                    }
+                    if let Some(next) = player.queue().get(current + 1) {
+                        pair(out, "nextsong", &(current + 1).to_string());
+                        pair(out, "nextsongid", &next.id.to_string());
+                    }
+                }
+            }
+            "currentsong" => {
+                let player = player.lock().await;
+                if let Some(current) = player.current() {
+                    let entry = &player.queue()[current];
+                    write_song(out, &entry.source, &entry.song);
+                    pair(out, "Pos", &current.to_string());
+                    pair(out, "Id", &entry.id.to_string());
+                }
+            }
+            "stats" => {
+                let songs = library_songs(self.user())?;
+                let artists: HashSet<&String> =
+                    songs.iter().flat_map(|(_, song)| &song.artists).collect();
+                let playtime: Duration = songs.iter().map(|(_, song)| song.duration).sum();
+                pair(out, "artists", &artists.len().to_string());
+                pair(out, "albums", "0");
+                pair(out, "songs", &songs.len().to_string());
+                pair(out, "db_playtime", &playtime.as_secs().to_string());
+            }
+
+            // the queue
+            "playlistinfo" | "playlistid" | "plchanges" | "plchangesposid" | "playlist" => {
+                let player = player.lock().await;
+                let queue = player.queue();
+                let (start, end) = match (command.as_str(), args.first()) {
+                    ("playlistinfo", Some(range)) => parse_range(range, queue.len())?,
+                    ("playlistid", Some(id)) => {
+                        let pos = player
+                            .position(number(id)?)
+                            .ok_or_else(|| Ack::new(AckCode::NoExist, "No such song"))?;
+                        (pos, pos + 1)
+                    }
+                    // the changes are not tracked, the whole queue is sent when there are some
+                    ("plchanges" | "plchangesposid", Some(version))
+                        if number::<u32>(version)? == player.version() =>
+                    {
+                        (0, 0)
+                    }
+                    _ => (0, queue.len()),
+                };
+                for (pos, entry) in queue.iter().enumerate().take(end).skip(start) {
+                    match command.as_str() {
+                        "plchangesposid" => pair(out, "cpos", &pos.to_string()),
+                        "playlist" => {
+                            let uri = song_uri(&entry.source, &entry.song);
+                            out.push_str(&format!("{}:file: {}\n", pos, uri));
+                            continue;
+                        }
+                        _ => {
+                            write_song(out, &entry.source, &entry.song);
+                            pair(out, "Pos", &pos.to_string());
+                        }
+                    }
+                    pair(out, "Id", &entry.id.to_string());
+                }
+            }
+            "add" => {
+                let songs = resolve_uri(arg(args, 0)?)?;
+                let mut player = player.lock().await;
+                for (source, song) in songs {
+                    player.add(&source, song, None);
+                }
+            }
+            "addid" => {
+                let mut songs = resolve_uri(arg(args, 0)?)?;
+                if songs.len() != 1 {
+                    return Err(Ack::new(AckCode::Arg, "addid only adds a song"));
+                }
+                let pos = args.get(1).map(|pos| number(pos)).transpose()?;
+                let (source, song) = songs.remove(0);
+                let id = player.lock().await.add(&source, song, pos);
+                pair(out, "Id", &id.to_string());
+            }
+            "clear" => player.lock().await.clear().await,
+            "delete" => {
+                let mut player = player.lock().await;
+                let (start, end) = parse_range(arg(args, 0)?, player.queue().len())?;
+                player.remove(start, end).await;
+            }
+            "deleteid" => {
+                let mut player = player.lock().await;
+                let pos = queue_position(&player, arg(args, 0)?)?;
+                player.remove(pos, pos + 1).await;
+            }
+            "move" | "moveid" => {
+                let mut player = player.lock().await;
+                let from = match command.as_str() {
+                    "moveid" => queue_position(&player, arg(args, 0)?)?,
+                    _ => number(arg(args, 0)?)?,
+                };
+                let to = number(arg(args, 1)?)?;
+                if from >= player.queue().len() || to >= player.queue().len() {
+                    return Err(Ack::new(AckCode::Arg, "Bad song index"));
+                }
+                player.move_song(from, to);
+            }
+            "load" => {
+                let name = arg(args, 0)?;
+                let (source, _, songs) = library(self.user())?
+                    .into_iter()
+                    .find(|(source, playlist, _)| playlist_name(source, playlist) == name)
+                    .ok_or_else(|| Ack::new(AckCode::NoExist, "No such playlist"))?;
+                let mut player = player.lock().await;
+                for song in songs {
+                    player.add(&source, song, None);
+                }
+            }
+            "findadd" | "searchadd" => {
+                let filters = parse_filters(args, command == "searchadd")?;
+                let songs = library_songs(self.user())?;
+                let mut player = player.lock().await;
+                for (source, song) in songs {
+                    if matches_all(&filters, &source, &song, command == "searchadd") {
+                        player.add(&source, song, None);
+                    }
+                }
+            }
+
+            // the playback
+            "play" => {
+                let pos = args.first().map(|pos| number(pos)).transpose()?;
+                player.lock().await.play(pos).await.map_err(system)?;
+            }
+            "playid" => {
+                let mut player = player.lock().await;
+                let pos = match args.first() {
+                    Some(id) => Some(queue_position(&player, id)?),
+                    None => None,
+                };
+                player.play(pos).await.map_err(system)?;
+            }
+            "pause" => {
+                let mut player = player.lock().await;
+                let pause = match args.first() {
+                    Some(pause) => pause == "1",
+                    None => player.state() == PlayerState::Play,
+                };
+                player.pause(pause).await.map_err(system)?;
+            }
+            "stop" => player.lock().await.stop().await,
+            "next" => player.lock().await.next().await.map_err(system)?,
+            "previous" => player.lock().await.previous().await.map_err(system)?,
+            "seek" | "seekid" => {
+                let mut player = player.lock().await;
+                let pos = match command.as_str() {
+                    "seekid" => queue_position(&player, arg(args, 0)?)?,
+                    _ => number(arg(args, 0)?)?,
+                };
+                let time = number::<f64>(arg(args, 1)?)?;
+                if player.current() != Some(pos) || player.state() == PlayerState::Stop {
+                    player.play(Some(pos)).await.map_err(system)?;
+                }
+                let time = Duration::from_secs_f64(time.max(0.));
+                player.seek(time).await.map_err(system)?;
+            }
+            "seekcur" => {
+                let mut player = player.lock().await;
+                let time = arg(args, 0)?;
+                let seconds = number::<f64>(time)?;
+                // a sign makes the time relative to the current position
+                let seconds = if time.starts_with('+') || time.starts_with('-') {
+                    player.elapsed().as_secs_f64() + seconds
+                } else {
+                    seconds
+                };
+                let time = Duration::from_secs_f64(seconds.max(0.));
+                player.seek(time).await.map_err(system)?;
+            }
+            "setvol" | "volume" => {
+                let mut player = player.lock().await;
+                let value = number::<i64>(arg(args, 0)?)?;
+                // volume is the deprecated relative change
+                let volume = match command.as_str() {
+                    "volume" => player.volume() as i64 + value,
+                    _ => value,
+                };
+                player
+                    .set_volume(volume.clamp(0, 100) as u32)
+                    .await
+                    .map_err(system)?;
+            }
+            "getvol" => pair(out, "volume", &player.lock().await.volume().to_string()),
+            "repeat" | "single" | "consume" => {
+                let value = arg(args, 0)?;
+                let value = match value {
+                    "0" => false,
+                    "1" | "oneshot" => true,
+                    _ => return Err(Ack::new(AckCode::Arg, "Boolean (0/1) expected")),
+                };
+                let mut player = player.lock().await;
+                match command.as_str() {
+                    "repeat" => player.repeat = value,
+                    "single" => player.single = value,
+                    _ => player.consume = value,
+                }
+                player.options_changed();
+            }
+            "random" => {
+                if arg(args, 0)? != "0" {
+                    return Err(Ack::new(AckCode::Arg, "Random playback is not supported"));
+                }
+            }
+
+            // the library
+            "listplaylists" => {
+                for (source, playlist, _) in library(self.user())? {
+                    pair(out, "playlist", &playlist_name(&source, &playlist));
+                }
+            }
+            "listplaylist" | "listplaylistinfo" => {
+                let name = arg(args, 0)?;
+                let (source, _, songs) = library(self.user())?
+                    .into_iter()
+                    .find(|(source, playlist, _)| playlist_name(source, playlist) == name)
+                    .ok_or_else(|| Ack::new(AckCode::NoExist, "No such playlist"))?;
+                for song in songs {
+                    match command.as_str() {
+                        "listplaylist" => pair(out, "file", &song_uri(&source, &song)),
+                        _ => write_song(out, &source, &song),
+                    }
+                }
+            }
+            "save" | "rm" | "rename" | "playlistadd" | "playlistclear" | "playlistdelete"
+            | "playlistmove" => {
+                return Err(Ack::new(
+                    AckCode::Permission,
+                    "The playlists are managed by the sources",
+                ))
+            }
+            "find" | "search" => {
+                let filters = parse_filters(args, command == "search")?;
+                for (source, song) in library_songs(self.user())? {
+                    if matches_all(&filters, &source, &song, command == "search") {
+                        write_song(out, &source, &song);
+                    }
+                }
+            }
+            "count" => {
+                let filters = parse_filters(args, false)?;
+                let (mut songs, mut playtime) = (0, Duration::ZERO);
+                for (source, song) in library_songs(self.user())? {
+                    if matches_all(&filters, &source, &song, false) {
+                        songs += 1;
+                        playtime += song.duration;
+                    }
+                }
+                pair(out, "songs", &songs.to_string());
+                pair(out, "playtime", &playtime.as_secs().to_string());
+            }
+            "list" => {
+                let tag = arg(args, 0)?.to_lowercase();
+                let mut args = &args[1..];
+                // the grouping is not supported, the values are listed alone
+                if let Some(group) = args.iter().position(|arg| arg == "group") {
+                    args = &args[..group];
+                }
+                let filters = match args {
+                    // the old syntax of `list album ARTIST`
+                    [artist] if !artist.starts_with('(') => vec![Filter {
+                        tag: "artist".to_string(),
+                        op: Op::Equal,
+                        value: artist.clone(),
+                    }],
+                    args => parse_filters(args, false)?,
+                };
+                let mut listed = HashSet::new();
+                for (source, song) in library_songs(self.user())? {
+                    if !matches_all(&filters, &source, &song, false) {
+                        continue;
+                    }
+                    for value in tag_values(&tag, &source, &song)? {
+                        if listed.insert(value.clone()) {
+                            pair(out, &tag_name(&tag), &value);
+                        }
+                    }
+                }
+            }
+            "lsinfo" | "listall" | "listallinfo" => {
+                let uri = args.first().map(String::as_str).unwrap_or_default();
+                let uri = uri.trim_matches('/');
+                let path = music_path(uri)?;
+                let downloaded = downloaded_songs()?;
+                if path.is_file() {
+                    write_file(out, uri, &path, &downloaded);
+                } else {
+                    let recursive = command != "lsinfo";
+                    let info = command != "listall";
+                    list_directory(out, uri, &path, &downloaded, recursive, info);
+                }
+                if command == "lsinfo" && uri.is_empty() {
+                    for (source, playlist, _) in library(self.user())? {
+                        pair(out, "playlist", &playlist_name(&source, &playlist));
+                    }
+                }
+            }
+            _ => {
+                return Err(Ack::new(
+                    AckCode::Unknown,
+                    format!("unknown command \"{}\"", command),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Permission needed to run `command`, `None` for those allowed before the password
+fn permission(command: &str) -> Option<Permission> {
+    match command {
+        "password" | "ping" | "commands" | "notcommands" | "tagtypes" | "urlhandlers"
+        | "decoders" => None,
+        "add" | "addid" | "clear" | "delete" | "deleteid" | "move" | "moveid" | "load"
+        | "findadd" | "searchadd" | "play" | "playid" | "pause" | "stop" | "next" | "previous"
+        | "seek" | "seekid" | "seekcur" | "setvol" | "volume" | "repeat" | "single" | "consume"
+        | "random" => Some(Permission::Play),
+        _ => Some(Permission::Read),
+    }
+}
+
+fn token(token: &str) -> Credentials {
+    Credentials::Token(token.to_string())
+}
+
+fn system(err: io::Error) -> Ack {
+    Ack::new(AckCode::System, err.to_string())
+}
+
+/// Writes a `key: value` line
+fn pair(out: &mut String, key: &str, value: &str) {
+    out.push_str(key);
+    out.push_str(": ");
+    out.push_str(&value.replace('\n', " "));
+    out.push('\n');
+}
+
+fn flag(value: bool) -> &'static str {
+    if value {
+        "1"
+    } else {
+        "0"
+    }
+}
+
+fn arg(args: &[String], index: usize) -> Result<&str, Ack> {
+    args.get(index)
+        .map(String::as_str)
+        .ok_or_else(|| Ack::new(AckCode::Arg, "wrong number of arguments"))
+}
+
+fn number<T: FromStr>(value: &str) -> Result<T, Ack> {
+    value
+        .parse()
+        .map_err(|_| Ack::new(AckCode::Arg, format!("Number expected: {}", value)))
+}
+
+/// Parses `N`, `START:END` or `START:` into a range of the queue
+fn parse_range(range: &str, len: usize) -> Result<(usize, usize), Ack> {
+    match range.split_once(':') {
+        Some((start, "")) => Ok((number(start)?, len)),
+        Some((start, end)) => Ok((number(start)?, number(end)?)),
+        None => {
+            let pos: usize = number(range)?;
+            if pos >= len {
+                return Err(Ack::new(AckCode::Arg, "Bad song index"));
+            }
+            Ok((pos, pos + 1))
+        }
+    }
+}
+
+fn queue_position(player: &player::Player, id: &str) -> Result<usize, Ack> {
+    player
+        .position(number(id)?)
+        .ok_or_else(|| Ack::new(AckCode::NoExist, "No such song"))
+}
+
+/// Splits a command line into its words, the quoted ones may contain spaces
+fn parse_args(line: &str) -> Result<Vec<String>, Ack> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+        let mut arg = String::new();
+        if first == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => arg.extend(chars.next()),
+                    Some(c) => arg.push(c),
+                    None => return Err(Ack::new(AckCode::Arg, "Missing closing '\"'")),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+#[derive(PartialEq)]
+enum Op {
+    Equal,
+    NotEqual,
+    Contains,
+}
+
+/// A condition on a tag of the songs
+struct Filter {
+    tag: String,
+    op: Op,
+    value: String,
+}
+
+/// Parses a filter expression like `((artist == 'a') AND (...))`, or `TAG VALUE` pairs
+/// matching the songs whose tag is the value, or contains it when `search`
+fn parse_filters(args: &[String], search: bool) -> Result<Vec<Filter>, Ack> {
+    match args {
+        [expression] if expression.starts_with('(') => {
+            let mut chars = expression.chars().peekable();
+            let mut filters = vec![];
+            parse_expression(&mut chars, &mut filters)?;
+            Ok(filters)
+        }
+        args if args.len() % 2 == 0 => Ok(args
+            .chunks(2)
+            .map(|pair| Filter {
+                tag: pair[0].to_lowercase(),
+                op: if search { Op::Contains } else { Op::Equal },
+                value: pair[1].clone(),
+            })
+            .collect()),
+        _ => Err(Ack::new(
+            AckCode::Arg,
+            "Incorrect number of filter arguments",
+        )),
+    }
+}
+
+fn parse_expression(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    filters: &mut Vec<Filter>,
+) -> MpdResult {
+    let invalid = || Ack::new(AckCode::Arg, "Invalid filter expression");
+    let skip_spaces = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    skip_spaces(chars);
+    if chars.next() != Some('(') {
+        return Err(invalid());
+    }
+    skip_spaces(chars);
+    if chars.peek() == Some(&'(') {
+        // sub expressions joined by AND
+        loop {
+            parse_expression(chars, filters)?;
+            skip_spaces(chars);
+            match chars.next() {
+                Some(')') => return Ok(()),
+                Some('A') if chars.next() == Some('N') && chars.next() == Some('D') => (),
+                _ => return Err(invalid()),
+            }
+        }
+    }
+    let mut word = || {
+        skip_spaces(chars);
+        let mut word = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            word.push(c);
+        }
+        word
+    };
+    let tag = word().to_lowercase();
+    let op = match word().as_str() {
+        "==" => Op::Equal,
+        "!=" => Op::NotEqual,
+        "contains" => Op::Contains,
+        _ => return Err(invalid()),
+    };
+    skip_spaces(chars);
+    let quote = chars
+        .next()
+        .filter(|c| *c == '\'' || *c == '"')
+        .ok_or_else(invalid)?;
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('\\') => value.extend(chars.next()),
+            Some(c) if c == quote => break,
+            Some(c) => value.push(c),
+            None => return Err(invalid()),
+        }
+    }
+    skip_spaces(chars);
+    if chars.next() != Some(')') {
+        return Err(invalid());
+    }
+    filters.push(Filter { tag, op, value });
+    Ok(())
+}
+
+fn matches_all(filters: &[Filter], source: &str, song: &Song, ignore_case: bool) -> bool {
+    let normalize = |value: &str| {
+        if ignore_case {
+            value.to_lowercase()
+        } else {
+            value.to_string()
+        }
+    };
+    filters.iter().all(|filter| {
+        let expected = normalize(&filter.value);
+        let values = tag_values(&filter.tag, source, song).unwrap_or_default();
+        let mut values = values.iter().map(|value| normalize(value));
+        match filter.op {
+            Op::Equal => values.any(|value| value == expected),
+            Op::NotEqual => values.all(|value| value != expected),
+            Op::Contains => values.any(|value| value.contains(&expected)),
+        }
+    })
+}
+
+/// Values of `tag` for `song`, `any` standing for every tag
+fn tag_values(tag: &str, source: &str, song: &Song) -> Result<Vec<String>, Ack> {
+    Ok(match tag {
+        "title" => vec![song.title.clone()],
+        "artist" | "albumartist" => song.artists.clone(),
+        "file" => vec![song_uri(source, song)],
+        "album" => vec![],
+        "any" => {
+            let mut values = vec![song.title.clone(), song_uri(source, song)];
+            values.extend(song.artists.iter().cloned());
+            values.extend(song.tags.iter().cloned());
+            values
+        }
+        _ => return Err(Ack::new(AckCode::Arg, format!("Unknown tag type: {}", tag))),
+    })
+}
+
+fn tag_name(tag: &str) -> String {
+    match tag {
+        "albumartist" => "AlbumArtist".to_string(),
+        "file" => "file".to_string(),
+        tag => {
+            let mut name = tag.to_string();
+            name[..1].make_ascii_uppercase();
+            name
+        }
+    }
+}
+
+/// Writes the tags of `song`
+fn write_song(out: &mut String, source: &str, song: &Song) {
+    pair(out, "file", &song_uri(source, song));
+    if !song.title.is_empty() {
+        pair(out, "Title", &song.title);
+    }
+    for artist in &song.artists {
+        pair(out, "Artist", artist);
+    }
+    if !song.duration.is_zero() {
+        pair(out, "Time", &song.duration.as_secs().to_string());
+        pair(
+            out,
+            "duration",
+            &format!("{:.3}", song.duration.as_secs_f64()),
+        );
+    }
+}
+
+/// Name of the stored playlist standing for a playlist of a source
+fn playlist_name(source: &str, playlist: &Playlist) -> String {
+    format!("{}: {}", source, playlist.title)
+}
+
+/// The playlists of `user` with their source and songs
+fn library(user: &str) -> Result<Vec<(String, Playlist, Vec<Song>)>, Ack> {
+    let db_error = |err: rusqlite::Error| Ack::new(AckCode::System, err.to_string());
+    let mut library = vec![];
+    for (source, playlist, _) in db::list_playlists(user).map_err(db_error)? {
+        let songs = db::get_playlist_songs(&playlist.id, &source, user).map_err(db_error)?;
+        library.push((source, playlist, songs));
+    }
+    Ok(library)
+}
+
+/// Every song in the playlists of `user`, once
+fn library_songs(user: &str) -> Result<Vec<(String, Song)>, Ack> {
+    let mut seen = HashSet::new();
+    let mut songs = vec![];
+    for (source, _, playlist) in library(user)? {
+        for song in playlist {
+            if seen.insert((source.clone(), song.id.clone())) {
+                songs.push((source.clone(), song));
+            }
+        }
+    }
+    Ok(songs)
+}
+
+fn music_dir() -> PathBuf {
+    PathBuf::from(format!("{}/music", config::get_config().data_location))
+}
+
+/// Path of `uri` in the music directory, which it cannot leave
+fn music_path(uri: &str) -> Result<PathBuf, Ack> {
+    let relative = Path::new(uri);
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return Err(Ack::new(AckCode::NoExist, "Malformed uri"));
+    }
+    let path = music_dir().join(relative);
+    // the music directory only exists once something was downloaded
+    if !path.exists() && !uri.is_empty() {
+        return Err(Ack::new(AckCode::NoExist, "No such directory"));
+    }
+    Ok(path)
+}
+
+/// Uri of a song: its path in the music directory when downloaded, `source://id` otherwise
+fn song_uri(source: &str, song: &Song) -> String {
+    song_uri_in(&music_dir(), source, song)
+}
+
+fn song_uri_in(music_dir: &Path, source: &str, song: &Song) -> String {
+    if song.downloaded {
+        if let Some(uri) = file_uri(music_dir, Path::new(&song.url)) {
+            return uri;
+        }
+    }
+    format!("{}://{}", source.to_lowercase(), song.id)
+}
+
+/// Uri of `file` in `music_dir`, `None` when it is not in it.
+/// Both are canonicalized when the path of the file does not start with the directory:
+/// the stored path of a file may be absolute while the directory is relative.
+fn file_uri(music_dir: &Path, file: &Path) -> Option<String> {
+    if let Ok(relative) = file.strip_prefix(music_dir) {
+        return Some(uri_of(relative));
+    }
+    let (dir, file) = (music_dir.canonicalize().ok()?, file.canonicalize().ok()?);
+    file.strip_prefix(dir).ok().map(uri_of)
+}
+
+fn uri_of(relative: &Path) -> String {
+    relative
+        .iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The downloaded songs by uri, with their source
+fn downloaded_songs() -> Result<HashMap<String, (String, Song)>, Ack> {
+    let songs = db::get_all_songs().map_err(|err| Ack::new(AckCode::System, err.to_string()))?;
+    Ok(songs
+        .into_iter()
+        .filter(|(_, song)| song.downloaded)
+        .map(|(source, song)| (song_uri(&source, &song), (source, song)))
+        .collect())
+}
+
+/// The songs `uri` stands for: a song of a source, a stream, or a file or directory
+/// of the music directory
+fn resolve_uri(uri: &str) -> Result<Vec<(String, Song)>, Ack> {
+    if let Some((scheme, id)) = uri.split_once("://") {
+        if let Some(source) = source::SOURCES
+            .iter()
+            .find(|source| source.eq_ignore_ascii_case(scheme))
+        {
+            let song = db::get_song(id, source)
+                .map_err(|err| Ack::new(AckCode::System, err.to_string()))?
+                .ok_or_else(|| Ack::new(AckCode::NoExist, "No such song"))?;
+            return Ok(vec![(source.to_string(), song)]);
+        }
+        if scheme == "http" || scheme == "https" {
+            return Ok(vec![(String::new(), file_song(uri, uri.to_string()))]);
+        }
+        return Err(Ack::new(AckCode::NoExist, "Unsupported uri scheme"));
+    }
+    let uri = uri.trim_matches('/');
+    let path = music_path(uri)?;
+    let downloaded = downloaded_songs()?;
+    let mut files = vec![];
+    collect_files(&path, &mut files);
+    Ok(files
+        .into_iter()
+        .map(|file| {
+            let uri = uri_of(file.strip_prefix(music_dir()).unwrap_or(&file));
+            match downloaded.get(&uri) {
+                Some((source, song)) => (source.clone(), song.clone()),
+                None => (String::new(), file_song(&uri, file.display().to_string())),
+            }
+        })
+        .collect())
+}
+
+/// A song for a file or stream that is not in the database
+fn file_song(uri: &str, url: String) -> Song {
+    let title = uri.rsplit('/').next().unwrap_or(uri).to_string();
+    let mut song = Song::new(title, vec![], vec![], uri.to_string(), Duration::ZERO, url);
+    song.downloaded = !uri.contains("://");
+    song
+}
+
+/// The files in `path`, in the order of their names
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) {
+    if path.is_file() {
+        files.push(path.to_path_buf());
+        return;
+    }
+    for entry in sorted_entries(path) {
+        collect_files(&entry, files);
+    }
+}
+
+fn sorted_entries(path: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = match std::fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .collect(),
+        Err(_) => vec![],
+    };
+    entries.sort();
+    entries
+}
+
+fn write_file(
+    out: &mut String,
+    uri: &str,
+    path: &Path,
+    downloaded: &HashMap<String, (String, Song)>,
+) {
+    match downloaded.get(uri) {
+        Some((source, song)) => write_song(out, source, song),
+        None => {
+            pair(out, "file", uri);
+            if let Some(name) = path.file_stem() {
+                pair(out, "Title", &name.to_string_lossy());
+            }
+        }
+    }
+}
+
+fn list_directory(
+    out: &mut String,
+    uri: &str,
+    path: &Path,
+    downloaded: &HashMap<String, (String, Song)>,
+    recursive: bool,
+    info: bool,
+) {
+    for entry in sorted_entries(path) {
+        let name = entry
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let entry_uri = if uri.is_empty() {
+            name
+        } else {
+            format!("{}/{}", uri, name)
+        };
+        if entry.is_dir() {
+            pair(out, "directory", &entry_uri);
+            if recursive {
+                list_directory(out, &entry_uri, &entry, downloaded, recursive, info);
+            }
+        } else if info {
+            write_file(out, &entry_uri, &entry, downloaded);
+        } else {
+            pair(out, "file", &entry_uri);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Option<Vec<String>> {
+        parse_args(line).ok()
+    }
+
+    /// Tag, operator and value of the filters, `None` when they are invalid
+    fn filters(args: &[&str], search: bool) -> Option<Vec<(String, &'static str, String)>> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let filters = parse_filters(&args, search).ok()?;
+        let op = |op: &Op| match op {
+            Op::Equal => "==",
+            Op::NotEqual => "!=",
+            Op::Contains => "contains",
+        };
+        Some(
+            filters
+                .iter()
+                .map(|f| (f.tag.clone(), op(&f.op), f.value.clone()))
+                .collect(),
+        )
+    }
+
+    fn filter(tag: &str, op: &'static str, value: &str) -> (String, &'static str, String) {
+        (tag.to_string(), op, value.to_string())
+    }
+
+    #[test]
+    fn args_quoting() {
+        assert_eq!(args("play 3").unwrap(), ["play", "3"]);
+        assert_eq!(args("  status  ").unwrap(), ["status"]);
+        assert_eq!(args("add \"a b/c d\"").unwrap(), ["add", "a b/c d"]);
+        assert_eq!(args("find \"\"").unwrap(), ["find", ""]);
+        assert_eq!(args("").unwrap(), Vec::<String>::new());
+        assert!(args("add \"unclosed").is_none());
+    }
+
+    #[test]
+    fn args_escaping() {
+        assert_eq!(args(r#"add "say \"hi\"""#).unwrap(), ["add", r#"say "hi""#]);
+        assert_eq!(
+            args(r#"add "back\\slash""#).unwrap(),
+            ["add", r"back\slash"]
+        );
+        assert_eq!(
+            args(r#"find "(artist == 'Guns N\\' Roses')""#).unwrap(),
+            ["find", r"(artist == 'Guns N\' Roses')"]
+        );
+    }
+
+    #[test]
+    fn filter_pairs() {
+        let expected = vec![filter("artist", "==", "A"), filter("title", "==", "B")];
+        assert_eq!(
+            filters(&["Artist", "A", "Title", "B"], false).unwrap(),
+            expected
+        );
+        let expected = vec![filter("any", "contains", "x")];
+        assert_eq!(filters(&["any", "x"], true).unwrap(), expected);
+        assert!(filters(&["artist"], false).is_none());
+    }
+
+    #[test]
+    fn filter_expressions() {
+        let expected = vec![filter("artist", "==", "A B")];
+        assert_eq!(filters(&["(Artist == 'A B')"], false).unwrap(), expected);
+        let expected = vec![
+            filter("artist", "!=", "A"),
+            filter("title", "contains", "it's"),
+        ];
+        let expression = r#"((artist != "A") AND (title contains 'it\'s'))"#;
+        assert_eq!(filters(&[expression], false).unwrap(), expected);
+        for invalid in [
+            "(artist 'A')",
+            "(artist == 'A'",
+            "((artist == 'A') OR (a == 'b'))",
+        ] {
+            assert!(filters(&[invalid], false).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("2", 5).ok(), Some((2, 3)));
+        assert_eq!(parse_range("1:3", 5).ok(), Some((1, 3)));
+        assert_eq!(parse_range("1:", 5).ok(), Some((1, 5)));
+        assert!(parse_range("5", 5).is_err());
+        assert!(parse_range("a:3", 5).is_err());
+        assert!(parse_range("-1", 5).is_err());
+    }
+
+    /// A directory of the temporary directory for the test `name`, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = format!("music-server-{}-{}", name, std::process::id());
+            let dir = std::env::temp_dir().join(dir);
+            std::fs::create_dir_all(dir.join("music/artist")).unwrap();
+            std::fs::write(dir.join("music/artist/song.mp3"), b"").unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn file_uris() {
+        let tmp = TempDir::new("file-uris");
+        let music = tmp.0.join("music");
+        let file = music.join("artist/song.mp3");
+        assert_eq!(file_uri(&music, &file).as_deref(), Some("artist/song.mp3"));
+        let detour = tmp.0.join("music/artist/../../music");
+        assert_eq!(file_uri(&detour, &file).as_deref(), Some("artist/song.mp3"));
+        let relative = file_uri(Path::new("music"), Path::new("music/artist/song.mp3"));
+        assert_eq!(relative.as_deref(), Some("artist/song.mp3"));
+        assert_eq!(file_uri(&music.join("artist"), &tmp.0.join("music")), None);
+        assert_eq!(file_uri(&music, &tmp.0.join("elsewhere.mp3")), None);
+    }
+
+    #[test]
+    fn absolute_song_urls() {
+        let tmp = TempDir::new("song-uris");
+        let mut song = Song {
+            id: "id".to_string(),
+            url: tmp.0.join("music/artist/song.mp3").display().to_string(),
+            downloaded: true,
+            ..Default::default()
+        };
+        // the directory is relative to the working directory of the server
+        let cwd = std::env::current_dir().unwrap();
+        let depth = cwd.components().count() - 1;
+        let up = "../".repeat(depth);
+        let music = PathBuf::from(format!("{}{}", up, tmp.0.join("music").display()));
+        assert!(music.is_relative());
+        assert_eq!(song_uri_in(&music, "Youtube", &song), "artist/song.mp3");
+        song.downloaded = false;
+        assert_eq!(song_uri_in(&music, "Youtube", &song), "youtube://id");
+    }
+}
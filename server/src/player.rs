@@ -0,0 +1,582 @@
+//! Playback on the machine running the server, through an mpv process driven over its ipc
+
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use music_server::request::{
+    AnswerType, ErrorType, EventType, PlayerRequest, PlayerState, PlayerStatus,
+};
+use music_server::source_types::Song;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{config, db, events, source, stream, utils};
+
+static PLAYER: OnceLock<Mutex<Player>> = OnceLock::new();
+static CHANGES: OnceLock<broadcast::Sender<Change>> = OnceLock::new();
+
+/// The player shared by every connection
+pub fn player() -> &'static Mutex<Player> {
+    PLAYER.get_or_init(|| Mutex::new(Player::default()))
+}
+
+/// Whether the server plays songs itself
+pub fn enabled() -> bool {
+    config::get_config().playback
+}
+
+/// Runs a request of a client, answering the status of the player.
+/// The songs to enqueue are taken from `source`.
+pub async fn handle(source: &str, request: PlayerRequest) -> AnswerType {
+    if !enabled() {
+        return player_error("Playback is disabled on the server".to_string());
+    }
+    let mut player = player().lock().await;
+    let res = match request {
+        PlayerRequest::Status => Ok(()),
+        PlayerRequest::Play(index) => player.play(index).await,
+        PlayerRequest::Pause => player.pause(true).await,
+        PlayerRequest::Toggle => match player.state() {
+            PlayerState::Play => player.pause(true).await,
+            _ => player.play(None).await,
+        },
+        PlayerRequest::Stop => {
+            player.stop().await;
+            Ok(())
+        }
+        PlayerRequest::Next => player.next().await,
+        PlayerRequest::Previous => player.previous().await,
+        PlayerRequest::Seek(position) => player.seek(position).await,
+        PlayerRequest::SetVolume(volume) => player.set_volume(volume).await,
+        PlayerRequest::Enqueue(ids) => {
+            let Some(source) = source::SOURCES
+                .iter()
+                .find(|name| name.eq_ignore_ascii_case(source))
+            else {
+                return player_error(format!("Unknown source {}", source));
+            };
+            for id in ids {
+                match db::get_song(&id, source) {
+                    Ok(Some(song)) => {
+                        player.add(source, song, None);
+                    }
+                    _ => return player_error(format!("Unknown song {}", id)),
+                }
+            }
+            Ok(())
+        }
+        PlayerRequest::Remove(index) => {
+            player.remove(index, index + 1).await;
+            Ok(())
+        }
+        PlayerRequest::Clear => {
+            player.clear().await;
+            Ok(())
+        }
+    };
+    match res {
+        Ok(()) => AnswerType::PlayerStatus(player.status()),
+        Err(err) => player_error(format!("Playback failed: {}", err)),
+    }
+}
+
+fn player_error(message: String) -> AnswerType {
+    AnswerType::Error(ErrorType::PlayerError(message))
+}
+
+/// Notified whenever the player changes
+pub fn subscribe() -> broadcast::Receiver<Change> {
+    changes().subscribe()
+}
+
+fn changes() -> &'static broadcast::Sender<Change> {
+    CHANGES.get_or_init(|| broadcast::channel(64).0)
+}
+
+/// What changed in the player
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// Songs were added, removed or moved in the queue
+    Queue,
+    /// Playback started, stopped, paused, seeked or moved to another song
+    Playback,
+    Volume,
+    /// The repeat, single or consume options
+    Options,
+}
+
+/// A song in the queue
+#[derive(Clone)]
+pub struct Entry {
+    /// Stays the same when the song moves in the queue
+    pub id: u32,
+    pub source: String,
+    pub song: Song,
+}
+
+pub struct Player {
+    queue: Vec<Entry>,
+    next_id: u32,
+    /// Incremented on every change of the queue
+    version: u32,
+    current: Option<usize>,
+    state: PlayerState,
+    volume: u32,
+    /// Starts over at the end of the queue
+    pub repeat: bool,
+    /// Stops after the current song, or plays it again with `repeat`
+    pub single: bool,
+    /// Removes the songs from the queue once played
+    pub consume: bool,
+    /// Position in the current song when the clock was last started or stopped
+    elapsed: Duration,
+    /// When the current song started playing from `elapsed`, `None` while not playing
+    since: Option<Instant>,
+    /// Incremented on every load, the url of an older load is not played once resolved
+    loads: u32,
+    mpv: Option<Mpv>,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Player {
+            queue: vec![],
+            next_id: 1,
+            version: 1,
+            current: None,
+            state: PlayerState::Stop,
+            volume: 100,
+            repeat: false,
+            single: false,
+            consume: false,
+            elapsed: Duration::ZERO,
+            since: None,
+            loads: 0,
+            mpv: None,
+        }
+    }
+}
+
+impl Player {
+    pub fn queue(&self) -> &[Entry] {
+        &self.queue
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.state
+    }
+
+    pub fn volume(&self) -> u32 {
+        self.volume
+    }
+
+    /// Index of the playing or paused song
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    /// Position in the current song
+    pub fn elapsed(&self) -> Duration {
+        match self.since {
+            Some(since) => self.elapsed + since.elapsed(),
+            None => self.elapsed,
+        }
+    }
+
+    /// Index of the song with the queue id `id`
+    pub fn position(&self, id: u32) -> Option<usize> {
+        self.queue.iter().position(|entry| entry.id == id)
+    }
+
+    /// Adds `song` at `pos`, or at the end, and returns its queue id
+    pub fn add(&mut self, source: &str, song: Song, pos: Option<usize>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let entry = Entry {
+            id,
+            source: source.to_string(),
+            song,
+        };
+        match pos {
+            Some(pos) if pos < self.queue.len() => {
+                self.queue.insert(pos, entry);
+                if matches!(self.current, Some(current) if current >= pos) {
+                    self.current = self.current.map(|current| current + 1);
+                }
+            }
+            _ => self.queue.push(entry),
+        }
+        self.queue_changed();
+        id
+    }
+
+    /// Removes the songs in `start..end`, stopping if the current one is among them
+    pub async fn remove(&mut self, start: usize, end: usize) {
+        let end = end.min(self.queue.len());
+        if start >= end {
+            return;
+        }
+        self.queue.drain(start..end);
+        match self.current {
+            Some(current) if current >= end => self.current = Some(current - (end - start)),
+            Some(current) if current >= start => {
+                self.stop().await;
+                self.current = None;
+            }
+            _ => (),
+        }
+        self.queue_changed();
+    }
+
+    pub async fn clear(&mut self) {
+        self.stop().await;
+        self.queue.clear();
+        self.current = None;
+        self.queue_changed();
+    }
+
+    /// Moves the song at `from` to `to`
+    pub fn move_song(&mut self, from: usize, to: usize) {
+        if from >= self.queue.len() || to >= self.queue.len() {
+            return;
+        }
+        let entry = self.queue.remove(from);
+        self.queue.insert(to, entry);
+        self.current = self.current.map(|current| {
+            if current == from {
+                to
+            } else if from < current && current <= to {
+                current - 1
+            } else if to <= current && current < from {
+                current + 1
+            } else {
+                current
+            }
+        });
+        self.queue_changed();
+    }
+
+    /// Plays the song at `index`, or resumes the current one
+    pub async fn play(&mut self, index: Option<usize>) -> io::Result<()> {
+        match index {
+            Some(index) if index < self.queue.len() => {
+                self.load(index);
+                Ok(())
+            }
+            Some(index) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No song at {}", index),
+            )),
+            None if self.state == PlayerState::Pause => self.pause(false).await,
+            None if self.state == PlayerState::Play || self.queue.is_empty() => Ok(()),
+            None => {
+                self.load(self.current.unwrap_or(0));
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn pause(&mut self, pause: bool) -> io::Result<()> {
+        match (self.state, pause) {
+            (PlayerState::Play, true) => {
+                self.elapsed = self.elapsed();
+                self.since = None;
+                self.state = PlayerState::Pause;
+            }
+            (PlayerState::Pause, false) => {
+                self.since = Some(Instant::now());
+                self.state = PlayerState::Play;
+            }
+            _ => return Ok(()),
+        }
+        self.command(json!(["set_property", "pause", pause]))
+            .await?;
+        self.changed(Change::Playback);
+        Ok(())
+    }
+
+    pub async fn stop(&mut self) {
+        if self.state == PlayerState::Stop {
+            return;
+        }
+        // the process may already be gone, in which case nothing plays anyway
+        let _ = self.command(json!(["stop"])).await;
+        self.state = PlayerState::Stop;
+        self.elapsed = Duration::ZERO;
+        self.since = None;
+        self.changed(Change::Playback);
+    }
+
+    pub async fn next(&mut self) -> io::Result<()> {
+        match self.current {
+            Some(current) if current + 1 < self.queue.len() => self.load(current + 1),
+            Some(_) if self.repeat && !self.queue.is_empty() => self.load(0),
+            _ => self.stop().await,
+        }
+        Ok(())
+    }
+
+    pub async fn previous(&mut self) -> io::Result<()> {
+        match self.current {
+            Some(current) if current > 0 => self.load(current - 1),
+            Some(_) if self.repeat && !self.queue.is_empty() => self.load(self.queue.len() - 1),
+            Some(current) => self.load(current),
+            None => (),
+        }
+        Ok(())
+    }
+
+    /// Seeks to `position` in the current song
+    pub async fn seek(&mut self, position: Duration) -> io::Result<()> {
+        if self.state == PlayerState::Stop {
+            return Ok(());
+        }
+        self.command(json!(["seek", position.as_secs_f64(), "absolute"]))
+            .await?;
+        self.elapsed = position;
+        // the clock starts again once mpv restarted the playback
+        self.since = None;
+        self.changed(Change::Playback);
+        Ok(())
+    }
+
+    /// Sets the volume, from 0 to 100
+    pub async fn set_volume(&mut self, volume: u32) -> io::Result<()> {
+        self.volume = volume.min(100);
+        if self.mpv.is_some() {
+            self.command(json!(["set_property", "volume", self.volume]))
+                .await?;
+        }
+        self.changed(Change::Volume);
+        Ok(())
+    }
+
+    pub fn options_changed(&self) {
+        self.changed(Change::Options);
+    }
+
+    fn queue_changed(&mut self) {
+        self.version += 1;
+        self.changed(Change::Queue);
+    }
+
+    /// Notifies the frontends, and the clients subscribed to the player
+    fn changed(&self, change: Change) {
+        // an error only means that nobody is listening
+        let _ = changes().send(change);
+        events::publish_all("player", EventType::PlayerChanged(self.status()));
+    }
+
+    pub fn status(&self) -> PlayerStatus {
+        PlayerStatus {
+            state: self.state,
+            queue: self
+                .queue
+                .iter()
+                .map(|entry| (entry.source.clone(), entry.song.clone()))
+                .collect(),
+            current: self.current,
+            elapsed: self.elapsed(),
+            volume: self.volume,
+        }
+    }
+
+    /// Plays the song at `index`. Its url is resolved in the background, which may
+    /// take a while with yt-dlp, and the player is only locked again to load it.
+    fn load(&mut self, index: usize) {
+        let entry = self.queue[index].clone();
+        self.loads += 1;
+        let load = self.loads;
+        self.current = Some(index);
+        self.elapsed = Duration::ZERO;
+        self.since = None;
+        self.state = PlayerState::Play;
+        self.changed(Change::Playback);
+        tokio::spawn(async move {
+            let url = song_url(&entry.source, &entry.song).await;
+            player().lock().await.load_url(load, url).await;
+        });
+    }
+
+    /// Gives mpv the url of the load number `load`, unless the player moved on since
+    async fn load_url(&mut self, load: u32, url: String) {
+        if load != self.loads || self.state == PlayerState::Stop {
+            return;
+        }
+        let pause = self.state == PlayerState::Pause;
+        let res = self.command(json!(["loadfile", url, "replace"])).await;
+        let res = match res {
+            Ok(()) => self.command(json!(["set_property", "pause", pause])).await,
+            err => err,
+        };
+        if let Err(err) = res {
+            println!("Cannot play {}: {}", url, err);
+            self.state = PlayerState::Stop;
+            self.changed(Change::Playback);
+        }
+    }
+
+    /// Called when mpv actually starts or resumes playing, after a load or a seek
+    fn playback_restarted(&mut self) {
+        if self.state == PlayerState::Play {
+            self.since = Some(Instant::now());
+        }
+    }
+
+    /// Called when the current song played to its end
+    async fn song_ended(&mut self) {
+        let Some(current) = self.current else {
+            return;
+        };
+        let mut next = if self.single {
+            self.repeat.then_some(current)
+        } else if current + 1 < self.queue.len() {
+            Some(current + 1)
+        } else {
+            self.repeat.then_some(0)
+        };
+        if self.consume {
+            self.queue.remove(current);
+            self.queue_changed();
+            next = match next {
+                // the song to play again is gone
+                Some(next) if next == current && self.single => None,
+                Some(next) if next > current => Some(next - 1),
+                next => next,
+            };
+        }
+        match next {
+            Some(next) if next < self.queue.len() => self.load(next),
+            _ => {
+                self.current = if self.consume { None } else { Some(current) };
+                self.state = PlayerState::Stop;
+                self.elapsed = Duration::ZERO;
+                self.since = None;
+                self.changed(Change::Playback);
+            }
+        }
+    }
+
+    /// Sends `command` to mpv, starting it if needed
+    async fn command(&mut self, command: Value) -> io::Result<()> {
+        if self.mpv.is_none() {
+            self.mpv = Some(Mpv::start(self.volume).await?);
+        }
+        let mpv = self.mpv.as_mut().expect("mpv is started");
+        let res = mpv.send(command).await;
+        if res.is_err() {
+            // the process is gone, it is started again on the next command
+            self.mpv = None;
+        }
+        res
+    }
+}
+
+/// Plays the next song once the current one ended. The future is boxed
+/// since playing the next song may start mpv, which waits for the songs to end.
+fn on_song_end() -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async { player().lock().await.song_ended().await })
+}
+
+/// Where mpv plays `song` from: the downloaded or cached file when there is one
+async fn song_url(source: &str, song: &Song) -> String {
+    if song.downloaded && Path::new(&song.url).exists() {
+        return song.url.clone();
+    }
+    if source.is_empty() {
+        return song.url.clone();
+    }
+    let cached = stream::cache_path(source, &song.id);
+    if cached.exists() {
+        return cached.display().to_string();
+    }
+    utils::song_link(song, source).await
+}
+
+/// A running mpv process
+struct Mpv {
+    _process: Child,
+    ipc: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl Mpv {
+    async fn start(volume: u32) -> io::Result<Self> {
+        let path = ipc_path();
+        let process = Command::new("mpv")
+            .args(["--idle=yes", "--no-video", "--no-terminal"])
+            .arg(format!("--volume={}", volume))
+            .arg(format!("--input-ipc-server={}", path))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        // mpv needs a moment to create its socket
+        let mut tries = 0;
+        let ipc = loop {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            match connect(&path).await {
+                Ok(ipc) => break ipc,
+                Err(err) if tries >= 50 => return Err(err),
+                Err(_) => tries += 1,
+            }
+        };
+        let (reader, writer) = tokio::io::split(ipc);
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                    continue;
+                };
+                match message["event"].as_str() {
+                    Some("playback-restart") => player().lock().await.playback_restarted(),
+                    Some("end-file") if matches!(message["reason"].as_str(), Some("eof")) => {
+                        on_song_end().await
+                    }
+                    _ => (),
+                }
+            }
+        });
+        Ok(Mpv {
+            _process: process,
+            ipc: Box::new(writer),
+        })
+    }
+
+    async fn send(&mut self, command: Value) -> io::Result<()> {
+        let mut line = json!({ "command": command }).to_string();
+        line.push('\n');
+        self.ipc.write_all(line.as_bytes()).await
+    }
+}
+
+#[cfg(unix)]
+fn ipc_path() -> String {
+    format!("{}/mpv.sock", config::get_config().data_location)
+}
+
+#[cfg(unix)]
+async fn connect(path: &str) -> io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(path).await
+}
+
+#[cfg(windows)]
+fn ipc_path() -> String {
+    r"\\.\pipe\music-server-mpv".to_string()
+}
+
+#[cfg(windows)]
+async fn connect(path: &str) -> io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    tokio::net::windows::named_pipe::ClientOptions::new().open(path)
+}
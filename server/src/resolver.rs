@@ -0,0 +1,247 @@
+#![warn(clippy::unwrap_used)]
+use std::{path::PathBuf, time::Duration};
+
+use ytd_rs::{Arg, YoutubeDL};
+
+use crate::{config, db, source::Song};
+
+/// Words that usually denote something other than the studio recording.
+/// A candidate containing one of them is penalized unless the song title also does.
+const NOISE_WORDS: [&str; 10] = [
+    "cover",
+    "live",
+    "karaoke",
+    "remix",
+    "loop",
+    "hour",
+    "nightcore",
+    "sped",
+    "slowed",
+    "reaction",
+];
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub id: String,
+    pub title: String,
+    pub channel: String,
+    pub duration: Duration,
+}
+
+impl Candidate {
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(4, '\t');
+        let id = fields.next()?.to_string();
+        let duration = fields.next()?.parse::<f64>().unwrap_or_default();
+        let channel = fields.next()?.to_string();
+        let title = fields.next()?.to_string();
+        if id.is_empty() {
+            return None;
+        }
+        Some(Candidate {
+            id,
+            title,
+            channel,
+            duration: Duration::from_secs_f64(duration.max(0.0)),
+        })
+    }
+}
+
+/// Query used to search a song on youtube
+pub fn search_query(song: &Song) -> String {
+    format!("{} - {}", song.artists.join(", "), song.title)
+}
+
+fn tokens(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_string())
+        .collect()
+}
+
+/// Proportion of the song's words found in the candidate's title and channel
+fn title_similarity(song: &Song, candidate: &Candidate) -> f64 {
+    let expected = tokens(&search_query(song));
+    if expected.is_empty() {
+        return 0.0;
+    }
+    let found = tokens(&format!("{} {}", candidate.title, candidate.channel));
+    let common = expected.iter().filter(|t| found.contains(t)).count();
+    common as f64 / expected.len() as f64
+}
+
+fn duration_score(song: &Song, candidate: &Candidate) -> f64 {
+    if song.duration.is_zero() || candidate.duration.is_zero() {
+        // nothing to compare, stay neutral
+        return 0.5;
+    }
+    let diff = (song.duration.as_secs_f64() - candidate.duration.as_secs_f64()).abs();
+    if diff <= 2.0 {
+        1.0
+    } else {
+        (1.0 - diff / 30.0).max(0.0)
+    }
+}
+
+fn channel_score(song: &Song, candidate: &Candidate) -> f64 {
+    let channel = candidate.channel.to_lowercase();
+    let mut score = 0.0;
+    if channel.ends_with("- topic") {
+        // auto-generated channels only host the studio recordings
+        score += 0.3;
+    }
+    if channel.contains("vevo") || candidate.title.to_lowercase().contains("official") {
+        score += 0.15;
+    }
+    let channel_tokens = tokens(&channel).join("");
+    if song
+        .artists
+        .iter()
+        .any(|a| !a.is_empty() && channel_tokens.contains(&tokens(a).join("")))
+    {
+        score += 0.15;
+    }
+    score
+}
+
+fn noise_penalty(song: &Song, candidate: &Candidate) -> f64 {
+    let expected = tokens(&song.title);
+    let found = tokens(&candidate.title);
+    NOISE_WORDS
+        .iter()
+        .filter(|w| {
+            found.iter().any(|t| t.starts_with(*w)) && !expected.iter().any(|t| t.starts_with(*w))
+        })
+        .count() as f64
+        * 0.3
+}
+
+pub fn score(song: &Song, candidate: &Candidate) -> f64 {
+    0.45 * duration_score(song, candidate)
+        + 0.35 * title_similarity(song, candidate)
+        + channel_score(song, candidate)
+        - noise_penalty(song, candidate)
+}
+
+/// Returns the first `count` results of a youtube search for `song`
+pub async fn search_candidates(song: &Song, count: u32) -> Vec<Candidate> {
+    let config = config::get_config();
+    let args = vec![
+        Arg::new("--quiet"),
+        Arg::new("--flat-playlist"),
+        Arg::new_with_arg("--print", "%(id)s\t%(duration)s\t%(channel)s\t%(title)s"),
+    ];
+    let link = format!("ytsearch{}:{}", count, search_query(song));
+    let ytdlp = match YoutubeDL::new(&PathBuf::from(config.data_location), args, &link) {
+        Ok(ytdlp) => ytdlp,
+        Err(err) => {
+            println!("{}", err);
+            return vec![];
+        }
+    };
+    let result = match tokio::task::spawn_blocking(move || ytdlp.download()).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(err)) => {
+            println!("{}", err);
+            return vec![];
+        }
+        Err(err) => {
+            println!("{}", err);
+            return vec![];
+        }
+    };
+    result
+        .output()
+        .lines()
+        .filter_map(Candidate::from_line)
+        .collect()
+}
+
+/// Returns the id of the youtube video matching `song`.
+/// A previously chosen (or user provided) match is reused, otherwise the best candidate
+/// of a search is picked and stored.
+pub async fn resolve(song: &Song, source: &str) -> Option<String> {
+    if let Ok(Some(video_id)) = db::get_song_match(&song.id, source) {
+        return Some(video_id);
+    }
+    let count = config::get_config().resolver_candidates;
+    let candidates = search_candidates(song, count).await;
+    let best = candidates.into_iter().max_by(|a, b| {
+        score(song, a)
+            .partial_cmp(&score(song, b))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    })?;
+    println!("Matched {} with {} ({})", song.title, best.title, best.id);
+    let _ = db::set_song_match(&song.id, source, &best.id, false);
+    Some(best.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(title: &str, artist: &str, secs: u64) -> Song {
+        Song {
+            title: title.to_string(),
+            artists: vec![artist.to_string()],
+            duration: Duration::from_secs(secs),
+            ..Default::default()
+        }
+    }
+
+    fn candidate(title: &str, channel: &str, secs: u64) -> Candidate {
+        Candidate {
+            id: "id".to_string(),
+            title: title.to_string(),
+            channel: channel.to_string(),
+            duration: Duration::from_secs(secs),
+        }
+    }
+
+    #[test]
+    fn duration() {
+        let song = song("Song", "Artist", 200);
+        let exact = score(&song, &candidate("Artist - Song", "Uploader", 201));
+        let close = score(&song, &candidate("Artist - Song", "Uploader", 210));
+        let far = score(&song, &candidate("Artist - Song", "Uploader", 600));
+        assert!(exact > close && close > far);
+        // an unknown duration is neither a good nor a bad sign
+        let unknown = score(&song, &candidate("Artist - Song", "Uploader", 0));
+        assert!(exact > unknown && unknown > far);
+    }
+
+    #[test]
+    fn title_similarity() {
+        let song = song("Blue Sky", "Artist", 200);
+        let full = score(&song, &candidate("Artist - Blue Sky", "Uploader", 200));
+        let partial = score(&song, &candidate("Blue", "Uploader", 200));
+        let none = score(&song, &candidate("Something else", "Uploader", 200));
+        assert!(full > partial && partial > none);
+    }
+
+    #[test]
+    fn channel_bonus() {
+        let song = song("Song", "Artist", 200);
+        let plain = score(&song, &candidate("Song", "Uploader", 200));
+        let topic = score(&song, &candidate("Song", "Artist - Topic", 200));
+        let official = score(&song, &candidate("Song (Official Audio)", "Uploader", 200));
+        let vevo = score(&song, &candidate("Song", "ArtistVEVO", 200));
+        assert!(topic > vevo && vevo > official && official > plain);
+    }
+
+    #[test]
+    fn noise_penalties() {
+        let studio = song("Song", "Artist", 200);
+        let expected = score(&studio, &candidate("Artist - Song", "Uploader", 200));
+        for noisy in ["Song (Live)", "Song cover", "Song 1 hour loop"] {
+            let title = format!("Artist - {}", noisy);
+            assert!(expected > score(&studio, &candidate(&title, "Uploader", 200)));
+        }
+        // no penalty when the song itself is a live version
+        let live = song("Song (Live)", "Artist", 200);
+        let live_score = score(&live, &candidate("Artist - Song (Live)", "Uploader", 200));
+        let studio_score = score(&live, &candidate("Artist - Song", "Uploader", 200));
+        assert!(live_score > studio_score);
+    }
+}
@@ -1,6 +1,12 @@
-use crate::db;
-use music_server::request::{Answer, AnswerType, ErrorType, ObjRequest, Request, RequestType};
+use crate::config::UserConfig;
+use crate::{access, auth, config, db, events};
+use music_server::request::{
+    Answer, AnswerType, AuthRequest, ErrorType, EventType, ObjRequest, Request, RequestType,
+    SourceStatus,
+};
 pub use async_trait::async_trait;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
 use RequestType::*;
 pub use music_server::source_types::*;
 pub mod spotify;
@@ -8,6 +14,42 @@ pub mod youtube;
 
 pub type SourceResult<T> = Result<T, SourceError>;
 
+/// Names of the available sources
+pub const SOURCES: [&str; 2] = ["Youtube", "Spotify"];
+/// Tag of the songs that are podcast episodes, whose url is where their audio is
+pub const PODCAST_TAG: &str = "podcast";
+
+/// Creates the source called `name`, ignoring the case
+pub async fn new_source(
+    name: &str,
+    user: UserConfig,
+    in_channel: broadcast::Receiver<Request>,
+    out_channel: mpsc::Sender<Answer>,
+) -> Option<Box<dyn Source>> {
+    match name.to_lowercase().as_str() {
+        "youtube" => youtube::Client::new("Youtube", user, in_channel, out_channel)
+            .await
+            .ok()
+            .map(|client| Box::new(client) as Box<dyn Source>),
+        "spotify" => Some(Box::new(
+            spotify::Client::new("Spotify", user, in_channel, out_channel).await,
+        )),
+        _ => None,
+    }
+}
+
+/// A playlist whose content changed since the last sync, along with the songs added to it
+pub type ChangedPlaylist = (Box<dyn PlaylistTrait>, Vec<Song>);
+
+/// Songs of `songs` that are not in `previous`
+pub fn new_songs(songs: &[Song], previous: &[Song]) -> Vec<Song> {
+    songs
+        .iter()
+        .filter(|s| !previous.iter().any(|p| p.id == s.id))
+        .cloned()
+        .collect()
+}
+
 pub trait SongTrait {
     fn to_song(&self) -> Song;
 }
@@ -17,22 +59,104 @@ pub trait PlaylistTrait: Sync + Send {
     fn to_playlist(&self) -> Playlist;
     fn get_id(&self) -> String;
     fn get_source(&self) -> String;
+    /// Name of the user whose library holds the playlist
+    fn get_user(&self) -> String;
     async fn get_songs(&mut self) -> Vec<Song>;
     async fn load_from_db(&self) -> Playlist {
-        db::load_playlist(&self.get_id(), &self.get_source()).unwrap()
+        db::load_playlist(&self.get_id(), &self.get_source(), &self.get_user()).unwrap()
     }
 }
 
 #[async_trait]
 pub trait Source: Sync + Send {
     fn get_name(&self) -> String;
+    /// Name of the user whose library the source gives access to
+    fn get_user(&self) -> String;
     fn get_number_of_playlist(&self) -> usize;
     async fn get_all_playlists(&mut self) -> Vec<Playlist>;
     async fn get_playlist_by_id(&mut self, id: &str) -> SourceResult<Box<dyn PlaylistTrait>>;
     async fn init(&mut self) -> ();
     async fn send(&self, data: Answer) -> ();
-    async fn listen(&mut self) -> ();
-    async fn download_songs(&self, songs: &[Song], playlist_title: String);
+    /// The next request sent to the sources, `None` once the connection is closed
+    async fn receive(&mut self) -> Option<Request>;
+    /// Downloads `songs` in the background
+    async fn download_songs(&self, songs: &[Song], playlist_title: String) -> JoinHandle<()>;
+    /// Makes sure the source has a valid token, running the authentication flow if needed.
+    /// The flow waits for the user in the background, the returned task ends with it.
+    async fn authenticate(&mut self) -> JoinHandle<()>;
+    /// Starts an authentication flow, returns the url the user must visit.
+    /// Sources whose flow is started by their api client return `None`.
+    async fn begin_auth(&mut self) -> Option<String> {
+        None
+    }
+    /// Fetches the playlists metadata again and reloads the ones whose etag changed
+    async fn refresh(&mut self) -> Vec<ChangedPlaylist>;
+
+    /// Refreshes the playlists and notifies every connection of the user,
+    /// returns the playlists that changed
+    async fn sync(&mut self) -> Vec<Playlist> {
+        println!("Syncing {}", self.get_name());
+        let (user, name) = (self.get_user(), self.get_name());
+        events::publish(&user, &name, EventType::SourceStatus(SourceStatus::Syncing));
+        // the pins of the user syncing, the other users have their own
+        let pinned = config::get_config()
+            .user(&user)
+            .map(|user| user.pinned_playlists)
+            .unwrap_or_default();
+        let mut changed = vec![];
+        for (mut playlist, added) in self.refresh().await {
+            let songs = playlist.get_songs().await;
+            let info = playlist.to_playlist();
+            if !added.is_empty() && pinned.contains(&playlist.get_id()) {
+                self.download_songs(&added, info.title.clone()).await;
+            }
+            changed.push(info.clone());
+            events::publish(&user, &name, EventType::PlaylistUpdated(info, songs));
+        }
+        events::publish(&user, &name, EventType::SourceStatus(SourceStatus::Ready));
+        changed
+    }
+
+    async fn listen(&mut self) {
+        println!("Start listening");
+        while let Some(request) = self.receive().await {
+            self.handle_request(request).await;
+        }
+    }
+
+    /// Authenticates and loads the source, then answers its requests until the connection
+    /// is closed. The requests received while the user logs in are answered once the source
+    /// is ready, rather than left in the channel until they are lost.
+    async fn run(&mut self) {
+        self.send_with_name(AnswerType::Event(EventType::SourceStatus(
+            SourceStatus::Loading,
+        )))
+        .await;
+        let mut authenticating = self.authenticate().await;
+        let mut waiting = vec![];
+        loop {
+            tokio::select! {
+                _ = &mut authenticating => break,
+                request = self.receive() => match request {
+                    // the clients learn about the source without waiting for the user
+                    Some(request) if matches!(request.ty, GetAll(ObjRequest::ClientList)) => {
+                        self.handle_request(request).await
+                    }
+                    Some(request) => waiting.push(request),
+                    None => return,
+                },
+            }
+        }
+        self.init().await;
+        self.send_with_name(AnswerType::Event(EventType::SourceStatus(
+            SourceStatus::Ready,
+        )))
+        .await;
+        for request in waiting {
+            self.handle_request(request).await;
+        }
+        self.listen().await;
+    }
 
     async fn send_with_name(&self, data: AnswerType) {
         self.send(Answer::new(self.get_name(), data)).await
@@ -40,6 +164,10 @@ pub trait Source: Sync + Send {
 
     async fn handle_request(&mut self, request: Request) {
         if request.client == self.get_name() || request.client == "all" {
+            if !request.allowed() {
+                self.send_with_name(access::denied(&request)).await;
+                return;
+            }
             match request.ty {
                 GetAll(ObjRequest::PlaylistList) => {
                     let playlists = self.get_all_playlists().await;
@@ -74,6 +202,60 @@ pub trait Source: Sync + Send {
                     }
                 }
 
+                Set(ObjRequest::SongMatch(song_id, video_id)) => {
+                    let name = self.get_name();
+                    let answer = match db::set_song_match(&song_id, &name, &video_id, true) {
+                        Ok(_) => {
+                            // the previously downloaded file came from the wrong video
+                            let _ = db::reset_downloaded(&song_id, &name);
+                            AnswerType::Message(format!("{} now matches {}", song_id, video_id))
+                        }
+                        Err(err) => AnswerType::Message(format!("Could not set match: {}", err)),
+                    };
+                    self.send_with_name(answer).await;
+                }
+
+                Auth(AuthRequest::Begin) => {
+                    let answer = match self.begin_auth().await {
+                        Some(url) => AnswerType::AuthUrl(url),
+                        None => {
+                            AnswerType::AuthStatus(auth::status(&self.get_user(), &self.get_name()))
+                        }
+                    };
+                    self.send_with_name(answer).await;
+                }
+
+                Search(query) => {
+                    let mut results = vec![];
+                    for playlist in self.get_all_playlists().await {
+                        if let Ok(mut playlist) = self.get_playlist_by_id(&playlist.id).await {
+                            let info = playlist.to_playlist();
+                            results.extend(
+                                playlist
+                                    .get_songs()
+                                    .await
+                                    .into_iter()
+                                    .filter(|s| s.matches(&query))
+                                    .map(|s| (info.clone(), s)),
+                            );
+                        }
+                    }
+                    self.send_with_name(AnswerType::SearchResults(results)).await;
+                }
+
+                Played(id) => {
+                    if let Err(err) = db::add_history(&self.get_user(), &self.get_name(), &id) {
+                        let answer = AnswerType::Message(format!("Could not save play: {}", err));
+                        self.send_with_name(answer).await;
+                    }
+                }
+
+                GetAll(ObjRequest::History) => {
+                    let history = db::get_history(&self.get_user(), &self.get_name(), 100)
+                        .unwrap_or_default();
+                    self.send_with_name(AnswerType::History(history)).await;
+                }
+
                 GetAll(ObjRequest::ClientList) => {
                     let answer = AnswerType::Client(self.get_name());
                     self.send_with_name(answer).await;
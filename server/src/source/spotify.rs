@@ -1,32 +1,98 @@
 #![warn(clippy::unwrap_used)]
+use std::collections::HashSet;
 use std::path::Path;
 
 use async_trait::async_trait;
-use futures::TryStreamExt;
+use futures::{StreamExt, TryStreamExt};
 use rspotify::clients::{BaseClient, OAuthClient};
-use rspotify::model::{PlayableItem, PlaylistId, SimplifiedPlaylist};
+use rspotify::model::{
+    AlbumId, FullEpisode, FullTrack, PlayableItem, PlaylistId, SavedAlbum, SimplifiedArtist,
+};
+use rspotify::ClientResult;
 use tokio::sync::broadcast::error::RecvError;
 
-use crate::{config, db, utils};
-use music_server::request::{send_request, Answer, AnswerType, Request, RequestType};
+use crate::auth::{self, PendingCode};
+use crate::config::UserConfig;
+use crate::{db, source, utils};
+use music_server::request::{Answer, AuthStatus, Request};
 
 use super::Song;
-use super::{Playlist, PlaylistTrait, Song as SpotifySong, Source, SourceError, SourceResult};
+use super::{
+    ChangedPlaylist, Playlist, PlaylistTrait, Song as SpotifySong, Source, SourceError,
+    SourceResult,
+};
 use rspotify::{self, AuthCodeSpotify};
 use tokio::sync::broadcast::Receiver;
-use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
 
 const MAX_RESULT: u32 = 50;
+/// Id under which the saved tracks are stored
+const LIKED_ID: &str = "liked";
+
+/// What a `SpotifyPlaylist` is built from
+#[derive(Clone, Debug, Default)]
+enum PlaylistKind {
+    #[default]
+    Playlist,
+    /// The user's saved tracks
+    Liked,
+    /// A saved album
+    Album,
+}
 
 #[derive(Clone, Debug, Default)]
 struct SpotifyPlaylist {
     playlist: Playlist,
+    kind: PlaylistKind,
     songs: Vec<SpotifySong>,
     id: String,
     etag: String, // used to check if playlist has changed
     is_loaded: bool,
     client: AuthCodeSpotify,
     source: String,
+    user: String,
+}
+
+fn make_song(
+    title: String,
+    artists: &[SimplifiedArtist],
+    id: Option<String>,
+    duration: std::time::Duration,
+) -> Song {
+    Song::new(
+        title,
+        artists.iter().map(|artist| artist.name.clone()).collect(),
+        Default::default(),
+        id.unwrap_or_default(),
+        duration,
+        Default::default(),
+    )
+}
+
+fn track_to_song(track: FullTrack) -> Song {
+    make_song(
+        track.name,
+        &track.artists,
+        track.id.map(|id| id.to_string()),
+        track.duration.to_std().unwrap_or_default(),
+    )
+}
+
+/// The episodes are played from their preview, or their page, since youtube does not have them
+fn episode_to_song(episode: FullEpisode) -> Song {
+    let url = episode
+        .audio_preview_url
+        .or_else(|| episode.external_urls.get("spotify").cloned())
+        .unwrap_or_default();
+    Song::new(
+        episode.name,
+        vec![episode.show.name, episode.show.publisher],
+        vec![source::PODCAST_TAG.to_string()],
+        episode.id.to_string(),
+        episode.duration.to_std().unwrap_or_default(),
+        url,
+    )
 }
 
 async fn load_all_songs(client: &AuthCodeSpotify, id: PlaylistId<'_>) -> Vec<Song> {
@@ -37,48 +103,128 @@ async fn load_all_songs(client: &AuthCodeSpotify, id: PlaylistId<'_>) -> Vec<Son
             break;
         }
         let items = page.unwrap_or_default();
-        let tracks = items.track.unwrap();
-        match tracks {
-            PlayableItem::Episode(_) => (),
-            PlayableItem::Track(fulltrack) => songs.push(Song::new(
-                fulltrack.name,
-                fulltrack
-                    .artists
-                    .iter()
-                    .cloned()
-                    .map(|artist| artist.name)
-                    .collect(),
-                Default::default(),
-                if fulltrack.id.is_some() {
-                    fulltrack.id.unwrap().to_string()
-                } else {
-                    "".to_string()
-                },
-                fulltrack.duration.to_std().unwrap(),
-                Default::default(),
-            )),
+        match items.track {
+            // local files and unavailable tracks
+            None => (),
+            Some(PlayableItem::Episode(episode)) => songs.push(episode_to_song(episode)),
+            Some(PlayableItem::Track(fulltrack)) => songs.push(track_to_song(fulltrack)),
         }
     }
     songs
 }
 
+async fn load_saved_songs(client: &AuthCodeSpotify) -> Vec<Song> {
+    let mut saved_tracks = client.current_user_saved_tracks(None);
+    let mut songs = vec![];
+    while let Ok(Some(saved)) = saved_tracks.try_next().await {
+        songs.push(track_to_song(saved.track));
+    }
+    songs
+}
+
+async fn load_album_songs(client: &AuthCodeSpotify, id: AlbumId<'_>) -> Vec<Song> {
+    let mut album_tracks = client.album_track(id);
+    let mut songs = vec![];
+    while let Ok(Some(track)) = album_tracks.try_next().await {
+        songs.push(make_song(
+            track.name,
+            &track.artists,
+            track.id.map(|id| id.to_string()),
+            track.duration.to_std().unwrap_or_default(),
+        ));
+    }
+    songs
+}
+
 impl SpotifyPlaylist {
-    pub async fn new(id: &str, client: AuthCodeSpotify, source: String) -> Self {
-        let id = PlaylistId::from_uri(id).unwrap();
-        let playlist = client.playlist(id, None, None).await.unwrap();
-        SpotifyPlaylist {
+    pub async fn new(
+        id: PlaylistId<'_>,
+        client: AuthCodeSpotify,
+        source: String,
+        user: String,
+    ) -> ClientResult<Self> {
+        let playlist = client.playlist(id, None, None).await?;
+        Ok(SpotifyPlaylist {
             playlist: Playlist {
                 title: playlist.name,
                 tags: Default::default(),
                 id: playlist.id.to_string(),
                 size: playlist.tracks.total,
             },
+            kind: PlaylistKind::Playlist,
             songs: Vec::with_capacity(playlist.tracks.total as usize),
             id: playlist.id.to_string(),
             etag: playlist.snapshot_id,
             is_loaded: false,
             client,
             source,
+            user,
+        })
+    }
+
+    /// The user's saved tracks, exposed like youtube's "Liked Videos"
+    pub async fn liked(client: AuthCodeSpotify, source: String, user: String) -> Self {
+        // there is no snapshot id for saved tracks, the number of tracks and
+        // the date of the last addition are used instead
+        let (size, etag) = match client
+            .current_user_saved_tracks_manual(None, Some(1), None)
+            .await
+        {
+            Ok(page) => {
+                let last_added = page
+                    .items
+                    .first()
+                    .map(|saved| saved.added_at.to_string())
+                    .unwrap_or_default();
+                (page.total, format!("{}-{}", page.total, last_added))
+            }
+            Err(err) => {
+                println!("Cannot fetch liked songs: {}", err);
+                (0, Default::default())
+            }
+        };
+        SpotifyPlaylist {
+            playlist: Playlist {
+                title: "Liked Songs".to_string(),
+                tags: Default::default(),
+                id: LIKED_ID.to_string(),
+                size,
+            },
+            kind: PlaylistKind::Liked,
+            songs: Vec::with_capacity(size as usize),
+            id: LIKED_ID.to_string(),
+            etag,
+            is_loaded: false,
+            client,
+            source,
+            user,
+        }
+    }
+
+    pub fn from_album(
+        saved: SavedAlbum,
+        client: AuthCodeSpotify,
+        source: String,
+        user: String,
+    ) -> Self {
+        let album = saved.album;
+        let artists: Vec<String> = album.artists.into_iter().map(|a| a.name).collect();
+        SpotifyPlaylist {
+            playlist: Playlist {
+                title: format!("{} - {}", artists.join(", "), album.name),
+                tags: vec!["album".to_string()],
+                id: album.id.to_string(),
+                size: album.tracks.total,
+            },
+            kind: PlaylistKind::Album,
+            songs: Vec::with_capacity(album.tracks.total as usize),
+            id: album.id.to_string(),
+            // the content of an album does not change
+            etag: album.id.to_string(),
+            is_loaded: false,
+            client,
+            source,
+            user,
         }
     }
 
@@ -86,17 +232,39 @@ impl SpotifyPlaylist {
         if self.is_loaded || self.load_from_db() {
             return;
         };
-        let id = PlaylistId::from_uri(&self.id).unwrap();
-        self.songs = load_all_songs(&self.client, id).await;
-        let _ = db::add_playlist(&self.source, self.to_playlist(), &self.songs, &self.etag);
+        let songs = match self.kind {
+            PlaylistKind::Playlist => match PlaylistId::from_uri(&self.id) {
+                Ok(id) => Ok(load_all_songs(&self.client, id).await),
+                Err(err) => Err(err),
+            },
+            PlaylistKind::Liked => Ok(load_saved_songs(&self.client).await),
+            PlaylistKind::Album => match AlbumId::from_uri(&self.id) {
+                Ok(id) => Ok(load_album_songs(&self.client, id).await),
+                Err(err) => Err(err),
+            },
+        };
+        self.songs = match songs {
+            Ok(songs) => songs,
+            Err(err) => {
+                println!("Cannot load {}: {}", self.id, err);
+                return;
+            }
+        };
+        let _ = db::add_playlist(
+            &self.source,
+            &self.user,
+            self.to_playlist(),
+            &self.songs,
+            &self.etag,
+        );
     }
     fn load_from_db(&mut self) -> bool {
-        let db_bool = db::playlist_needs_update(&self.id, &self.source, &self.etag);
+        let db_bool = db::playlist_needs_update(&self.id, &self.source, &self.user, &self.etag);
         if db_bool && !self.is_loaded {
-            let playlist = db::load_playlist(&self.id, &self.source).unwrap();
+            let playlist = db::load_playlist(&self.id, &self.source, &self.user).unwrap();
             self.playlist.title = playlist.title;
             self.playlist.size = playlist.size;
-            self.songs = db::get_playlist_songs(&self.id, &self.source).unwrap();
+            self.songs = db::get_playlist_songs(&self.id, &self.source, &self.user).unwrap();
             self.is_loaded = true;
         }
         db_bool
@@ -113,6 +281,10 @@ impl PlaylistTrait for SpotifyPlaylist {
         self.source.clone()
     }
 
+    fn get_user(&self) -> String {
+        self.user.clone()
+    }
+
     async fn get_songs(&mut self) -> Vec<Song> {
         if !self.is_loaded {
             self.load_all().await
@@ -125,16 +297,10 @@ impl PlaylistTrait for SpotifyPlaylist {
     }
 }
 
-async fn convert_playlist(
-    playlist: SimplifiedPlaylist,
-    client: AuthCodeSpotify,
-) -> SpotifyPlaylist {
-    SpotifyPlaylist::new(&playlist.id.to_string(), client, "Spotify".to_string()).await
-}
-
 pub struct Client {
     client: rspotify::AuthCodeSpotify,
     pub name: String,
+    user: UserConfig,
     playlists: Vec<SpotifyPlaylist>,
     in_channel: Receiver<Request>,
     out_channel: Sender<Answer>,
@@ -145,15 +311,22 @@ pub struct Client {
 impl Client {
     pub async fn new(
         name: &str,
+        user: UserConfig,
         in_channel: Receiver<Request>,
         out_channel: Sender<Answer>,
     ) -> Client {
-        let config = config::get_config();
-        let credentials = rspotify::Credentials::new(&config.spotify_id, &config.spotify_secret);
-        let secrets = config.secrets_location;
+        let credentials = rspotify::Credentials::new(&user.spotify_id, &user.spotify_secret);
+        let secrets = &user.secrets_location;
+        if let Err(err) = std::fs::create_dir_all(secrets) {
+            println!("Cannot create {}: {}", secrets, err);
+        }
         let oauth = rspotify::OAuth {
             redirect_uri: "https://localhost:8888/callback".to_string(),
-            scopes: rspotify::scopes!("user-read-recently-played"),
+            scopes: rspotify::scopes!(
+                "user-read-recently-played",
+                "user-library-read",
+                "playlist-read-private"
+            ),
             ..Default::default()
         };
         let client_config: rspotify::Config = rspotify::Config {
@@ -167,6 +340,7 @@ impl Client {
         Client {
             client,
             name: name.to_string(),
+            user,
             playlists: Default::default(),
             in_channel,
             out_channel,
@@ -175,102 +349,139 @@ impl Client {
         }
     }
 
-    async fn reauth(&mut self) {
-        let url = self.client.get_authorize_url(false).unwrap_or_default();
-        send_request(
-            self.out_channel.clone(),
-            Answer::new("Spotify".to_string(), AnswerType::Message(url)),
-        )
-        .await;
-        loop {
-            match self.in_channel.recv().await {
-                Ok(request) => {
-                    if request.client == self.name {
-                        match request.ty {
-                            RequestType::Message(url) => {
-                                let code =
-                                    self.client.parse_response_code(&url).unwrap_or_default();
-                                self.client.request_token(&code).await;
-                                break;
-                            }
-                            _ => continue,
-                        }
-                    }
-                }
-                Err(RecvError::Closed) => {
-                    break;
-                },
-                _ => continue
-            };
+    pub async fn fetch_all_playlists(&mut self) -> ClientResult<()> {
+        if self.playlist_loaded {
+            return Ok(());
         }
+        let playlists: Vec<_> = self.client.current_user_playlists().try_collect().await?;
+        let (client, name, user) = (&self.client, &self.name, &self.user.name);
+        let mut res: Vec<SpotifyPlaylist> = futures::stream::iter(playlists)
+            .map(|playlist| {
+                SpotifyPlaylist::new(playlist.id, client.clone(), name.clone(), user.clone())
+            })
+            .buffer_unordered(10)
+            .try_collect()
+            .await?;
+        res.push(
+            SpotifyPlaylist::liked(
+                self.client.clone(),
+                self.name.clone(),
+                self.user.name.clone(),
+            )
+            .await,
+        );
+        res.extend(self.fetch_saved_albums().await);
+        self.playlists = res;
+        self.playlist_loaded = true;
+        Ok(())
     }
-    pub async fn authenticate(&mut self) {
-        match self.client.read_token_cache(true).await {
-            Ok(Some(new_token)) => {
-                let expired = new_token.is_expired();
-
-                // Load token into client regardless of whether it's expired o
-                // not, since it will be refreshed later anyway.
-                *self.client.get_token().lock().await.unwrap() = Some(new_token);
-
-                if expired {
-                    // Ensure that we actually got a token from the refetch
-                    let token = self.client.refetch_token().await;
-                    match token {
-                        Err(err) => println!("Error: {}", err),
-                        Ok(val) => match val {
-                            Some(refreshed_token) => {
-                                *self.client.get_token().lock().await.unwrap() =
-                                    Some(refreshed_token)
-                            }
-                            // If not, prompt the user for it
-                            None => {
-                                self.reauth().await;
-                            }
-                        },
-                    }
-                }
-            }
-            // Otherwise following the usual procedure to get the token.
-            _ => {
-                println!("no token found");
-                self.reauth().await;
+
+    async fn fetch_saved_albums(&self) -> Vec<SpotifyPlaylist> {
+        let mut saved_albums = self.client.current_user_saved_albums(None);
+        let mut albums = vec![];
+        while let Ok(Some(saved)) = saved_albums.try_next().await {
+            albums.push(SpotifyPlaylist::from_album(
+                saved,
+                self.client.clone(),
+                self.name.clone(),
+                self.user.name.clone(),
+            ));
+        }
+        albums
+    }
+}
+
+/// Exchanges the code sent by the user for a token.
+/// The token is shared between the clones of a client, so this can run in the background.
+async fn finish_auth(client: AuthCodeSpotify, user: String, name: String, code: PendingCode) {
+    let input = match code.wait().await {
+        Ok(input) => input,
+        Err(err) => {
+            auth::set_status(&user, &name, AuthStatus::Failed(err));
+            return;
+        }
+    };
+    let code = client
+        .parse_response_code(&input)
+        .unwrap_or_else(|| auth::parse_code(&input));
+    match client.request_token(&code).await {
+        Ok(_) => {
+            auth::set_status(&user, &name, AuthStatus::Authenticated);
+            if let Err(err) = client.write_token_cache().await {
+                println!("{}", err);
             }
         }
+        Err(err) => auth::set_status(&user, &name, AuthStatus::Failed(err.to_string())),
+    }
+}
 
-        match self.client.write_token_cache().await {
-            Ok(_) => (),
-            Err(e) => println!("{}", e),
+/// Waits for the user to authenticate through an `AuthRequest::Complete` request
+async fn reauth(client: &AuthCodeSpotify, user: &str, name: &str) {
+    let url = client.get_authorize_url(false).unwrap_or_default();
+    let code = auth::request_code(user, name, url);
+    if code.started {
+        finish_auth(client.clone(), user.to_string(), name.to_string(), code).await;
+        return;
+    }
+    // the flow started with `begin_auth` exchanges the code, its token is read once it is saved
+    if matches!(auth::outcome(user, name).await, AuthStatus::Authenticated) {
+        if let Ok(Some(token)) = client.read_token_cache(true).await {
+            *client.get_token().lock().await.unwrap() = Some(token);
         }
     }
+}
 
-    pub async fn fetch_all_playlists(&mut self) {
-        if self.playlist_loaded {
-            return;
+/// Whether a token granted `scopes` has every scope the client asks for,
+/// which a token cached before a scope was added does not
+fn has_scopes(client: &AuthCodeSpotify, scopes: &HashSet<String>) -> bool {
+    client.get_oauth().scopes.is_subset(scopes)
+}
+
+/// Loads the cached token, refreshing it or asking the user for a new one when needed
+async fn load_token(client: &AuthCodeSpotify, user: &str, name: &str) {
+    match client.read_token_cache(true).await {
+        Ok(Some(new_token)) if !has_scopes(client, &new_token.scopes) => {
+            println!("The cached token misses scopes, asking for a new one");
+            reauth(client, user, name).await;
         }
-        let (tx, mut rx) = mpsc::channel(32);
-        let playlists = self.client.current_user_playlists();
-        let client = &self.client;
-        playlists
-            .try_for_each_concurrent(10, |playlist| async {
-                tx.send(convert_playlist(playlist, client.clone()).await)
-                    .await;
-                Ok(())
-            })
-            .await
-            .unwrap();
-        drop(tx);
+        Ok(Some(new_token)) => {
+            let expired = new_token.is_expired();
 
-        let res = tokio::spawn(async move {
-            let mut playlists = vec![];
-            while let Some(playlist) = rx.recv().await {
-                playlists.push(playlist)
+            // Load token into client regardless of whether it's expired o
+            // not, since it will be refreshed later anyway.
+            *client.get_token().lock().await.unwrap() = Some(new_token);
+
+            if !expired {
+                auth::set_status(user, name, AuthStatus::Authenticated);
+            } else {
+                // Ensure that we actually got a token from the refetch
+                let token = client.refetch_token().await;
+                match token {
+                    Err(err) => {
+                        println!("Error: {}", err);
+                        auth::set_status(user, name, AuthStatus::Failed(err.to_string()));
+                    }
+                    Ok(val) => match val {
+                        Some(refreshed_token) => {
+                            *client.get_token().lock().await.unwrap() = Some(refreshed_token);
+                            auth::set_status(user, name, AuthStatus::Authenticated);
+                        }
+                        // If not, prompt the user for it
+                        None => reauth(client, user, name).await,
+                    },
+                }
             }
-            playlists
-        });
-        let res = res.await.unwrap();
-        self.playlists = res;
-        self.playlist_loaded = true;
+        }
+        // Otherwise following the usual procedure to get the token.
+        _ => {
+            println!("no token found");
+            reauth(client, user, name).await;
+        }
+    }
+
+    match client.write_token_cache().await {
+        Ok(_) => (),
+        Err(e) => println!("{}", e),
     }
 }
 
@@ -279,6 +490,9 @@ impl Source for Client {
     fn get_name(&self) -> String {
         self.name.clone()
     }
+    fn get_user(&self) -> String {
+        self.user.name.clone()
+    }
     fn get_number_of_playlist(&self) -> usize {
         self.playlists.len()
     }
@@ -303,27 +517,92 @@ impl Source for Client {
             None => Err(SourceError::PlaylistNotFound),
         }
     }
+    async fn refresh(&mut self) -> Vec<ChangedPlaylist> {
+        let previous = std::mem::take(&mut self.playlists);
+        self.playlist_loaded = false;
+        if let Err(err) = self.fetch_all_playlists().await {
+            println!("Cannot fetch the playlists: {}", err);
+            self.playlists = previous;
+            self.playlist_loaded = true;
+            return vec![];
+        }
+        let mut changed: Vec<ChangedPlaylist> = vec![];
+        for playlist in self.playlists.iter_mut() {
+            if db::playlist_needs_update(
+                &playlist.id,
+                &playlist.source,
+                &playlist.user,
+                &playlist.etag,
+            ) {
+                // the stored etag is still valid, keep what was already loaded
+                // unless another connection synced it in the meantime
+                if let Some(old) = previous
+                    .iter()
+                    .find(|p| p.id == playlist.id && p.etag == playlist.etag)
+                {
+                    *playlist = old.clone();
+                }
+                continue;
+            }
+            let old_songs = db::get_playlist_songs(&playlist.id, &playlist.source, &playlist.user)
+                .unwrap_or_default();
+            playlist.load_all().await;
+            let added = source::new_songs(&playlist.songs, &old_songs);
+            let playlist: Box<dyn PlaylistTrait> = Box::new(playlist.clone());
+            changed.push((playlist, added));
+        }
+        changed
+    }
+    async fn authenticate(&mut self) -> JoinHandle<()> {
+        let client = self.client.clone();
+        let user = self.user.name.clone();
+        let name = self.name.clone();
+        tokio::spawn(async move {
+            // the other connections of the user wait for the token instead of asking for one
+            let _flow = auth::lock(&user, &name).await;
+            load_token(&client, &user, &name).await
+        })
+    }
+    async fn begin_auth(&mut self) -> Option<String> {
+        let url = self.client.get_authorize_url(false).unwrap_or_default();
+        let code = auth::request_code(&self.user.name, &self.name, url.clone());
+        // a flow already waiting for the user is finished by whoever started it
+        if !code.started {
+            return auth::pending_url(&self.user.name, &self.name).or(Some(url));
+        }
+        tokio::spawn(finish_auth(
+            self.client.clone(),
+            self.user.name.clone(),
+            self.name.clone(),
+            code,
+        ));
+        Some(url)
+    }
     async fn init(&mut self) -> () {
-        self.fetch_all_playlists().await;
+        if let Err(err) = self.fetch_all_playlists().await {
+            println!("Cannot fetch the playlists: {}", err);
+        }
         self.get_all_playlists().await;
     }
-    async fn listen(&mut self) {
-        println!("Start listening");
+    async fn receive(&mut self) -> Option<Request> {
         loop {
-            let _ = match self.in_channel.recv().await {
-                Ok(msg) => self.handle_request(msg).await,
-                Err(RecvError::Closed) => {
-                    break;
-                },
-                _ => continue
-            };
+            match self.in_channel.recv().await {
+                Ok(request) => return Some(request),
+                Err(RecvError::Closed) => return None,
+                Err(RecvError::Lagged(_)) => continue,
+            }
         }
     }
-    async fn download_songs(&self, songs: &[SpotifySong], playlist_title: String) {
+    async fn download_songs(
+        &self,
+        songs: &[SpotifySong],
+        playlist_title: String,
+    ) -> JoinHandle<()> {
         let songs = songs.to_vec();
         let name = self.name.clone();
-        tokio::spawn(
-            async move { utils::download_spotify_playlist(songs, name, playlist_title).await },
-        );
+        let user = self.user.name.clone();
+        tokio::spawn(async move {
+            utils::download_spotify_playlist(songs, name, user, playlist_title).await
+        })
     }
 }
@@ -1,29 +1,42 @@
 #![warn(clippy::unwrap_used)]
 extern crate google_youtube3 as youtube3;
-use music_server::request::{send_request, Answer, AnswerType, Request};
+use music_server::request::{Answer, AuthStatus, Request};
 use tokio::sync::broadcast::error::RecvError;
 use super::Song as YoutubeSong;
-use super::{Playlist, Song, Source, SourceError, SourceResult};
+use super::{ChangedPlaylist, Playlist, Song, Source, SourceError, SourceResult};
+use crate::title_parser::TitleParser;
 use crate::utils::parse_duration;
-use crate::{db, utils};
+use crate::{auth, db, source, utils};
 use async_trait::async_trait;
 use futures::stream::StreamExt;
 use google_youtube3::hyper::client::HttpConnector;
 use google_youtube3::hyper_rustls::HttpsConnector;
+use google_youtube3::oauth2::authenticator::Authenticator;
 use google_youtube3::oauth2::authenticator_delegate::InstalledFlowDelegate;
+use std::collections::HashMap;
 use std::default::Default;
 use std::future::Future;
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
 use youtube3::api::Playlist as YtPlaylist;
 use youtube3::api::{PlaylistItem, PlaylistListResponse};
 use youtube3::{hyper, hyper_rustls, oauth2, YouTube};
 
 use super::PlaylistTrait;
-use crate::config;
+use crate::config::{self, UserConfig};
 
 const MAX_RESULT: u32 = 50;
+const READONLY_SCOPE: &str = "https://www.googleapis.com/auth/youtube.readonly";
+
+type YoutubeAuth = Authenticator<HttpsConnector<HttpConnector>>;
+
+/// Authenticator of each user, shared by their connections so that the token obtained
+/// by one of them serves the others
+static AUTHENTICATORS: OnceLock<Mutex<HashMap<String, YoutubeAuth>>> = OnceLock::new();
 
 #[derive(Clone, Default)]
 struct YoutubePlaylist {
@@ -35,6 +48,7 @@ struct YoutubePlaylist {
     hub: Option<YouTube<HttpsConnector<HttpConnector>>>,
     is_loaded: bool,
     source: String,
+    user: String,
 }
 
 impl YoutubePlaylist {
@@ -46,6 +60,7 @@ impl YoutubePlaylist {
         etag: String,
         hub: YouTube<HttpsConnector<HttpConnector>>,
         source: String,
+        user: String,
     ) -> Self {
         YoutubePlaylist {
             playlist: Playlist {
@@ -61,6 +76,7 @@ impl YoutubePlaylist {
             hub: Some(hub),
             is_loaded: false,
             source,
+            user,
         }
     }
 
@@ -75,7 +91,11 @@ impl YoutubePlaylist {
         let result = request.doit().await.unwrap_or_default();
         let (_, result) = result;
         let items = result.items.unwrap_or_default();
-        let songs: Vec<YoutubeSong> = items.into_iter().flat_map(song_from_item).collect();
+        let parser = TitleParser::new(config::get_config().title_parser);
+        let songs: Vec<YoutubeSong> = items
+            .into_iter()
+            .flat_map(|item| song_from_item(item, &parser))
+            .collect();
         for s in songs.into_iter() {
             self.songs.push(s)
         }
@@ -131,7 +151,13 @@ impl YoutubePlaylist {
             }
         }
         self.fetch_songs_data().await;
-        let _ = db::add_playlist(&self.source, self.to_playlist(), &self.songs, &self.etag);
+        let _ = db::add_playlist(
+            &self.source,
+            &self.user,
+            self.to_playlist(),
+            &self.songs,
+            &self.etag,
+        );
     }
 
     async fn load_all_clone(mut self) -> Box<YoutubePlaylist> {
@@ -143,12 +169,14 @@ impl YoutubePlaylist {
         self.is_loaded
     }
     fn load_from_db(&mut self) -> bool {
-        let db_bool = db::playlist_needs_update(&self.id, &self.source, &self.etag);
+        let db_bool = db::playlist_needs_update(&self.id, &self.source, &self.user, &self.etag);
         if db_bool && !self.is_loaded {
-            let playlist = db::load_playlist(&self.id, &self.source).unwrap_or_default();
+            let playlist =
+                db::load_playlist(&self.id, &self.source, &self.user).unwrap_or_default();
             self.playlist.title = playlist.title;
             self.playlist.size = playlist.size;
-            self.songs = db::get_playlist_songs(&self.id, &self.source).unwrap_or_default();
+            self.songs =
+                db::get_playlist_songs(&self.id, &self.source, &self.user).unwrap_or_default();
             self.is_loaded = true;
         }
         db_bool
@@ -164,6 +192,10 @@ impl PlaylistTrait for YoutubePlaylist {
     fn get_source(&self) -> String {
         self.source.clone()
     }
+
+    fn get_user(&self) -> String {
+        self.user.clone()
+    }
     async fn get_songs(&mut self) -> Vec<Song> {
         if !self.is_fully_loaded() {
             self.load_all().await
@@ -177,7 +209,9 @@ impl PlaylistTrait for YoutubePlaylist {
 
 pub struct Client {
     pub hub: YouTube<HttpsConnector<HttpConnector>>,
+    auth: YoutubeAuth,
     pub name: String,
+    user: UserConfig,
     playlists: Vec<YoutubePlaylist>,
     in_channel: Receiver<Request>,
     out_channel: Sender<Answer>,
@@ -188,36 +222,30 @@ pub struct Client {
 impl Client {
     pub async fn new(
         name: &str,
+        user: UserConfig,
         in_channel: Receiver<Request>,
         out_channel: Sender<Answer>,
     ) -> std::result::Result<Self, std::io::Error> {
-        // Get an ApplicationSecret instance by some means. It contains the `client_id` and
-        // `client_secret`, among other things.
-        let secrets_location = config::get_config().secrets_location;
-        let credentials_path = format!("{}/youtube_credentials.json", secrets_location);
-        let token_path = format!("{}/youtube_tokencache.json", secrets_location);
-        let secret = oauth2::read_application_secret(credentials_path).await;
-        let secret = match secret {
-            Err(e) => {
-                println!("Cannot find credentials for youtube client : {}", e);
-                return Err(e);
+        let secrets_location = &user.secrets_location;
+        std::fs::create_dir_all(secrets_location)?;
+        let authenticators = AUTHENTICATORS.get_or_init(Default::default);
+        let shared = authenticators
+            .lock()
+            .expect("poisoned lock")
+            .get(&user.name)
+            .cloned();
+        let auth = match shared {
+            Some(auth) => auth,
+            None => {
+                let auth = build_authenticator(&user).await?;
+                let mut authenticators = authenticators.lock().expect("poisoned lock");
+                // another connection may have built one in the meantime
+                authenticators
+                    .entry(user.name.clone())
+                    .or_insert(auth)
+                    .clone()
             }
-            Ok(secret) => secret,
         };
-        // Instantiate the authenticator. It will choose a suitable authentication flow for you,
-        // unless you replace  `None` with the desired Flow.
-        // Provide your own `AuthenticatorDelegate` to adjust the way it operates and get feedback about
-        // what's going on. You probably want to bring in your own `TokenStorage` to persist tokens and
-        // retrieve them from storage.
-        let auth = oauth2::InstalledFlowAuthenticator::builder(
-            secret,
-            oauth2::InstalledFlowReturnMethod::HTTPRedirect,
-        )
-        .persist_tokens_to_disk(token_path)
-        .flow_delegate(Box::new(CustomFlowDelegate::new(out_channel.clone())))
-        .build()
-        .await
-        .unwrap();
         let hub = YouTube::new(
             hyper::Client::builder().build(
                 hyper_rustls::HttpsConnectorBuilder::new()
@@ -227,11 +255,13 @@ impl Client {
                     .enable_http2()
                     .build(),
             ),
-            auth,
+            auth.clone(),
         );
         Ok(Client {
             hub,
+            auth,
             name: name.to_string(),
+            user,
             playlists: Default::default(),
             in_channel,
             out_channel,
@@ -245,6 +275,9 @@ impl Client {
             liked_videos.playlist.title = "Liked Videos".to_string();
             let mut playlists_list = self.load_all_playlists_mine().await;
             playlists_list.push(liked_videos);
+            if config::get_config().youtube_subscriptions {
+                playlists_list.append(&mut self.load_subscriptions_uploads().await);
+            }
             self.playlists = playlists_list;
             self.playlist_loaded = true;
         }
@@ -265,15 +298,102 @@ impl Client {
     }
 
     async fn load_all_playlists_mine(&self) -> Vec<YoutubePlaylist> {
-        let request = self
-            .hub
-            .playlists()
-            .list(&vec!["snippet".to_string(), "contentDetails".to_string()])
-            .mine(true)
-            .max_results(MAX_RESULT);
-        let result = request.doit().await.unwrap_or_default();
-        let (_, result) = result;
-        convert_playlist_list(result, &self.hub)
+        let mut playlists = vec![];
+        let mut page_token = String::new();
+        loop {
+            let request = self
+                .hub
+                .playlists()
+                .list(&vec!["snippet".to_string(), "contentDetails".to_string()])
+                .mine(true)
+                .max_results(MAX_RESULT)
+                .page_token(&page_token);
+            let result = request.doit().await.unwrap_or_default();
+            let (_, result) = result;
+            let next_page_token = result.next_page_token.clone();
+            playlists.append(&mut convert_playlist_list(
+                result,
+                &self.hub,
+                &self.user.name,
+            ));
+            match next_page_token {
+                Some(token) => page_token = token,
+                None => break,
+            }
+        }
+        playlists
+    }
+
+    /// Returns the uploads playlists of the channels the user is subscribed to
+    async fn load_subscriptions_uploads(&self) -> Vec<YoutubePlaylist> {
+        let mut channels_id = vec![];
+        let mut page_token = String::new();
+        loop {
+            let request = self
+                .hub
+                .subscriptions()
+                .list(&vec!["snippet".to_string()])
+                .mine(true)
+                .max_results(MAX_RESULT)
+                .page_token(&page_token);
+            let result = request.doit().await.unwrap_or_default();
+            let (_, result) = result;
+            for subscription in result.items.unwrap_or_default() {
+                let channel_id = subscription
+                    .snippet
+                    .and_then(|snippet| snippet.resource_id)
+                    .and_then(|resource| resource.channel_id);
+                if let Some(id) = channel_id {
+                    channels_id.push(id);
+                }
+            }
+            match result.next_page_token {
+                Some(token) => page_token = token,
+                None => break,
+            }
+        }
+
+        let mut uploads_id = vec![];
+        for chunk in channels_id.chunks(MAX_RESULT as usize) {
+            let request = self
+                .hub
+                .channels()
+                .list(&vec!["contentDetails".to_string()])
+                .max_results(MAX_RESULT);
+            let request = chunk.iter().fold(request, |req, id| req.add_id(id));
+            let result = request.doit().await.unwrap_or_default();
+            let (_, result) = result;
+            for channel in result.items.unwrap_or_default() {
+                let uploads = channel
+                    .content_details
+                    .and_then(|content| content.related_playlists)
+                    .and_then(|related| related.uploads);
+                if let Some(id) = uploads {
+                    uploads_id.push(id);
+                }
+            }
+        }
+
+        let mut playlists = vec![];
+        for chunk in uploads_id.chunks(MAX_RESULT as usize) {
+            let request = self
+                .hub
+                .playlists()
+                .list(&vec!["snippet".to_string(), "contentDetails".to_string()])
+                .max_results(MAX_RESULT);
+            let request = chunk.iter().fold(request, |req, id| req.add_id(id));
+            let result = request.doit().await.unwrap_or_default();
+            let (_, result) = result;
+            playlists.append(&mut convert_playlist_list(
+                result,
+                &self.hub,
+                &self.user.name,
+            ));
+        }
+        for playlist in playlists.iter_mut() {
+            playlist.playlist.tags.push("subscription".to_string());
+        }
+        playlists
     }
 
     async fn load_playlist_by_id(&self, id: &str) -> YoutubePlaylist {
@@ -289,7 +409,7 @@ impl Client {
                 let result = request.doit().await.unwrap_or_default();
                 let (_, result) = result;
 
-                convert_playlist_list(result, &self.hub)
+                convert_playlist_list(result, &self.hub, &self.user.name)
                     .into_iter()
                     .next()
                     .unwrap()
@@ -297,19 +417,58 @@ impl Client {
         }
     }
 }
+async fn build_authenticator(user: &UserConfig) -> std::io::Result<YoutubeAuth> {
+    // Get an ApplicationSecret instance by some means. It contains the `client_id` and
+    // `client_secret`, among other things.
+    // A user without their own google application uses the top level one.
+    let secrets_location = &user.secrets_location;
+    let mut credentials_path = format!("{}/youtube_credentials.json", secrets_location);
+    if !Path::new(&credentials_path).exists() {
+        credentials_path = format!(
+            "{}/youtube_credentials.json",
+            config::get_config().secrets_location
+        );
+    }
+    let token_path = format!("{}/youtube_tokencache.json", secrets_location);
+    let secret = match oauth2::read_application_secret(credentials_path).await {
+        Err(e) => {
+            println!("Cannot find credentials for youtube client : {}", e);
+            return Err(e);
+        }
+        Ok(secret) => secret,
+    };
+    // Instantiate the authenticator. It will choose a suitable authentication flow for you,
+    // unless you replace  `None` with the desired Flow.
+    // Provide your own `AuthenticatorDelegate` to adjust the way it operates and get feedback about
+    // what's going on. You probably want to bring in your own `TokenStorage` to persist tokens and
+    // retrieve them from storage.
+    oauth2::InstalledFlowAuthenticator::builder(
+        secret,
+        oauth2::InstalledFlowReturnMethod::Interactive,
+    )
+    .persist_tokens_to_disk(token_path)
+    .flow_delegate(Box::new(CustomFlowDelegate::new(
+        user.name.clone(),
+        config::get_config().youtube_redirect_uri,
+    )))
+    .build()
+    .await
+}
+
 fn convert_playlist_list(
     content: PlaylistListResponse,
     hub: &YouTube<HttpsConnector<HttpConnector>>,
+    user: &str,
 ) -> Vec<YoutubePlaylist> {
     let items = content.items.unwrap_or_default();
     let mut playlists = vec![];
     for i in items {
-        playlists.push(convert_playlist(i, hub.clone()));
+        playlists.push(convert_playlist(i, hub.clone(), user.to_string()));
     }
     playlists
 }
 
-fn song_from_item(item: PlaylistItem) -> Option<YoutubeSong> {
+fn song_from_item(item: PlaylistItem, parser: &TitleParser) -> Option<YoutubeSong> {
     let details = item.snippet.unwrap_or_default();
     let title = &details.title.unwrap_or_default();
     let id = details
@@ -324,20 +483,24 @@ fn song_from_item(item: PlaylistItem) -> Option<YoutubeSong> {
     if artists.is_empty() {
         None
     } else {
-        Some(YoutubeSong::new(
-            title.to_string(),
-            vec![artists.to_string()],
+        let parsed = parser.parse(title, artists);
+        let mut song = YoutubeSong::new(
+            parsed.title,
+            parsed.artists,
             Default::default(),
             id,
             Default::default(),
             Default::default(),
-        ))
+        );
+        song.original_title = title.to_string();
+        Some(song)
     }
 }
 
 fn convert_playlist(
     playlist: YtPlaylist,
     hub: YouTube<HttpsConnector<HttpConnector>>,
+    user: String,
 ) -> YoutubePlaylist {
     let snippet = playlist.snippet.unwrap_or_default();
     let content = playlist.content_details.unwrap_or_default();
@@ -353,6 +516,7 @@ fn convert_playlist(
         etag,
         hub,
         "Youtube".to_string(),
+        user,
     )
 }
 
@@ -361,6 +525,10 @@ impl Source for Client {
     fn get_name(&self) -> String {
         self.name.to_string()
     }
+
+    fn get_user(&self) -> String {
+        self.user.name.clone()
+    }
     async fn get_all_playlists(&mut self) -> std::vec::Vec<Playlist> {
         self.load_all_playlists().await
     }
@@ -369,16 +537,13 @@ impl Source for Client {
         self.playlists.len()
     }
 
-    async fn listen(&mut self) {
-        println!("Start listening");
+    async fn receive(&mut self) -> Option<Request> {
         loop {
-            let _ = match self.in_channel.recv().await {
-                Ok(msg) => self.handle_request(msg).await,
-                Err(RecvError::Closed) => {
-                    break;
-                },
-                _ => continue
-            };
+            match self.in_channel.recv().await {
+                Ok(request) => return Some(request),
+                Err(RecvError::Closed) => return None,
+                Err(RecvError::Lagged(_)) => continue,
+            }
         }
     }
 
@@ -386,15 +551,65 @@ impl Source for Client {
         self.out_channel.send(data).await;
     }
 
+    async fn authenticate(&mut self) -> JoinHandle<()> {
+        let authenticator = self.auth.clone();
+        let user = self.user.name.clone();
+        let name = self.name.clone();
+        tokio::spawn(async move {
+            // the other connections of the user wait for the token instead of asking for one
+            let _flow = auth::lock(&user, &name).await;
+            // fetching a token runs the authentication flow if needed
+            match authenticator.token(&[READONLY_SCOPE]).await {
+                Ok(_) => auth::set_status(&user, &name, AuthStatus::Authenticated),
+                Err(err) => auth::set_status(&user, &name, AuthStatus::Failed(err.to_string())),
+            }
+        })
+    }
+
     async fn init(&mut self) {
         self.fetch_all_playlists().await;
         self.get_all_playlists().await;
     }
 
-    async fn download_songs(&self, songs: &[Song], playlist_title: String) {
+    async fn download_songs(&self, songs: &[Song], playlist_title: String) -> JoinHandle<()> {
         let songs = songs.to_vec();
         let name = self.name.clone();
-        tokio::spawn(async move { utils::download_yt_playlist(songs, name, playlist_title).await });
+        let user = self.user.name.clone();
+        tokio::spawn(
+            async move { utils::download_yt_playlist(songs, name, user, playlist_title).await },
+        )
+    }
+
+    async fn refresh(&mut self) -> Vec<ChangedPlaylist> {
+        let previous = std::mem::take(&mut self.playlists);
+        self.playlist_loaded = false;
+        self.fetch_all_playlists().await;
+        let mut changed: Vec<ChangedPlaylist> = vec![];
+        for playlist in self.playlists.iter_mut() {
+            if db::playlist_needs_update(
+                &playlist.id,
+                &playlist.source,
+                &playlist.user,
+                &playlist.etag,
+            ) {
+                // the stored etag is still valid, keep what was already loaded
+                // unless another connection synced it in the meantime
+                if let Some(old) = previous
+                    .iter()
+                    .find(|p| p.id == playlist.id && p.etag == playlist.etag)
+                {
+                    *playlist = old.clone();
+                }
+                continue;
+            }
+            let old_songs = db::get_playlist_songs(&playlist.id, &playlist.source, &playlist.user)
+                .unwrap_or_default();
+            playlist.load_all().await;
+            let added = source::new_songs(&playlist.songs, &old_songs);
+            let playlist: Box<dyn PlaylistTrait> = Box::new(playlist.clone());
+            changed.push((playlist, added));
+        }
+        changed
     }
 
     async fn get_playlist_by_id(&mut self, id: &str) -> SourceResult<Box<dyn PlaylistTrait>> {
@@ -408,51 +623,48 @@ impl Source for Client {
 }
 
 struct CustomFlowDelegate {
-    out: Sender<Answer>,
+    user: String,
+    redirect_uri: String,
 }
 
 impl CustomFlowDelegate {
-    pub fn new(out: Sender<Answer>) -> Self {
-        CustomFlowDelegate { out }
+    pub fn new(user: String, redirect_uri: String) -> Self {
+        CustomFlowDelegate { user, redirect_uri }
     }
 }
 
 impl InstalledFlowDelegate for CustomFlowDelegate {
     /// Configure a custom redirect uri if needed.
     fn redirect_uri(&self) -> Option<&str> {
-        None
+        if self.redirect_uri.is_empty() {
+            None
+        } else {
+            Some(&self.redirect_uri)
+        }
     }
 
-    /// We need the user to navigate to a URL using their browser and potentially paste back a code
-    /// (or maybe not). Whether they have to enter a code depends on the InstalledFlowReturnMethod
-    /// used.
+    /// We need the user to navigate to a URL using their browser and paste back the code,
+    /// or the url they were redirected to, with an `AuthRequest::Complete` request.
     fn present_user_url<'a>(
         &'a self,
         url: &'a str,
         need_code: bool,
     ) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>> {
-        Box::pin(present_user_url(url, need_code, self.out.clone()))
+        Box::pin(present_user_url(&self.user, url, need_code))
     }
 }
 
-async fn present_user_url(
-    url: &str,
-    need_code: bool,
-    out: Sender<Answer>,
-) -> Result<String, String> {
-    let message: String = if need_code {
-        "Inputting code to authenticate not supported".to_owned()
-    } else {
-        format!(
-            "Please direct your browser to {} and follow the instructions displayed \
-             there.",
-            url
-        )
-    };
-    send_request(
-        out,
-        Answer::new("Youtube".to_string(), AnswerType::Message(message)),
-    )
-    .await;
-    Ok(String::new())
+async fn present_user_url(user: &str, url: &str, need_code: bool) -> Result<String, String> {
+    let code = auth::request_code(user, "Youtube", url.to_string());
+    if !need_code {
+        // the flow receives the code by itself
+        return Ok(String::new());
+    }
+    match code.wait().await {
+        Ok(input) => Ok(auth::parse_code(&input)),
+        Err(err) => {
+            auth::set_status(user, "Youtube", AuthStatus::Failed(err.clone()));
+            Err(err)
+        }
+    }
 }
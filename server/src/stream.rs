@@ -0,0 +1,269 @@
+//! Streaming of the songs over http: the downloaded files are served as they are,
+//! the other songs are fetched by yt-dlp and cached once complete
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use axum::body::{self, Body, Bytes, StreamBody};
+use axum::http::{header, HeaderValue, Request};
+use axum::response::{IntoResponse, Response};
+use futures::{Stream, StreamExt};
+use music_server::request::{Answer, AnswerType, EventType};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+use crate::source::Song;
+use crate::{config, utils, ListenAddr};
+
+/// Base of the stream urls, `None` when there is no http api
+static BASE_URL: OnceLock<Option<String>> = OnceLock::new();
+
+/// Distinguishes the partial files of a song streamed by several clients at once
+static PARTIAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the base of the stream urls from the configuration,
+/// or from the first of the addresses serving the http api
+pub fn set_base_url(http_listen: &[ListenAddr]) {
+    let config = config::get_config();
+    let base = if http_listen.is_empty() {
+        None
+    } else if !config.http_public_url.is_empty() {
+        Some(config.http_public_url.trim_end_matches('/').to_string())
+    } else {
+        let scheme = if config.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        http_listen.iter().find_map(|addr| match addr {
+            // the clients cannot connect to an unspecified address
+            ListenAddr::Tcp(addr) => match addr.parse::<SocketAddr>() {
+                Ok(socket) if socket.ip().is_unspecified() => None,
+                _ => Some(format!("{}://{}", scheme, addr)),
+            },
+            ListenAddr::Unix(_) => None,
+        })
+    };
+    let _ = BASE_URL.set(base);
+}
+
+/// Url streaming the song `id` of `source`, empty when there is no http api
+pub fn stream_url(source: &str, id: &str) -> String {
+    match BASE_URL.get() {
+        Some(Some(base)) => format!("{}/stream/{}/{}", base, source, id),
+        _ => String::new(),
+    }
+}
+
+/// Fills the stream url of the songs carried by `answer`
+pub fn add_stream_urls(answer: &mut Answer) {
+    if !matches!(BASE_URL.get(), Some(Some(_))) {
+        return;
+    }
+    let songs: Vec<&mut Song> = match &mut answer.data {
+        AnswerType::Songs(_, songs) | AnswerType::Event(EventType::PlaylistUpdated(_, songs)) => {
+            songs.iter_mut().collect()
+        }
+        AnswerType::Event(EventType::SongDownloaded(song)) => vec![song],
+        AnswerType::SearchResults(found) => found.iter_mut().map(|(_, song)| song).collect(),
+        AnswerType::History(history) => history.iter_mut().map(|(song, _)| song).collect(),
+        _ => vec![],
+    };
+    for song in songs {
+        song.stream_url = stream_url(&answer.client, &song.id);
+    }
+}
+
+/// Where a song fetched while streaming is kept
+pub fn cache_path(source: &str, id: &str) -> PathBuf {
+    PathBuf::from(format!(
+        "{}/cache/{}/{}",
+        config::get_config().data_location,
+        source,
+        cache_name(id)
+    ))
+}
+
+/// Name of the cached file of the song `id`, which stays in the cache whatever the id:
+/// every byte but the letters, digits, `-` and `_` is percent encoded
+fn cache_name(id: &str) -> String {
+    id.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Answers `request` with `song`: the downloaded or cached file, with support for range
+/// requests, or the song as it is fetched
+pub async fn respond(source: &str, song: &Song, request: Request<Body>) -> io::Result<Response> {
+    let cached = cache_path(source, &song.id);
+    let file = if song.downloaded && Path::new(&song.url).exists() {
+        Some(PathBuf::from(&song.url))
+    } else if cached.exists() {
+        Some(cached.clone())
+    } else {
+        None
+    };
+    match file {
+        Some(file) => {
+            let mut start = [0; 12];
+            let read = match tokio::fs::File::open(&file).await {
+                Ok(mut opened) => opened.read(&mut start).await.unwrap_or(0),
+                Err(_) => 0,
+            };
+            let mut response = match ServeFile::new(file).oneshot(request).await {
+                Ok(response) => response,
+                Err(err) => match err {},
+            };
+            // the cached files have no extension to guess their type from
+            let guessed = response.headers().get(header::CONTENT_TYPE);
+            if guessed.is_none_or(|mime| mime == "application/octet-stream") {
+                let mime = HeaderValue::from_static(content_type(&start[..read]));
+                response.headers_mut().insert(header::CONTENT_TYPE, mime);
+            }
+            Ok(response.map(body::boxed))
+        }
+        None => {
+            let (mime, chunks) = fetch(song, source, cached).await?;
+            Ok(([(header::CONTENT_TYPE, mime)], StreamBody::new(chunks)).into_response())
+        }
+    }
+}
+
+/// Type of the audio file starting with `start`, from its magic number
+fn content_type(start: &[u8]) -> &'static str {
+    if start.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        "audio/webm"
+    } else if start.get(4..8) == Some(b"ftyp") {
+        "audio/mp4"
+    } else if start.starts_with(b"OggS") {
+        "audio/ogg"
+    } else if start.starts_with(b"fLaC") {
+        "audio/flac"
+    } else if start.starts_with(b"ID3") || start.starts_with(&[0xff, 0xfb]) {
+        "audio/mpeg"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// Streams `song` as yt-dlp fetches it, and caches it at `cached` once complete.
+/// The song keeps being fetched when the client goes away, so that it is cached anyway.
+/// Its type is found from the start of the song.
+async fn fetch(
+    song: &Song,
+    source: &str,
+    cached: PathBuf,
+) -> io::Result<(&'static str, impl Stream<Item = io::Result<Bytes>>)> {
+    let link = utils::song_link(song, source).await;
+    let mut child = Command::new("yt-dlp")
+        .args([
+            "--quiet",
+            "--format",
+            "bestaudio",
+            "--default-search",
+            "ytsearch",
+        ])
+        .args(["--output", "-", &link])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    if let Some(folder) = cached.parent() {
+        tokio::fs::create_dir_all(folder).await?;
+    }
+    let partial = PathBuf::from(format!(
+        "{}.{}.part",
+        cached.display(),
+        PARTIAL.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = tokio::fs::File::create(&partial).await.ok();
+    let (tx, mut rx) = mpsc::channel::<io::Result<Bytes>>(16);
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        let complete = loop {
+            match stdout.read(&mut buf).await {
+                Ok(0) => break true,
+                Ok(n) => {
+                    let chunk = Bytes::copy_from_slice(&buf[..n]);
+                    if let Some(out) = file.as_mut() {
+                        if out.write_all(&chunk).await.is_err() {
+                            file = None;
+                        }
+                    }
+                    // an error only means that the client went away
+                    let _ = tx.send(Ok(chunk)).await;
+                }
+                Err(err) => {
+                    let _ = tx.send(Err(err)).await;
+                    break false;
+                }
+            }
+        };
+        let success = child.wait().await.is_ok_and(|status| status.success());
+        let cached_ok = match file {
+            Some(mut file) if complete && success => file.flush().await.is_ok(),
+            _ => false,
+        };
+        if cached_ok {
+            let _ = tokio::fs::rename(&partial, &cached).await;
+        } else {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+    });
+    // nothing is answered until yt-dlp found the song
+    let first = match rx.recv().await {
+        Some(Ok(chunk)) => chunk,
+        Some(Err(err)) => return Err(err),
+        None => return Err(io::Error::other("yt-dlp could not fetch the song")),
+    };
+    let rest = futures::stream::unfold(rx, |mut rx| async {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    let mime = content_type(&first);
+    Ok((mime, futures::stream::once(async { Ok(first) }).chain(rest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Component;
+
+    #[test]
+    fn content_types() {
+        assert_eq!(content_type(&[0x1a, 0x45, 0xdf, 0xa3, 0x01]), "audio/webm");
+        assert_eq!(content_type(b"\0\0\0\x20ftypM4A "), "audio/mp4");
+        assert_eq!(content_type(b"OggS\0\x02"), "audio/ogg");
+        assert_eq!(content_type(b"ID3\x04\0"), "audio/mpeg");
+        assert_eq!(content_type(b"<html>"), "application/octet-stream");
+        assert_eq!(content_type(b""), "application/octet-stream");
+    }
+
+    #[test]
+    fn cache_names() {
+        assert_eq!(cache_name("dQw4w9WgXcQ"), "dQw4w9WgXcQ");
+        assert_eq!(cache_name("spotify:track:4u"), "spotify%3Atrack%3A4u");
+        assert_eq!(cache_name("a%2F"), "a%252F");
+        for id in ["..", ".", "", "a/../..", "/etc/passwd", "..\\..", "C:\\x"] {
+            let name = cache_name(id);
+            let mut components = Path::new(&name).components();
+            assert!(
+                matches!(components.next(), Some(Component::Normal(_)) | None),
+                "{} gave {}",
+                id,
+                name
+            );
+            assert!(components.next().is_none(), "{} gave {}", id, name);
+            assert!(!name.contains(['/', '\\', '.']));
+        }
+    }
+}
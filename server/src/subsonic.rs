@@ -0,0 +1,472 @@
+//! Subset of the Subsonic and OpenSubsonic api, so that their clients can browse
+//! the playlists and stream the songs.
+//!
+//! The ids are `source:id`, for the songs as well as the playlists.
+
+use std::collections::HashSet;
+use std::path::Path as FilePath;
+
+use axum::body::Body;
+use axum::extract::{Path, Query};
+use axum::http::{header, Request};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::Router;
+use music_server::request::{Credentials, Permission};
+use music_server::source_types::{Playlist, Song};
+use serde_json::{json, Map, Value};
+
+use crate::access::{self, Identity};
+use crate::{db, source, stream};
+
+const VERSION: &str = "1.16.1";
+
+pub fn router() -> Router {
+    Router::new().route("/:method", get(dispatch).post(dispatch))
+}
+
+/// Error codes of the subsonic api
+#[derive(Clone, Copy)]
+enum ErrorCode {
+    Generic = 0,
+    MissingParameter = 10,
+    WrongCredentials = 40,
+    NotAuthorized = 50,
+    NotFound = 70,
+}
+
+struct Error(ErrorCode, String);
+
+impl Error {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Error(code, message.into())
+    }
+}
+
+/// A node of the answers, written as xml or as json
+struct Element {
+    name: &'static str,
+    attributes: Vec<(&'static str, Value)>,
+    children: Vec<Element>,
+    /// Written in a json array along with its siblings of the same name
+    item: bool,
+}
+
+impl Element {
+    fn new(name: &'static str) -> Self {
+        Element {
+            name,
+            attributes: vec![],
+            children: vec![],
+            item: false,
+        }
+    }
+
+    fn item(name: &'static str) -> Self {
+        Element {
+            item: true,
+            ..Element::new(name)
+        }
+    }
+
+    fn attr(mut self, name: &'static str, value: impl Into<Value>) -> Self {
+        self.attributes.push((name, value.into()));
+        self
+    }
+
+    fn children(mut self, children: impl IntoIterator<Item = Element>) -> Self {
+        self.children.extend(children);
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        let mut object: Map<String, Value> = self
+            .attributes
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        for child in &self.children {
+            if child.item {
+                let items = object
+                    .entry(child.name)
+                    .or_insert_with(|| Value::Array(vec![]));
+                if let Value::Array(items) = items {
+                    items.push(child.to_json());
+                }
+            } else {
+                object.insert(child.name.to_string(), child.to_json());
+            }
+        }
+        Value::Object(object)
+    }
+
+    fn write_xml(&self, out: &mut String) {
+        out.push('<');
+        out.push_str(self.name);
+        for (name, value) in &self.attributes {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            out.push_str(&format!(" {}=\"{}\"", name, escape(&value)));
+        }
+        if self.children.is_empty() {
+            out.push_str("/>");
+            return;
+        }
+        out.push('>');
+        for child in &self.children {
+            child.write_xml(out);
+        }
+        out.push_str(&format!("</{}>", self.name));
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The parameters of a request, some of which can be repeated
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, Error> {
+        self.get(name).ok_or_else(|| {
+            Error::new(
+                ErrorCode::MissingParameter,
+                format!("Required parameter is missing: {}", name),
+            )
+        })
+    }
+
+    fn number(&self, name: &str, default: usize) -> usize {
+        self.get(name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+}
+
+async fn dispatch(
+    Path(method): Path<String>,
+    Query(params): Query<Vec<(String, String)>>,
+    request: Request<Body>,
+) -> Response {
+    let params = Params(params);
+    let json = matches!(params.get("f"), Some("json"));
+    // most clients add the extension of the original servlets
+    let method = method.trim_end_matches(".view");
+    let res = match authenticate(&params) {
+        Ok(identity) => handle(method, &params, &identity, request).await,
+        Err(err) => Err(err),
+    };
+    match res {
+        Ok(Answer::Body(body)) => respond(json, "ok", body),
+        Ok(Answer::Raw(response)) => response,
+        Err(Error(code, message)) => {
+            let error = Element::new("error")
+                .attr("code", code as u32)
+                .attr("message", message);
+            respond(json, "failed", Some(error))
+        }
+    }
+}
+
+/// Identifies the client by its api key, its salted password or its password
+fn authenticate(params: &Params) -> Result<Identity, Error> {
+    let wrong = || Error::new(ErrorCode::WrongCredentials, "Wrong username or password");
+    if let Some(key) = params.get("apiKey") {
+        return access::login(&Credentials::Token(key.to_string())).map_err(|_| wrong());
+    }
+    let Some(user) = params.get("u") else {
+        return access::anonymous().ok_or_else(|| {
+            Error::new(
+                ErrorCode::MissingParameter,
+                "Required parameter is missing: u",
+            )
+        });
+    };
+    if let (Some(token), Some(salt)) = (params.get("t"), params.get("s")) {
+        return access::login_salted(user, token, salt).map_err(|_| wrong());
+    }
+    let password = decode_password(params.required("p")?).ok_or_else(wrong)?;
+    let credentials = Credentials::Password {
+        user: user.to_string(),
+        password,
+    };
+    access::login(&credentials).map_err(|_| wrong())
+}
+
+/// The password given as is, or hex encoded after `enc:`
+fn decode_password(password: &str) -> Option<String> {
+    match password.strip_prefix("enc:") {
+        Some(hex) => decode_hex(hex),
+        None => Some(password.to_string()),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<String> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Wraps `body` in the response of the api
+fn respond(json: bool, status: &'static str, body: Option<Element>) -> Response {
+    let response = Element::new("subsonic-response")
+        .attr("status", status)
+        .attr("version", VERSION)
+        .attr("type", "music-server")
+        .attr("openSubsonic", true)
+        .children(body);
+    if json {
+        let body = json!({ "subsonic-response": response.to_json() });
+        return axum::Json(body).into_response();
+    }
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let response = response.attr("xmlns", "http://subsonic.org/restapi");
+    response.write_xml(&mut xml);
+    ([(header::CONTENT_TYPE, "text/xml; charset=utf-8")], xml).into_response()
+}
+
+enum Answer {
+    /// Wrapped in the response of the api
+    Body(Option<Element>),
+    /// Sent as is, like the songs and the images
+    Raw(Response),
+}
+
+async fn handle(
+    method: &str,
+    params: &Params,
+    identity: &Identity,
+    request: Request<Body>,
+) -> Result<Answer, Error> {
+    let user = identity.user.name.as_str();
+    let body = match method {
+        "ping" => None,
+        "getLicense" => Some(Element::new("license").attr("valid", true)),
+        "getPlaylists" => {
+            let playlists = library(user)?.into_iter().map(|(source, playlist, songs)| {
+                playlist_element(&source, &playlist, &songs, user)
+            });
+            Some(Element::new("playlists").children(playlists))
+        }
+        "getPlaylist" => {
+            let (source, id) = split_id(params.required("id")?)?;
+            let songs = db::get_playlist_songs(id, source, user)
+                .map_err(|_| Error::new(ErrorCode::NotFound, "Playlist not found"))?;
+            let playlist = db::load_playlist(id, source, user).map_err(internal)?;
+            let entries = songs.iter().map(|song| song_element("entry", source, song));
+            Some(playlist_element(source, &playlist, &songs, user).children(entries))
+        }
+        "search3" => {
+            let query = params.get("query").unwrap_or_default().trim_matches('"');
+            let query = query.to_lowercase();
+            let count = params.number("songCount", 20);
+            let offset = params.number("songOffset", 0);
+            let songs: Vec<Element> = library_songs(user)?
+                .into_iter()
+                .filter(|(_, song)| {
+                    query.is_empty()
+                        || song.title.to_lowercase().contains(&query)
+                        || song
+                            .artists
+                            .iter()
+                            .any(|artist| artist.to_lowercase().contains(&query))
+                })
+                .skip(offset)
+                .take(count)
+                .map(|(source, song)| song_element("song", &source, &song))
+                .collect();
+            Some(Element::new("searchResult3").children(songs))
+        }
+        "stream" | "download" => {
+            allowed(identity, Permission::Play)?;
+            let (source, id) = split_id(params.required("id")?)?;
+            let song = find_song(source, id, user)?;
+            let response = stream::respond(source, &song, request)
+                .await
+                .map_err(|err| Error::new(ErrorCode::Generic, err.to_string()))?;
+            return Ok(Answer::Raw(response));
+        }
+        "getCoverArt" => {
+            let (source, id) = split_id(params.required("id")?)?;
+            let url = cover_url(source, id)
+                .ok_or_else(|| Error::new(ErrorCode::NotFound, "Cover art not found"))?;
+            return Ok(Answer::Raw(Redirect::temporary(&url).into_response()));
+        }
+        "scrobble" => {
+            allowed(identity, Permission::Play)?;
+            // the songs being played are not tracked, only the listening history is
+            if params.get("submission") != Some("false") {
+                for id in params.all("id") {
+                    let (source, id) = split_id(id)?;
+                    db::add_history(user, source, id).map_err(internal)?;
+                }
+            }
+            None
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorCode::NotFound,
+                format!("Unknown method {}", method),
+            ))
+        }
+    };
+    Ok(Answer::Body(body))
+}
+
+fn internal(err: rusqlite::Error) -> Error {
+    Error::new(ErrorCode::Generic, err.to_string())
+}
+
+fn allowed(identity: &Identity, permission: Permission) -> Result<(), Error> {
+    if identity.permission < permission {
+        return Err(Error::new(
+            ErrorCode::NotAuthorized,
+            format!("{:?} permission needed", permission),
+        ));
+    }
+    Ok(())
+}
+
+/// Splits a `source:id` id
+fn split_id(id: &str) -> Result<(&'static str, &str), Error> {
+    let not_found = || Error::new(ErrorCode::NotFound, format!("Unknown id {}", id));
+    let (source, id) = id.split_once(':').ok_or_else(not_found)?;
+    let source = source::SOURCES
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(source))
+        .ok_or_else(not_found)?;
+    Ok((source, id))
+}
+
+/// The song `id` of `source`, if it is in the library of `user`
+fn find_song(source: &str, id: &str, user: &str) -> Result<Song, Error> {
+    db::get_user_song(id, source, user)
+        .map_err(internal)?
+        .ok_or_else(|| Error::new(ErrorCode::NotFound, "Song not found"))
+}
+
+/// Only the youtube videos have a known thumbnail
+fn cover_url(source: &str, id: &str) -> Option<String> {
+    (source == "Youtube").then(|| format!("https://i.ytimg.com/vi/{}/hqdefault.jpg", id))
+}
+
+/// The playlists of `user` with their source and songs
+fn library(user: &str) -> Result<Vec<(String, Playlist, Vec<Song>)>, Error> {
+    let mut library = vec![];
+    for (source, playlist, _) in db::list_playlists(user).map_err(internal)? {
+        let songs = db::get_playlist_songs(&playlist.id, &source, user).map_err(internal)?;
+        library.push((source, playlist, songs));
+    }
+    Ok(library)
+}
+
+/// Every song in the playlists of `user`, once
+fn library_songs(user: &str) -> Result<Vec<(String, Song)>, Error> {
+    let mut seen = HashSet::new();
+    let mut songs = vec![];
+    for (source, _, playlist) in library(user)? {
+        for song in playlist {
+            if seen.insert((source.clone(), song.id.clone())) {
+                songs.push((source.clone(), song));
+            }
+        }
+    }
+    Ok(songs)
+}
+
+fn playlist_element(source: &str, playlist: &Playlist, songs: &[Song], user: &str) -> Element {
+    let duration: u64 = songs.iter().map(|song| song.duration.as_secs()).sum();
+    let mut element = Element::item("playlist")
+        .attr("id", format!("{}:{}", source, playlist.id))
+        .attr("name", playlist.title.clone())
+        .attr("comment", source)
+        .attr("owner", user)
+        .attr("public", false)
+        .attr("songCount", songs.len())
+        .attr("duration", duration);
+    if let Some(song) = songs.first() {
+        if cover_url(source, &song.id).is_some() {
+            element = element.attr("coverArt", format!("{}:{}", source, song.id));
+        }
+    }
+    element
+}
+
+fn song_element(name: &'static str, source: &str, song: &Song) -> Element {
+    let mut element = Element::item(name)
+        .attr("id", format!("{}:{}", source, song.id))
+        .attr("isDir", false)
+        .attr("title", song.title.clone())
+        .attr("artist", song.artists.join(", "))
+        .attr("duration", song.duration.as_secs())
+        .attr("type", "music");
+    if cover_url(source, &song.id).is_some() {
+        element = element.attr("coverArt", format!("{}:{}", source, song.id));
+    }
+    let file = FilePath::new(&song.url);
+    if song.downloaded && file.exists() {
+        let suffix = file
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if let Ok(metadata) = file.metadata() {
+            element = element.attr("size", metadata.len());
+        }
+        element = element
+            .attr("contentType", content_type(&suffix))
+            .attr("suffix", suffix);
+    }
+    element
+}
+
+fn content_type(suffix: &str) -> &'static str {
+    match suffix {
+        "mp3" => "audio/mpeg",
+        "m4a" | "mp4" | "aac" => "audio/mp4",
+        "webm" => "audio/webm",
+        "opus" | "ogg" => "audio/ogg",
+        "flac" => "audio/flac",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords() {
+        assert_eq!(decode_password("sesame").as_deref(), Some("sesame"));
+        assert_eq!(decode_password("enc:736573616d65"), Some("sesame".into()));
+        assert_eq!(decode_password("enc:736573616D65"), Some("sesame".into()));
+        assert_eq!(decode_password("enc:c3a9").as_deref(), Some("é"));
+        assert_eq!(decode_password("enc:").as_deref(), Some(""));
+        assert_eq!(decode_password("enc:7365736"), None);
+        assert_eq!(decode_password("enc:zz"), None);
+        assert_eq!(decode_password("enc:ff"), None);
+    }
+}
@@ -0,0 +1,51 @@
+use music_server::request::{Answer, AuthStatus, Request};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{Duration, MissedTickBehavior};
+
+use crate::config::{self, UserConfig, DEFAULT_USER};
+use crate::{auth, source};
+
+/// Starts the background syncs of the playlists: a single task per user and source,
+/// whatever the number of connections. The clients learn about the changes through the events.
+pub fn start() {
+    let config = config::get_config();
+    if config.sync_interval == 0 {
+        return;
+    }
+    let period = Duration::from_secs(config.sync_interval);
+    let names = std::iter::once(DEFAULT_USER).chain(config.users.iter().map(|u| u.name.as_str()));
+    for user in names.filter_map(|name| config.user(name)) {
+        for name in source::SOURCES {
+            tokio::spawn(run(name, user.clone(), period));
+        }
+    }
+}
+
+/// Syncs the source called `name` every `period`, the first time after a whole period
+async fn run(name: &str, user: UserConfig, period: Duration) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the source is driven from here: nobody sends it requests,
+    // and what it answers is published as events by `Source::sync`
+    let (_requests, broad_rx) = broadcast::channel::<Request>(1);
+    let (mpsc_tx, mut mpsc_rx) = mpsc::channel::<Answer>(16);
+    tokio::spawn(async move { while mpsc_rx.recv().await.is_some() {} });
+    let Some(mut source) = source::new_source(name, user.clone(), broad_rx, mpsc_tx).await else {
+        println!("Cannot start the background sync of {}", name);
+        return;
+    };
+    let authenticated = || matches!(auth::status(&user.name, name), AuthStatus::Authenticated);
+    let mut loaded = false;
+    loop {
+        interval.tick().await;
+        // the token of the source is loaded the first time even if a connection already
+        // logged the user in, the flow of the connections is joined if one is running
+        if !loaded || !authenticated() {
+            let _ = source.authenticate().await.await;
+            loaded = authenticated();
+        }
+        if loaded {
+            source.sync().await;
+        }
+    }
+}
@@ -0,0 +1,249 @@
+#![warn(clippy::unwrap_used)]
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TitleParserConfig {
+    pub enabled: bool,
+    /// Strings separating the artists from the title, e.g. "Artist - Title"
+    pub separators: Vec<String>,
+    /// Case insensitive regexes matching bracketed or `|` separated parts to drop
+    pub noise_patterns: Vec<String>,
+}
+
+impl std::default::Default for TitleParserConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            separators: vec![" - ", " – ", " — ", " ~ "]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            noise_patterns: vec![
+                r"^official",
+                r"video$",
+                r"audio$",
+                r"^lyrics?",
+                r"^(hd|hq|4k|1080p|720p)$",
+                r"^(mv|m/v)$",
+                r"visuali[sz]er",
+                r"^explicit$",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+}
+
+pub struct ParsedTitle {
+    pub artists: Vec<String>,
+    pub title: String,
+}
+
+pub struct TitleParser {
+    config: TitleParserConfig,
+    noise: Vec<Regex>,
+    brackets: Regex,
+    feat: Regex,
+}
+
+impl TitleParser {
+    pub fn new(config: TitleParserConfig) -> Self {
+        let noise = config
+            .noise_patterns
+            .iter()
+            .filter_map(
+                |pat| match RegexBuilder::new(pat).case_insensitive(true).build() {
+                    Ok(re) => Some(re),
+                    Err(err) => {
+                        println!("Invalid title noise pattern {}: {}", pat, err);
+                        None
+                    }
+                },
+            )
+            .collect();
+        TitleParser {
+            config,
+            noise,
+            brackets: Regex::new(r"\s*[\(\[]([^\)\]]*)[\)\]]").expect("valid regex"),
+            feat: Regex::new(r"(?i)\s*[\(\[]?\b(?:feat|ft|featuring)\b\.?\s+([^\)\]]+)[\)\]]?")
+                .expect("valid regex"),
+        }
+    }
+
+    fn is_noise(&self, text: &str) -> bool {
+        let text = text.trim();
+        self.noise.iter().any(|re| re.is_match(text))
+    }
+
+    fn strip_noise(&self, title: &str) -> String {
+        // "Title | Official Video" style suffixes
+        let title = title
+            .split('|')
+            .enumerate()
+            .filter(|(i, part)| *i == 0 || !self.is_noise(part))
+            .map(|(_, part)| part)
+            .collect::<Vec<_>>()
+            .join("|");
+        let title = self.brackets.replace_all(&title, |caps: &regex::Captures| {
+            if self.is_noise(&caps[1]) {
+                String::new()
+            } else {
+                caps[0].to_string()
+            }
+        });
+        title.trim().to_string()
+    }
+
+    /// Removes the "feat. X" credits from `text`, returning the cleaned text and the credited artists
+    fn extract_feat(&self, text: &str) -> (String, Vec<String>) {
+        let mut featured = vec![];
+        for caps in self.feat.captures_iter(text) {
+            featured.extend(split_artists(&caps[1], &[',', '&']));
+        }
+        let text = self.feat.replace_all(text, "").trim().to_string();
+        (text, featured)
+    }
+
+    /// Guesses the artists and the song title from a video title and its uploader channel
+    pub fn parse(&self, video_title: &str, channel: &str) -> ParsedTitle {
+        if !self.config.enabled {
+            return ParsedTitle {
+                artists: vec![channel.to_string()],
+                title: video_title.to_string(),
+            };
+        }
+        let channel = clean_channel(channel);
+        let title = self.strip_noise(video_title);
+        // "- Topic" channels are generated from the label metadata, their titles are already clean
+        let split = if is_topic(channel) {
+            None
+        } else {
+            self.config
+                .separators
+                .iter()
+                .filter_map(|sep| title.find(sep.as_str()).map(|pos| (pos, sep.len())))
+                .min_by_key(|(pos, _)| *pos)
+        };
+        let (artists, title) = match split {
+            Some((pos, len)) => (title[..pos].to_string(), title[pos + len..].to_string()),
+            None => (channel.trim_end_matches(" - Topic").to_string(), title),
+        };
+        let (artists, mut featured_artists) = self.extract_feat(&artists);
+        let (title, featured_title) = self.extract_feat(&title);
+        featured_artists.extend(featured_title);
+        let mut artists = split_artists(&artists, &[',']);
+        for artist in featured_artists {
+            if !artists.contains(&artist) {
+                artists.push(artist)
+            }
+        }
+        if artists.is_empty() {
+            artists.push(channel.to_string());
+        }
+        let title = title.trim().trim_matches('"').trim().to_string();
+        ParsedTitle {
+            artists,
+            title: if title.is_empty() {
+                video_title.to_string()
+            } else {
+                title
+            },
+        }
+    }
+}
+
+fn is_topic(channel: &str) -> bool {
+    channel.ends_with(" - Topic")
+}
+
+/// Removes the "VEVO" and "Official" decorations of a channel name
+fn clean_channel(channel: &str) -> &str {
+    let channel = channel.trim();
+    let channel = channel.strip_suffix("VEVO").unwrap_or(channel);
+    let channel = channel.strip_suffix("Official").unwrap_or(channel);
+    channel.trim()
+}
+
+fn split_artists(artists: &str, separators: &[char]) -> Vec<String> {
+    artists
+        .split(separators)
+        .map(|a| a.trim().to_string())
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Video title, channel, expected artists and expected title
+    type Case<'a> = (&'a str, &'a str, &'a [&'a str], &'a str);
+
+    fn check(cases: &[Case]) {
+        let parser = TitleParser::new(TitleParserConfig::default());
+        for (title, channel, artists, expected) in cases {
+            let parsed = parser.parse(title, channel);
+            assert_eq!(parsed.artists, *artists, "artists of {:?}", title);
+            assert_eq!(parsed.title, *expected, "title of {:?}", title);
+        }
+    }
+
+    #[test]
+    fn separators() {
+        check(&[
+            ("A - Song", "Up", &["A"], "Song"),
+            ("A – Song", "Up", &["A"], "Song"),
+            ("A — Song", "Up", &["A"], "Song"),
+            ("A ~ Song", "Up", &["A"], "Song"),
+            ("A, B - Song - Part 2", "Up", &["A", "B"], "Song - Part 2"),
+            ("Just a song", "Up", &["Up"], "Just a song"),
+            ("A - \"Song\"", "Up", &["A"], "Song"),
+        ]);
+    }
+
+    #[test]
+    fn feat_credits() {
+        check(&[
+            ("A feat. B - Song", "Up", &["A", "B"], "Song"),
+            ("A - Song (ft. B & C)", "Up", &["A", "B", "C"], "Song"),
+            ("A - Song [Featuring B]", "Up", &["A", "B"], "Song"),
+            ("A - Song feat. A", "Up", &["A"], "Song"),
+        ]);
+    }
+
+    #[test]
+    fn noise_stripping() {
+        check(&[
+            ("A - Song (Official Video)", "Up", &["A"], "Song"),
+            ("A - Song [Official Music Video]", "Up", &["A"], "Song"),
+            ("A - Song (Lyrics) [HD]", "Up", &["A"], "Song"),
+            ("A - Song | Official Audio", "Up", &["A"], "Song"),
+            ("A - Song (Live)", "Up", &["A"], "Song (Live)"),
+            ("(Official Video)", "Up", &["Up"], "(Official Video)"),
+        ]);
+    }
+
+    #[test]
+    fn channels() {
+        check(&[
+            ("Song", "A - Topic", &["A"], "Song"),
+            ("Intro - Reprise", "A - Topic", &["A"], "Intro - Reprise"),
+            ("Song", "AVEVO", &["A"], "Song"),
+            ("Song", "A Official", &["A"], "Song"),
+        ]);
+    }
+
+    #[test]
+    fn disabled() {
+        let config = TitleParserConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        let parsed = TitleParser::new(config).parse("A - Song (Official Video)", "AVEVO");
+        assert_eq!(parsed.artists, ["AVEVO"]);
+        assert_eq!(parsed.title, "A - Song (Official Video)");
+    }
+}
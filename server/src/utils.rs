@@ -3,7 +3,9 @@ use regex::{Regex, RegexSet};
 use std::{path::PathBuf, time::Duration};
 use ytd_rs::{Arg, YoutubeDL};
 
-use crate::{config, db, source::Song};
+use crate::source::{self, Song};
+use crate::{config, db, events, resolver};
+use music_server::request::EventType;
 pub type UtilsResult<T> = Result<T, UtilsError>;
 
 #[derive(Debug)]
@@ -121,14 +123,10 @@ pub async fn download_yt_song(
         Arg::new_with_arg("--output", out_template),
         Arg::new_with_arg("--print", "after_move:filepath"),
     ];
-    let link = format!("https://youtube.com/watch?v={}", song.id);
+    let link = song_link(&song, &client).await;
     download_song(song, client, playlist_title, args, link).await
 }
 
-fn get_song_title(song: &Song) -> String {
-    format!("{} - {}", song.artists.join(", "), song.title)
-}
-
 pub async fn download_spotify_song(
     song: Song,
     client: String,
@@ -144,30 +142,89 @@ pub async fn download_spotify_song(
         Arg::new_with_arg("--output", out_template),
         Arg::new_with_arg("--print", "after_move:filepath"),
     ];
-    let title = get_song_title(&song);
-    download_song(song, client, playlist_title, args, title).await
+    let link = song_link(&song, &client).await;
+    download_song(song, client, playlist_title, args, link).await
+}
+
+/// What yt-dlp is given to fetch `song` of the source `client`.
+/// Needs `--default-search ytsearch` for the songs that are not on youtube.
+pub async fn song_link(song: &Song, client: &str) -> String {
+    if client == "Youtube" {
+        return format!("https://youtube.com/watch?v={}", song.id);
+    }
+    // the episodes are not on youtube, they are fetched from their own url
+    if song.tags.iter().any(|tag| tag == source::PODCAST_TAG) && !song.url.is_empty() {
+        return song.url.clone();
+    }
+    // fall back to yt-dlp's own search if no match could be found
+    match resolver::resolve(song, client).await {
+        Some(video_id) => format!("https://youtube.com/watch?v={}", video_id),
+        None => resolver::search_query(song),
+    }
 }
 
 
-async fn download_playlist<F, Fut>(songs: Vec<Song>, client: String, playlist_title: String, downloader: F)
+async fn download_playlist<F, Fut>(
+    songs: Vec<Song>,
+    client: String,
+    user: String,
+    playlist_title: String,
+    downloader: F,
+)
 where F: Fn(Song, String, String) -> Fut, 
       Fut:Future<Output = UtilsResult<Song>>{
     println!("Start Downloading");
     let songs = db::remove_downloaded(&songs, &client).unwrap();
-    let songs: Vec<UtilsResult<Song>> = futures::stream::iter(songs)
+    let total = songs.len();
+    let mut downloads = futures::stream::iter(songs)
         .map(|song| async { downloader(song, client.clone(), playlist_title.clone()).await })
-        .buffer_unordered(4)
-        .collect()
-        .await;
-    let songs_ok: Vec<Song> = songs.into_iter().filter_map(|s| s.ok()).collect();
+        .buffer_unordered(4);
+    let mut songs_ok: Vec<Song> = vec![];
+    let mut done = 0;
+    // also tells the clients when there is nothing to download
+    events::publish(
+        &user,
+        &client,
+        EventType::DownloadProgress {
+            playlist: playlist_title.clone(),
+            done,
+            total,
+        },
+    );
+    while let Some(song) = downloads.next().await {
+        done += 1;
+        if let Ok(song) = song {
+            events::publish(&user, &client, EventType::SongDownloaded(song.clone()));
+            songs_ok.push(song);
+        }
+        events::publish(
+            &user,
+            &client,
+            EventType::DownloadProgress {
+                playlist: playlist_title.clone(),
+                done,
+                total,
+            },
+        );
+    }
     let _ = db::update_songs(&songs_ok, &client);
     println!("Done Downloading");
 }
 
-pub async fn download_spotify_playlist(songs: Vec<Song>, client: String, playlist_title: String) {
-    download_playlist(songs, client, playlist_title, download_spotify_song).await;
+pub async fn download_spotify_playlist(
+    songs: Vec<Song>,
+    client: String,
+    user: String,
+    playlist_title: String,
+) {
+    download_playlist(songs, client, user, playlist_title, download_spotify_song).await;
 }
 
-pub async fn download_yt_playlist(songs: Vec<Song>, client: String, playlist_title: String) {
-    download_playlist(songs, client, playlist_title, download_yt_song).await;
+pub async fn download_yt_playlist(
+    songs: Vec<Song>,
+    client: String,
+    user: String,
+    playlist_title: String,
+) {
+    download_playlist(songs, client, user, playlist_title, download_yt_song).await;
 }
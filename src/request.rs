@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::{fmt};
+use std::{fmt, time::Duration};
 use tokio::sync::mpsc::{error::SendError, Sender};
 
 use crate::source_types::{Playlist, Song, SourceError};
@@ -22,12 +22,128 @@ impl fmt::Display for RequestError {
 pub enum RequestType {
     GetAll(ObjRequest),
     Error(String),
-    Set,
+    Set(ObjRequest),
     Add,
     Remove,
     Get(Attr),
     Download(ObjRequest),
     Message(String),
+    /// Receive the events of the given topics, replacing any previous subscription
+    Subscribe(Vec<Topic>),
+    Auth(AuthRequest),
+    /// Songs of the loaded playlists whose title, artists or tags contain the query
+    Search(String),
+    /// Authenticates the connection, required before any other request
+    /// when the server has clients configured
+    Login(Credentials),
+    /// Adds the song with this id to the user's history of the source
+    Played(String),
+    /// Controls the player of the server, answered with its status
+    Player(PlayerRequest),
+}
+
+impl RequestType {
+    /// Permission needed to make the request
+    pub fn permission(&self) -> Permission {
+        match self {
+            RequestType::Player(PlayerRequest::Status) => Permission::Read,
+            RequestType::Played(_) | RequestType::Player(_) => Permission::Play,
+            RequestType::Download(_) | RequestType::Set(_) => Permission::Download,
+            RequestType::Auth(AuthRequest::Status) => Permission::Read,
+            RequestType::Auth(_) | RequestType::Add | RequestType::Remove => Permission::Admin,
+            _ => Permission::Read,
+        }
+    }
+}
+
+/// What a connection is allowed to do, each level includes the previous ones
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    /// Browse the sources and subscribe to events
+    #[default]
+    Read,
+    Play,
+    /// Download songs and choose how they are matched
+    Download,
+    /// Authenticate the sources
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Credentials {
+    Token(String),
+    Password { user: String, password: String },
+}
+
+impl fmt::Debug for Credentials {
+    // requests are logged, keep the secrets out of the logs
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => write!(f, "Token(..)"),
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {:?}, .. }}", user),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AuthRequest {
+    Status,
+    /// Asks for the url the user must visit to authenticate the source
+    Begin,
+    /// Code, or full redirect url, obtained after visiting the authentication url
+    Complete(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum AuthStatus {
+    #[default]
+    Unknown,
+    /// Waiting for the user to visit the url
+    Pending(String),
+    Authenticated,
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum PlayerRequest {
+    Status,
+    /// Plays the song at this position of the queue, or resumes the playback
+    Play(Option<usize>),
+    Pause,
+    /// Pauses when playing, plays otherwise
+    Toggle,
+    Stop,
+    Next,
+    Previous,
+    /// Moves to this position in the current song
+    Seek(Duration),
+    /// From 0 to 100
+    SetVolume(u32),
+    /// Adds the songs of the request's source with these ids at the end of the queue
+    Enqueue(Vec<String>),
+    /// Removes the song at this position of the queue
+    Remove(usize),
+    Clear,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlayerState {
+    #[default]
+    Stop,
+    Play,
+    Pause,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PlayerStatus {
+    pub state: PlayerState,
+    /// Songs to play along with their source
+    pub queue: Vec<(String, Song)>,
+    /// Position in the queue of the playing or paused song
+    pub current: Option<usize>,
+    /// Position in the current song
+    pub elapsed: Duration,
+    pub volume: u32,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -37,6 +153,10 @@ pub enum ObjRequest {
     Song,
     Client(String),
     ClientList,
+    /// Youtube video (second field) to use when downloading a song (first field)
+    SongMatch(String, String),
+    /// The songs the user played, the most recent first
+    History,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -44,15 +164,74 @@ pub enum AnswerType {
     PlaylistList(Vec<Playlist>),
     Playlist(Playlist),
     Songs(Playlist, Vec<Song>),
+    Event(EventType),
+    AuthStatus(AuthStatus),
+    AuthUrl(String),
+    SearchResults(Vec<(Playlist, Song)>),
+    /// The connection is authenticated with the given permission
+    LoggedIn(Permission),
+    /// Songs played by the user along with when they were played, in seconds since the epoch
+    History(Vec<(Song, u64)>),
+    PlayerStatus(PlayerStatus),
     Song(Song),
     Client(String),
     Message(String),
     Error(ErrorType),
 }
 
+/// Categories of events a client can subscribe to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Topic {
+    SourceStatus,
+    Playlist,
+    Download,
+    Auth,
+    Player,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SourceStatus {
+    Loading,
+    Ready,
+    Syncing,
+}
+
+/// Notifications pushed by the server without a matching request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum EventType {
+    SourceStatus(SourceStatus),
+    /// The content of a playlist changed during a sync
+    PlaylistUpdated(Playlist, Vec<Song>),
+    SongDownloaded(Song),
+    DownloadProgress {
+        playlist: String,
+        done: usize,
+        total: usize,
+    },
+    /// The source needs the user to visit the given url
+    AuthRequired(String),
+    /// The player of the server changed, sent to every user
+    PlayerChanged(PlayerStatus),
+}
+
+impl EventType {
+    pub fn topic(&self) -> Topic {
+        match self {
+            EventType::SourceStatus(_) => Topic::SourceStatus,
+            EventType::PlaylistUpdated(..) => Topic::Playlist,
+            EventType::SongDownloaded(_) | EventType::DownloadProgress { .. } => Topic::Download,
+            EventType::AuthRequired(_) => Topic::Auth,
+            EventType::PlayerChanged(_) => Topic::Player,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ErrorType {
     SourceError(SourceError),
+    PermissionDenied(String),
+    /// The player of the server is disabled or failed
+    PlayerError(String),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -66,6 +245,23 @@ pub enum Attr {
 pub struct Request {
     pub client: String,
     pub ty: RequestType,
+    /// Set by the server from the connection's credentials
+    #[serde(skip)]
+    pub permission: Permission,
+}
+
+impl Request {
+    pub fn new(client: String, ty: RequestType) -> Self {
+        Request {
+            client,
+            ty,
+            permission: Default::default(),
+        }
+    }
+
+    pub fn allowed(&self) -> bool {
+        self.permission >= self.ty.permission()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -21,6 +21,12 @@ pub struct Song {
     pub duration: Duration,
     pub url: String,
     pub downloaded: bool,
+    /// Title as found on the source, before any parsing
+    #[serde(default)]
+    pub original_title: String,
+    /// Where the server streams the song over http, empty when it has no http api
+    #[serde(default)]
+    pub stream_url: String,
 }
 
 impl Song {
@@ -33,6 +39,7 @@ impl Song {
         url: String,
     ) -> Self {
         Song {
+            original_title: title.clone(),
             title,
             artists,
             tags,
@@ -40,8 +47,20 @@ impl Song {
             duration,
             url,
             downloaded: false,
+            stream_url: Default::default(),
         }
     }
+
+    /// Whether the title, one of the artists or one of the tags contains `query`, ignoring case
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.title.to_lowercase().contains(&query)
+            || self
+                .artists
+                .iter()
+                .chain(self.tags.iter())
+                .any(|s| s.to_lowercase().contains(&query))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]